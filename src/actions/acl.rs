@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::get_or_create_data_dir,
+    errors::{KVSError, KVSResult},
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, Session},
+    utils::{is_scope_addr, sha256, to_u8str},
+};
+
+use super::{Actions, KVSToken, KeyMeta, TeamMeta};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AclOp {
+    Get,
+    /// Replace the access list, an empty list removes it.
+    Set(Vec<String>),
+    Add(Vec<String>),
+    Remove(Vec<String>),
+}
+
+/// Read or change the access list of a public key, replies the resulting list.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AclAction {
+    pub token: KVSToken,
+    pub key: String,
+    pub op: AclOp,
+}
+
+/// Whether `scope` is listed in `acl` directly or through a team.
+pub fn acl_allows(acl: &[String], scope: &str) -> KVSResult<bool> {
+    for entry in acl {
+        if entry == scope {
+            return Ok(true);
+        }
+        if let Some(team) = entry.strip_prefix("team:") {
+            match TeamMeta::from_dir(team) {
+                Ok(team) if team.member(scope).is_some() => return Ok(true),
+                _ => continue,
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn check_acl_entries(entries: &[String]) -> KVSResult<()> {
+    for entry in entries {
+        let legal = match entry.strip_prefix("team:") {
+            Some(team) => TeamMeta::team_dir(team).is_ok(),
+            None => is_scope_addr(entry),
+        };
+        if !legal {
+            return Err(KVSError::LogicError(format!(
+                "Illegal access list entry: {}",
+                entry
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl KVSAction<Option<Vec<String>>> for AclAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<Option<Vec<String>>> {
+        let AclAction { token, key, op } = self;
+        let id_str = token.get_addr();
+        let o_key = key.clone();
+        let key = to_u8str(&sha256(key.as_bytes()));
+        let kv_path = get_or_create_data_dir()?.join(&id_str).join(&key);
+        if !kv_path.exists() {
+            return Err(KVSError::LogicError(format!(
+                "The key: `{}` is not exists.",
+                o_key
+            )));
+        }
+        let meta_file_path = kv_path.join("meta");
+        let mut meta = KeyMeta::from_file(&meta_file_path)?;
+        if meta.rand.is_some() {
+            return Err(KVSError::LogicError(format!(
+                "The key: `{}` is private, share it instead.",
+                o_key
+            )));
+        }
+        let acl = match op {
+            AclOp::Get => return Ok(meta.acl),
+            AclOp::Set(entries) => {
+                check_acl_entries(entries)?;
+                if entries.is_empty() {
+                    None
+                } else {
+                    Some(entries.clone())
+                }
+            }
+            AclOp::Add(entries) => {
+                check_acl_entries(entries)?;
+                let mut acl = meta.acl.clone().unwrap_or_default();
+                entries.iter().for_each(|entry| {
+                    if !acl.contains(entry) {
+                        acl.push(entry.clone())
+                    }
+                });
+                Some(acl)
+            }
+            AclOp::Remove(entries) => meta.acl.clone().map(|acl| {
+                acl.into_iter()
+                    .filter(|entry| !entries.contains(entry))
+                    .collect()
+            }),
+        };
+        meta.acl = acl;
+        meta.save(&meta_file_path)?;
+        tracing::info!("[{}] Update ACL: {} ({})", id_str, key, o_key);
+        Ok(meta.acl)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<Option<Vec<String>>> {
+        session.write(&Actions::AclAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<Option<Vec<String>>>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(acl) => Ok(acl),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{acl_allows, AclAction, AclOp};
+    use crate::{
+        actions::{TeamMember, TeamMeta, TeamRole},
+        client::KvsClient,
+        kv_server::test::test_repository,
+        secret::{KeyType, Secret},
+    };
+
+    #[test]
+    fn test_acl_allows() {
+        let repository = test_repository();
+        let (member, other) = (
+            "0x".to_string() + &"1".repeat(40),
+            "0x".to_string() + &"2".repeat(40),
+        );
+        TeamMeta {
+            name: "acl".to_string(),
+            members: vec![TeamMember {
+                scope: member.clone(),
+                role: TeamRole::Reader,
+                key: vec![],
+            }],
+            key_version: 1,
+        }
+        .save()
+        .unwrap();
+        assert!(acl_allows(std::slice::from_ref(&member), &member).unwrap());
+        assert!(acl_allows(&["team:acl".to_string()], &member).unwrap());
+        assert!(!acl_allows(&["team:acl".to_string()], &other).unwrap());
+        assert!(!acl_allows(&["team:missing".to_string(), member], &other).unwrap());
+        assert!(!acl_allows(&[], &other).unwrap());
+
+        let [owner, allowed, denied] = [(); 3]
+            .map(|_| KvsClient::connect(&repository, Secret::new(KeyType::Ed25519)).unwrap());
+        owner
+            .create("listed", b"listed", true, "text/plain")
+            .unwrap();
+        let address = format!("{}:listed", owner.token().unwrap().get_addr());
+        assert!(denied.read(&address).is_ok());
+        let entries = vec![allowed.token().unwrap().get_addr()];
+        owner
+            .request(&AclAction {
+                token: owner.token().unwrap(),
+                key: "listed".to_string(),
+                op: AclOp::Set(entries),
+            })
+            .unwrap();
+        assert_eq!(allowed.read(&address).unwrap().0, b"listed");
+        assert!(denied.read(&address).is_err());
+        assert_eq!(owner.read("listed").unwrap().0, b"listed");
+    }
+}
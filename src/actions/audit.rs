@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{query_audit_log, AuditQuery, AuditRecord},
+    config::get_audit_log_path,
+    errors::KVSResult,
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, Session},
+};
+
+use super::{Actions, KVSToken};
+
+/// At most this many of the latest records are replied.
+const MAX_AUDIT_RECORDS: usize = 1000;
+
+/// Query the audit log records of the token's own scope.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditAction {
+    pub token: KVSToken,
+    /// Unix time in milliseconds.
+    pub since: Option<i64>,
+    pub action: Option<String>,
+}
+
+impl KVSAction<Vec<AuditRecord>> for AuditAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<Vec<AuditRecord>> {
+        let AuditAction {
+            token,
+            since,
+            action,
+        } = self;
+        let mut records = query_audit_log(
+            get_audit_log_path()?,
+            &AuditQuery {
+                scope: Some(token.get_addr()),
+                since: *since,
+                action: action.clone(),
+            },
+        )?;
+        if records.len() > MAX_AUDIT_RECORDS {
+            records.drain(..records.len() - MAX_AUDIT_RECORDS);
+        }
+        Ok(records)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<Vec<AuditRecord>> {
+        session.write(&Actions::AuditAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<Vec<AuditRecord>>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(records) => Ok(records),
+        }
+    }
+}
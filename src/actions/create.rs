@@ -3,16 +3,20 @@ use std::path::Path;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{get_or_create_data_dir, get_or_create_secret},
+    at_rest,
+    config::get_or_create_secret,
     errors::{KVSError, KVSResult},
     kv_session::{KVSSession, NONCE},
+    secret::Secret,
     spec::{KVPayloadResult, KVSAction, ReplyCode, Session},
     utils::{sha256, to_u8str},
 };
 
 use aes_gcm::{aead::Aead, Aes256Gcm, Key, NewAead, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
 
-use super::{Actions, KVSToken};
+use super::{namespace_dir, Actions, KVSToken, TeamRole};
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KeyMeta {
     pub mime: String,
@@ -21,21 +25,206 @@ pub struct KeyMeta {
     pub name: String,
     pub rand: Option<Vec<u8>>,
     pub original_hash: Vec<u8>,
+    #[serde(default)]
+    pub shares: Vec<KeyShare>,
+    /// Scopes and `team:<name>` entries allowed to read a public value,
+    /// everyone when `None`.
+    #[serde(default)]
+    pub acl: Option<Vec<String>>,
+    #[serde(default)]
+    pub envelope: Envelope,
+    /// The owner's signature of a public value, see `KeyMeta::signed_payload`.
+    #[serde(default)]
+    pub signature: Option<Vec<u8>>,
+}
+
+/// How the `rand` of a private value is wrapped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Envelope {
+    /// AES-GCM keyed by the first bytes of the private key (or the team key)
+    /// with the fixed session nonce.
+    #[default]
+    Legacy,
+    /// AES-GCM keyed by HKDF-SHA256 over the private key (or the team key),
+    /// stored as `salt ++ nonce ++ ciphertext`.
+    Hkdf,
+}
+
+const WRAP_SALT_LENGTH: usize = 16;
+const WRAP_NONCE_LENGTH: usize = 12;
+
+fn wrap_cipher(key_material: &[u8], salt: &[u8]) -> KVSResult<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), key_material)
+        .expand(b"kvs rand wrap", &mut key)
+        .map_err(|_| KVSError::LogicError("HKDF Error".to_string()))?;
+    Ok(Aes256Gcm::new(Key::from_slice(&key)))
+}
+
+/// A copy of a private value's `rand` wrapped for another identity.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyShare {
+    pub scope: String,
+    pub rand: Vec<u8>,
+}
+
+/// The meta layout written before shares existed.
+#[derive(Deserialize)]
+struct LegacyKeyMeta {
+    mime: String,
+    size: u64,
+    owner: Vec<u8>,
+    name: String,
+    rand: Option<Vec<u8>>,
+    original_hash: Vec<u8>,
+}
+
+impl From<LegacyKeyMeta> for KeyMeta {
+    fn from(meta: LegacyKeyMeta) -> Self {
+        KeyMeta {
+            mime: meta.mime,
+            size: meta.size,
+            owner: meta.owner,
+            name: meta.name,
+            rand: meta.rand,
+            original_hash: meta.original_hash,
+            shares: vec![],
+            acl: None,
+            envelope: Envelope::Legacy,
+            signature: None,
+        }
+    }
 }
 
 impl KeyMeta {
     pub fn from_file<P: AsRef<Path>>(meta_file_path: P) -> KVSResult<KeyMeta> {
-        let meta = std::fs::read(meta_file_path)?;
-        KVSSession::to::<KeyMeta>(&meta)
+        let meta = at_rest::read(meta_file_path)?;
+        // fields were appended over time, a meta has those of the version that saved it
+        let mut reader = meta.as_slice();
+        let mut key_meta: KeyMeta =
+            bincode::deserialize_from::<_, LegacyKeyMeta>(&mut reader)?.into();
+        if !reader.is_empty() {
+            key_meta.shares = bincode::deserialize_from(&mut reader)?;
+        }
+        if !reader.is_empty() {
+            key_meta.acl = bincode::deserialize_from(&mut reader)?;
+        }
+        if !reader.is_empty() {
+            key_meta.envelope = bincode::deserialize_from(&mut reader)?;
+        }
+        if !reader.is_empty() {
+            key_meta.signature = bincode::deserialize_from(&mut reader)?;
+        }
+        Ok(key_meta)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, meta_file_path: P) -> KVSResult<()> {
+        at_rest::write(meta_file_path, &bincode::serialize(self)?)
+    }
+
+    /// What the owner of a public value signs, the content is bound by
+    /// `original_hash`.
+    pub fn signed_payload(&self) -> KVSResult<Vec<u8>> {
+        Ok(bincode::serialize(&(
+            "kvs public value",
+            &self.owner,
+            &self.name,
+            &self.mime,
+            self.size,
+            &self.original_hash,
+        ))?)
+    }
+
+    pub fn sign(&mut self, secret: &Secret) -> KVSResult<()> {
+        self.signature = Some(Secret::sign_with_priv_key_bits(
+            &secret.priv_key_bits,
+            &self.signed_payload()?,
+        )?);
+        Ok(())
+    }
+
+    /// Check the owner's signature and that `content` is the signed one.
+    pub fn verify(&self, pub_key: &[u8], content: &[u8]) -> KVSResult<bool> {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return Ok(false),
+        };
+        Ok(
+            Secret::verify_with_pub_key_bits(pub_key, &self.signed_payload()?, signature)
+                && sha256(content) == self.original_hash,
+        )
+    }
+
+    /// Wrap `rand` as `Envelope::Hkdf` with the private key or the team key.
+    pub fn wrap_rand(key_material: &[u8], rand: &[u8]) -> KVSResult<Vec<u8>> {
+        let salt = rand::random::<[u8; WRAP_SALT_LENGTH]>();
+        let nonce = rand::random::<[u8; WRAP_NONCE_LENGTH]>();
+        let wrapped = wrap_cipher(key_material, &salt)?.encrypt(Nonce::from_slice(&nonce), rand)?;
+        Ok([&salt[..], &nonce[..], &wrapped].concat())
+    }
+
+    fn unwrap(&self, rand: &[u8], key_material: &[u8], legacy_key: &[u8]) -> KVSResult<Vec<u8>> {
+        match self.envelope {
+            Envelope::Legacy => {
+                let cipher = Aes256Gcm::new(Key::from_slice(legacy_key));
+                Ok(cipher.decrypt(Nonce::from_slice(NONCE), rand)?)
+            }
+            Envelope::Hkdf => {
+                if rand.len() < WRAP_SALT_LENGTH + WRAP_NONCE_LENGTH {
+                    return Err(KVSError::LogicError(format!(
+                        "The key: `{}` has an illegal envelope.",
+                        self.name
+                    )));
+                }
+                let (salt, rand) = rand.split_at(WRAP_SALT_LENGTH);
+                let (nonce, rand) = rand.split_at(WRAP_NONCE_LENGTH);
+                Ok(wrap_cipher(key_material, salt)?.decrypt(Nonce::from_slice(nonce), rand)?)
+            }
+        }
+    }
+
+    /// Recover the plain `rand` of a private team value with the team key.
+    pub fn unwrap_team_rand(&self, team_key: &[u8]) -> KVSResult<Option<Vec<u8>>> {
+        match &self.rand {
+            Some(rand) => Ok(Some(self.unwrap(rand, team_key, team_key)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Recover the plain `rand` of a private value, either as its owner or
+    /// through a share addressed to `scope`.
+    pub fn unwrap_rand(&self, secret: &Secret, scope: &str) -> KVSResult<Option<Vec<u8>>> {
+        let rand = match &self.rand {
+            Some(rand) => rand,
+            None => return Ok(None),
+        };
+        if format!("0x{}", to_u8str(&self.owner)) == scope {
+            return Ok(Some(self.unwrap(
+                rand,
+                &secret.priv_key_bits,
+                &secret.legacy_wrap_key(),
+            )?));
+        }
+        match self.shares.iter().find(|share| share.scope == scope) {
+            Some(share) => Ok(Some(Secret::decrypt_width_priv_key_bits(
+                &secret.priv_key_bits,
+                &share.rand,
+            )?)),
+            None => Err(KVSError::LogicError(format!(
+                "The key: `{}` is not shared with {}.",
+                self.name, scope
+            ))),
+        }
     }
 }
 
-// impl KeyMeta {
-//     pub fn mime(&self) -> KVSResult<mime::Mime> {
-//         let result = self.mime.parse()?;
-//         Ok(result)
-//     }
-// }
+impl KeyMeta {
+    /// The parsed `mime` field, values stored by older versions may carry
+    /// names that are not mime types.
+    pub fn mime(&self) -> KVSResult<mime::Mime> {
+        Ok(self.mime.parse()?)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CreateAction {
@@ -43,24 +232,32 @@ pub struct CreateAction {
     pub key: String,
     pub meta: KeyMeta,
     pub value: Vec<u8>,
+    pub team: Option<String>,
+    /// Client side key material wrapping `meta.rand`, the private key of the
+    /// identity when `None`.
+    #[serde(skip)]
+    pub wrap_key: Option<Vec<u8>>,
+    /// The identity of the client, the identity of the profile when `None`.
+    #[serde(skip)]
+    pub identity: Option<Secret>,
 }
 
-impl KVSAction<()> for CreateAction {
-    fn serve(&mut self, session: &mut impl Session) -> KVSResult<()> {
+impl KVSAction<ReplyCode> for CreateAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
         let CreateAction {
             token,
             key,
             value,
             meta,
+            team,
+            ..
         } = self;
         let KVSToken { id, .. } = token;
         meta.owner = id.clone();
         let id_str = token.get_addr();
         let o_key = key.clone();
         let key = to_u8str(&sha256(key.as_bytes()));
-        let data_dir_path = get_or_create_data_dir()?;
-        let data_user_dir_path = data_dir_path.clone().join(&id_str);
-        let kv_path = data_user_dir_path.clone().join(&key);
+        let kv_path = namespace_dir(token, team, TeamRole::Writer)?.join(&key);
         if kv_path.exists() {
             return Err(KVSError::LogicError(format!(
                 "The key: `{}` arealy exists.",
@@ -68,33 +265,109 @@ impl KVSAction<()> for CreateAction {
             )));
         } else {
             std::fs::create_dir_all(&kv_path)?;
-            std::fs::write(kv_path.join("meta"), bincode::serialize(&meta)?)?;
-            std::fs::write(kv_path.join("value"), value)?;
+            meta.save(kv_path.join("meta"))?;
+            at_rest::write(kv_path.join("value"), value)?;
             tracing::info!("[{}] Create Key: {} ({})", id_str, key, o_key);
-            session.write(&KVPayloadResult::Ok(ReplyCode::Ok))?
         }
-        Ok(())
+        Ok(ReplyCode::Ok)
     }
 
-    fn request(&mut self, session: &mut impl Session) -> KVSResult<()> {
-        let secret = get_or_create_secret()?;
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+        let secret = match &self.identity {
+            Some(identity) => identity.clone(),
+            None => get_or_create_secret()?,
+        };
         if let Some(rand) = &self.meta.rand {
             let key = Key::from_slice(rand.as_slice());
             let cipher = Aes256Gcm::new(key);
             self.value = cipher.encrypt(Nonce::from_slice(NONCE), &*self.value)?;
-            let key = Key::from_slice(&secret.priv_key_bits[..32]);
-            let cipher = Aes256Gcm::new(key);
-            self.meta.rand = Some(cipher.encrypt(Nonce::from_slice(NONCE), rand.as_slice())?);
+            let key_material = match &self.wrap_key {
+                Some(wrap_key) => wrap_key,
+                None => &secret.priv_key_bits,
+            };
+            self.meta.rand = Some(KeyMeta::wrap_rand(key_material, rand)?);
+            self.meta.envelope = Envelope::Hkdf;
+        } else {
+            self.meta.owner = self.token.id.clone();
+            self.meta.sign(&secret)?;
         }
 
         session.write(&Actions::CreateKeyValue(self.clone()))?;
         let reply = session.read_vec()?;
         match KVSSession::to::<KVPayloadResult<ReplyCode>>(&reply)? {
-            KVPayloadResult::Err(error) => {
-                tracing::error!("{}", error);
-                Ok(())
-            }
-            KVPayloadResult::Ok(_) => Ok(()),
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Envelope, KeyMeta, NONCE};
+    use crate::{
+        secret::{KeyType, Secret},
+        utils::sha256,
+    };
+    use aes_gcm::{aead::Aead, Aes256Gcm, Key, NewAead, Nonce};
+
+    #[test]
+    fn test_rand_envelope() {
+        let team_key = rand::random::<[u8; 32]>();
+        let rand = rand::random::<[u8; 32]>();
+        let legacy_rand = Aes256Gcm::new(Key::from_slice(&team_key))
+            .encrypt(Nonce::from_slice(NONCE), &rand[..])
+            .unwrap();
+        let mut meta = KeyMeta {
+            mime: "text/plain".to_string(),
+            size: 0,
+            owner: vec![],
+            name: "a".to_string(),
+            rand: Some(legacy_rand),
+            original_hash: vec![],
+            shares: vec![],
+            acl: None,
+            envelope: Envelope::Legacy,
+            signature: None,
+        };
+        assert_eq!(meta.unwrap_team_rand(&team_key).unwrap().unwrap(), rand);
+
+        let wrapped = KeyMeta::wrap_rand(&team_key, &rand).unwrap();
+        assert_ne!(wrapped, KeyMeta::wrap_rand(&team_key, &rand).unwrap());
+        meta.rand = Some(wrapped);
+        meta.envelope = Envelope::Hkdf;
+        assert_eq!(meta.unwrap_team_rand(&team_key).unwrap().unwrap(), rand);
+        assert!(meta.unwrap_team_rand(&[0; 32]).is_err());
+
+        // a meta saved before envelopes existed reads as legacy
+        let meta_file_path = std::env::temp_dir().join("kvs_test_rand_envelope_meta");
+        let bytes = bincode::serialize(&meta).unwrap();
+        std::fs::write(&meta_file_path, &bytes[..bytes.len() - 5]).unwrap();
+        let saved = KeyMeta::from_file(&meta_file_path).unwrap();
+        std::fs::remove_file(&meta_file_path).unwrap();
+        assert_eq!(saved.envelope, Envelope::Legacy);
+        assert_eq!(saved.rand, meta.rand);
+    }
+
+    #[test]
+    fn test_publisher_signature() {
+        let secret = Secret::new(KeyType::Ed25519);
+        let mut meta = KeyMeta {
+            mime: "text/plain".to_string(),
+            size: 3,
+            owner: vec![1],
+            name: "a".to_string(),
+            rand: None,
+            original_hash: sha256(b"kvs"),
+            shares: vec![],
+            acl: None,
+            envelope: Envelope::Legacy,
+            signature: None,
+        };
+        assert!(!meta.verify(&secret.pub_key_bits, b"kvs").unwrap());
+        meta.sign(&secret).unwrap();
+        assert!(meta.verify(&secret.pub_key_bits, b"kvs").unwrap());
+        assert!(!meta.verify(&secret.pub_key_bits, b"kvs!").unwrap());
+        meta.name = "b".to_string();
+        assert!(!meta.verify(&secret.pub_key_bits, b"kvs").unwrap());
+    }
+}
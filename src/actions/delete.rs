@@ -4,28 +4,27 @@ use crate::{
     errors::KVSError,
     kv_session::KVSSession,
     spec::{KVPayloadResult, KVSAction, ReplyCode},
-    utils::{sha256, to_u8str}, config::get_or_create_data_dir,
+    utils::{sha256, to_u8str},
 };
 
-use super::{Actions, KVSToken};
+use super::{namespace_dir, Actions, KVSToken, TeamRole};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DeleteAction {
     pub token: KVSToken,
     pub key: String,
+    pub team: Option<String>,
 }
 
 impl KVSAction<ReplyCode> for DeleteAction {
     fn serve(&mut self, _: &mut impl crate::spec::Session) -> crate::errors::KVSResult<ReplyCode> {
-        let DeleteAction { key, token } = self;
+        let DeleteAction { key, token, team } = self;
         let KVSToken { id, .. } = token;
-        let id_str = ["0x", &to_u8str(&id)].concat();
+        let id_str = ["0x", &to_u8str(id)].concat();
         let o_key = key.clone();
         let key = to_u8str(&sha256(key.as_bytes()));
 
-        let data_dir_path = get_or_create_data_dir()?;
-        let data_user_dir_path = data_dir_path.clone().join(&id_str);
-        let kv_path = data_user_dir_path.clone().join(&key);
+        let kv_path = namespace_dir(token, team, TeamRole::Writer)?.join(&key);
         if !kv_path.exists() {
             Err(KVSError::LogicError(format!(
                 "The key: `{}` is not exists.",
@@ -46,7 +45,7 @@ impl KVSAction<ReplyCode> for DeleteAction {
         let bytes = session.read_vec()?;
         let reply = KVSSession::to::<KVPayloadResult<ReplyCode>>(&bytes)?;
         match reply {
-            KVPayloadResult::Err(error) => Err(KVSError::LogicError(error.to_string())),
+            KVPayloadResult::Err(error) => Err(error.into()),
             KVPayloadResult::Ok(_) => Ok(ReplyCode::Ok),
         }
     }
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{get_or_create_jwt_secret, get_or_create_secret},
+    config::{get_or_create_identities_dir, get_or_create_jwt_secret, get_or_create_secret},
     errors::{KVSError, KVSResult},
     kv_session::KVSSession,
     secret::Secret,
@@ -9,11 +9,14 @@ use crate::{
     utils::{sgin, to_u8str},
 };
 
-use super::Actions;
+use super::{signed::require_signatures, Actions, TokenClaims};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FetchTokenAction {
     pub pub_key: Vec<u8>,
+    /// The private key answering the nonce, the local identity when `None`.
+    #[serde(skip)]
+    pub priv_key_bits: Option<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -21,26 +24,64 @@ pub struct KVSToken {
     pub id: Vec<u8>, // len 20
     pub time_stamp: i64,
     pub sign: Vec<u8>, // len 32
+    /// The limits of a derived token, `None` for a full token.
+    pub claims: Option<TokenClaims>,
 }
 
 impl KVSToken {
     pub fn get_addr(&self) -> String {
         format!("0x{}", to_u8str(&self.id))
     }
+
+    pub fn signed(
+        id: Vec<u8>,
+        time_stamp: i64,
+        claims: Option<TokenClaims>,
+        jwt_secret: &[u8],
+    ) -> KVSResult<KVSToken> {
+        let sign = KVSToken::sign(&id, time_stamp, &claims, jwt_secret)?;
+        Ok(KVSToken {
+            id,
+            time_stamp,
+            sign,
+            claims,
+        })
+    }
+
+    /// Hash the fields, each prefixed by its length, with the secret of the
+    /// server.
+    pub fn sign(
+        id: &[u8],
+        time_stamp: i64,
+        claims: &Option<TokenClaims>,
+        jwt_secret: &[u8],
+    ) -> KVSResult<Vec<u8>> {
+        let claims = match claims {
+            Some(claims) => bincode::serialize(claims)?,
+            None => vec![],
+        };
+        let fields: [&[u8]; 4] = [id, &time_stamp.to_be_bytes(), &claims, jwt_secret];
+        let mut framed = vec![];
+        for field in fields {
+            framed.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            framed.extend_from_slice(field);
+        }
+        Ok(sgin(&framed))
+    }
 }
 
 impl KVSAction<KVSToken> for FetchTokenAction {
     fn serve(&mut self, session: &mut impl Session) -> KVSResult<KVSToken> {
         let jwt_secret = get_or_create_jwt_secret(false)?;
-        if self.pub_key.len() != 162 {
+        if !Secret::is_pub_key(&self.pub_key) {
             return Err(KVSError::LogicError("Illegal public key".to_string()));
         }
         let addr = sgin(&self.pub_key);
         let addr_str = to_u8str(&addr);
         tracing::info!("[0x{}] fetch_token", addr_str);
         let nonce = (0..32).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
-        let nonce_encrypt = Secret::encrypt_with_pub_key_bits(&self.pub_key, &nonce);
-        session.write_vec(&nonce_encrypt)?;
+        let nonce_encrypt = Secret::encrypt_with_pub_key_bits(&self.pub_key, &nonce)?;
+        session.write(&KVPayloadResult::Ok(nonce_encrypt))?;
         let c_nonce = session.read_vec()?;
         if c_nonce != nonce {
             return Err(KVSError::LogicError(format!(
@@ -49,39 +90,39 @@ impl KVSAction<KVSToken> for FetchTokenAction {
             )));
         }
 
+        let scope = format!("0x{}", addr_str);
+        let pub_key_file_path = get_or_create_identities_dir()?.join(&scope);
+        let registered = pub_key_file_path.exists();
+        std::fs::write(pub_key_file_path, &self.pub_key)?;
+        // new scopes sign every request from their first login
+        if !registered {
+            require_signatures(&scope)?;
+        }
+
         let time_stamp = chrono::Local::now().timestamp_millis();
 
-        let token = KVSToken {
-            id: addr.clone(),
-            time_stamp,
-            sign: sgin(
-                &[
-                    addr.clone(),
-                    time_stamp.to_be_bytes().to_vec(),
-                    jwt_secret.to_vec(),
-                ]
-                .concat(),
-            ),
-        };
-        Ok(token)
+        KVSToken::signed(addr, time_stamp, None, &jwt_secret)
     }
 
     fn request(&mut self, session: &mut impl Session) -> KVSResult<KVSToken> {
-        let secret = get_or_create_secret()?;
+        let priv_key_bits = match &self.priv_key_bits {
+            Some(priv_key_bits) => priv_key_bits.clone(),
+            None => get_or_create_secret()?.priv_key_bits,
+        };
         // 1. c -> s [fetch_token,public_key]
         let fetch_token_payload = Actions::FetchToken(self.clone());
 
         session.write(&fetch_token_payload)?;
         // 2. s -> c [random_nonce]
-        let random_nonce = session.read_vec()?;
+        let random_nonce = KVSSession::to::<KVPayloadResult<Vec<u8>>>(&session.read_vec()?)??;
         tracing::debug!("random_nonce {:x?}", random_nonce);
-        let c_nonce = Secret::decrypt_width_priv_key_bits(&secret.priv_key_bits, &random_nonce)?;
+        let c_nonce = Secret::decrypt_width_priv_key_bits(&priv_key_bits, &random_nonce)?;
         // 3. c -> s [random_sign]
         session.write_vec(&c_nonce)?;
         // 4. s -> c [jwt_token, addr,time_stamp,sign]
         let token_bytes = session.read_vec()?;
         match KVSSession::to::<KVPayloadResult<KVSToken>>(&token_bytes)? {
-            KVPayloadResult::Err(error) => Err(KVSError::LogicError(error)),
+            KVPayloadResult::Err(error) => Err(error.into()),
             KVPayloadResult::Ok(token) => Ok(token),
         }
     }
@@ -1,24 +1,26 @@
-use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
-use std::fmt::Debug;
+use std::{fmt::Debug, time::UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 
-use walkdir::WalkDir;
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
 
 use crate::{
     config::get_or_create_data_dir,
     errors::{KVSError, KVSResult},
     kv_session::KVSSession,
     spec::{KVPayloadResult, KVSAction},
-    utils::{sha256, to_u8str},
+    utils::{is_scope_addr, sha256, to_u8str},
 };
 
-use super::{Actions, KVSToken, KeyMeta};
+use super::{acl_allows, forwarded_to, namespace_dir, Actions, KVSToken, KeyMeta, TeamRole};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ListAction {
     pub token: KVSToken,
+    pub team: Option<String>,
+    /// List the keys of another scope readable by the token.
+    pub scope: Option<String>,
 }
 
 impl KVSAction<Vec<KeyMeta>> for ListAction {
@@ -26,9 +28,22 @@ impl KVSAction<Vec<KeyMeta>> for ListAction {
         &mut self,
         _: &mut impl crate::spec::Session,
     ) -> crate::errors::KVSResult<Vec<KeyMeta>> {
-        let ListAction { token } = self;
-        let addr = token.get_addr();
-        let addr_path = get_or_create_data_dir()?.join(addr);
+        let ListAction { token, team, scope } = self;
+        let addr_path = match scope {
+            Some(scope) => {
+                if !is_scope_addr(scope) {
+                    return Err(KVSError::LogicError(format!("Illegal scope: {}", scope)));
+                }
+                if let Some(record) = forwarded_to(scope)? {
+                    return Err(KVSError::LogicError(format!(
+                        "The scope: `{}` moved to {}.",
+                        scope, record.to
+                    )));
+                }
+                get_or_create_data_dir()?.join(scope)
+            }
+            None => namespace_dir(token, team, TeamRole::Reader)?,
+        };
         if !addr_path.exists() {
             return Ok(vec![]);
         }
@@ -36,10 +51,36 @@ impl KVSAction<Vec<KeyMeta>> for ListAction {
             .filter_map(|p| p.ok())
             .collect::<Vec<_>>();
 
-        Ok(key_files
+        let mut metas = key_files
             .par_iter()
-            .map(|file_path| KeyMeta::from_file(file_path.path().join("meta")).unwrap())
-            .collect::<Vec<_>>())
+            .map(|file_path| KeyMeta::from_file(file_path.path().join("meta")))
+            .collect::<KVSResult<Vec<_>>>()?;
+        if let Some(claims) = &token.claims {
+            metas.retain(|meta| claims.allows_key(&meta.name));
+        }
+        if scope.is_some() {
+            // the keys `ReadAction` would serve, without the others' shares
+            let id_str = token.get_addr();
+            let mut readable = vec![];
+            for mut meta in metas {
+                if meta.owner == token.id {
+                    readable.push(meta);
+                    continue;
+                }
+                let allowed = match (&meta.rand, &meta.acl) {
+                    (Some(_), _) => meta.shares.iter().any(|share| share.scope == id_str),
+                    (None, Some(acl)) => acl_allows(acl, &id_str)?,
+                    (None, None) => true,
+                };
+                if allowed {
+                    meta.shares.retain(|share| share.scope == id_str);
+                    meta.acl = None;
+                    readable.push(meta);
+                }
+            }
+            metas = readable;
+        }
+        Ok(metas)
     }
 
     fn request(
@@ -51,12 +92,74 @@ impl KVSAction<Vec<KeyMeta>> for ListAction {
 
         let reply = KVSSession::to::<KVPayloadResult<Vec<KeyMeta>>>(&bytes)?;
         match reply {
-            KVPayloadResult::Err(error) => Err(KVSError::LogicError(error.to_string())),
+            KVPayloadResult::Err(error) => Err(error.into()),
             KVPayloadResult::Ok(reply) => Ok(reply),
         }
     }
 }
 
+/// A stamp of the keys `ListAction` lists in a scope or a team, it changes
+/// whenever one of them is created, updated, shared or deleted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListStampAction {
+    pub token: KVSToken,
+    pub team: Option<String>,
+}
+
+impl KVSAction<Vec<u8>> for ListStampAction {
+    fn serve(&mut self, _: &mut impl crate::spec::Session) -> KVSResult<Vec<u8>> {
+        let ListStampAction { token, team } = self;
+        let addr_path = namespace_dir(token, team, TeamRole::Reader)?;
+        let mut stamp = vec![];
+        if addr_path.exists() {
+            let mut key_dirs = std::fs::read_dir(addr_path)?
+                .filter_map(|p| p.ok())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>();
+            key_dirs.sort();
+            for key_dir in key_dirs {
+                stamp.extend(key_dir.display().to_string().as_bytes());
+                for file in ["meta", "value"] {
+                    let metadata = std::fs::metadata(key_dir.join(file))?;
+                    let modified = metadata
+                        .modified()?
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_nanos();
+                    stamp.extend(modified.to_le_bytes());
+                    stamp.extend(metadata.len().to_le_bytes());
+                }
+            }
+        }
+        Ok(sha256(&stamp))
+    }
+
+    fn request(&mut self, session: &mut impl crate::spec::Session) -> KVSResult<Vec<u8>> {
+        session.write(&Actions::ListStampAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+
+        let reply = KVSSession::to::<KVPayloadResult<Vec<u8>>>(&bytes)?;
+        match reply {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
+        }
+    }
+}
+
+/// The gitignore syntax file of the files sync leaves out.
+pub const KVS_IGNORE_FILE_NAME: &str = ".kvsignore";
+
+/// Which files under a directory sync looks at. `.git` is always left out,
+/// the globs leave out more files than the ignore files do, never fewer.
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    /// Only the files matching one of these, when there are any.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Honor `.gitignore` files as well as `.kvsignore` files.
+    pub gitignore: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LocalFileMeta {
     pub name: String,
@@ -76,38 +179,129 @@ impl Debug for LocalFileMeta {
 }
 
 impl LocalFileMeta {
-    pub fn get_all_files_meta(target_path: &str) -> KVSResult<Vec<LocalFileMeta>> {
+    /// The files under `target_path` as `(key name, path)` passing `filter`,
+    /// nothing is read.
+    pub fn get_all_files(
+        target_path: &str,
+        filter: &FileFilter,
+    ) -> KVSResult<Vec<(String, String)>> {
         let cwd = std::env::current_dir()?;
         let target_path = relative_path::RelativePath::new(target_path);
         let target_path = target_path.to_logical_path(cwd);
-        let files_path = WalkDir::new(&target_path)
-            .into_iter()
-            .filter(|entry| entry.is_ok())
-            .map(|entry| entry.unwrap())
+        let mut overrides = OverrideBuilder::new(&target_path);
+        for glob in filter.include.iter() {
+            overrides.add(glob)?;
+        }
+        for glob in filter.exclude.iter() {
+            overrides.add(&format!("!{}", glob))?;
+        }
+        let globs = overrides.build()?;
+        let walker = WalkBuilder::new(&target_path)
+            .standard_filters(false)
+            .git_ignore(filter.gitignore)
+            .require_git(false)
+            .add_custom_ignore_filename(KVS_IGNORE_FILE_NAME)
+            .filter_entry(move |entry| {
+                let is_dir = entry
+                    .file_type()
+                    .is_some_and(|file_type| file_type.is_dir());
+                entry.file_name() != ".git" && !globs.matched(entry.path(), is_dir).is_ignore()
+            })
+            .build();
+        Ok(walker
+            .flatten()
             .filter(|entry| !entry.path().is_dir())
             .map(|entry| {
                 let entry_path = entry.path().display().to_string();
                 (
-                    entry_path.clone(),
                     entry_path
                         .clone()
                         .replace(&target_path.as_path().display().to_string(), ""),
+                    entry_path,
                 )
             })
-            .collect::<Vec<_>>();
-        tracing::info!("analysis local files");
-        Ok(files_path
-            .par_iter()
-            .progress_count(files_path.len() as u64)
-            .map(|(entry_path, path)| {
-                let bytes = std::fs::read(entry_path).unwrap();
-                LocalFileMeta {
-                    name: path.clone(),
-                    original_hash: sha256(&bytes),
-                    size: bytes.len() as u64,
-                    path: entry_path.to_string(),
-                }
-            })
             .collect::<Vec<_>>())
     }
+
+    /// Read a file once, for its meta and its content.
+    pub fn read(name: &str, path: &str) -> KVSResult<(LocalFileMeta, Vec<u8>)> {
+        let bytes = std::fs::read(path)?;
+        let meta = LocalFileMeta {
+            name: name.to_string(),
+            original_hash: sha256(&bytes),
+            size: bytes.len() as u64,
+            path: path.to_string(),
+        };
+        Ok((meta, bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{FileFilter, LocalFileMeta};
+    use crate::kv_server::test::test_dir;
+
+    fn names(dir: &str, filter: &FileFilter) -> Vec<String> {
+        let mut names = LocalFileMeta::get_all_files(dir, filter)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_file_filter() {
+        let dir = test_dir("file_filter");
+        for (file, content) in [
+            (".kvsignore", "*.log\n"),
+            (".gitignore", "build/\n"),
+            (".git/config", ""),
+            ("a.txt", ""),
+            ("b.md", ""),
+            ("c.log", ""),
+            ("build/out.txt", ""),
+            ("sub/d.txt", ""),
+        ] {
+            let path = Path::new(&dir).join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let all = [
+            "/.gitignore",
+            "/.kvsignore",
+            "/a.txt",
+            "/b.md",
+            "/build/out.txt",
+            "/sub/d.txt",
+        ];
+        assert_eq!(names(&dir, &FileFilter::default()), all);
+
+        let gitignore = FileFilter {
+            gitignore: true,
+            ..FileFilter::default()
+        };
+        assert!(!names(&dir, &gitignore).contains(&"/build/out.txt".to_string()));
+
+        // the globs can not bring back an ignored file
+        let include = FileFilter {
+            include: vec!["*.txt".to_string(), "*.log".to_string()],
+            ..FileFilter::default()
+        };
+        assert_eq!(
+            names(&dir, &include),
+            ["/a.txt", "/build/out.txt", "/sub/d.txt"]
+        );
+
+        // an excluded file is left out even when included
+        let exclude = FileFilter {
+            include: vec!["*.txt".to_string()],
+            exclude: vec!["sub".to_string(), "a.txt".to_string()],
+            ..FileFilter::default()
+        };
+        assert_eq!(names(&dir, &exclude), ["/build/out.txt"]);
+    }
 }
@@ -1,28 +1,138 @@
+mod acl;
+mod audit;
 mod create;
 mod delete;
 mod fetch_token;
 mod list;
+mod pub_key;
 mod read;
 mod remote_version;
+mod rewrap;
+mod rotate;
+mod share;
+mod signed;
+mod team;
+mod token;
 mod update;
 
-pub use create::{CreateAction, KeyMeta};
+pub use acl::{acl_allows, AclAction, AclOp};
+pub use audit::AuditAction;
+pub use create::{CreateAction, Envelope, KeyMeta, KeyShare};
 pub use delete::DeleteAction;
 pub use fetch_token::{FetchTokenAction, KVSToken};
-pub use list::{ListAction, LocalFileMeta};
-pub use read::{CatReply, ReadAction};
+pub use list::{FileFilter, ListAction, ListStampAction, LocalFileMeta};
+pub use pub_key::PubKeyAction;
+pub use read::ReadAction;
 pub use remote_version::RemoteVersionAction;
+pub use rewrap::{RewrapAction, RewrappedRand};
+pub use rotate::{forwarded_to, ForwardAction, ForwardRecord, InboundAction, RotateAction};
+pub use share::{ShareAction, UnshareAction};
+pub use signed::{requires_signatures, SignedRequest, SigningAction};
+pub use team::{
+    namespace_dir, TeamCreateAction, TeamInfoAction, TeamMember, TeamMeta, TeamRole,
+    TeamUpdateAction,
+};
+pub use token::{TokenClaims, TokenCreateAction, READ_ONLY_ACTIONS};
 pub use update::UpdateAction;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Actions {
-    FetchToken(FetchTokenAction),
-    CreateKeyValue(CreateAction),
-    CatAction(ReadAction),
-    DeleteAction(DeleteAction),
-    UpdateAction(UpdateAction),
-    RemoteVersionAction(RemoteVersionAction),
-    ListAction(ListAction),
+use crate::utils::{sha256, to_addr, to_u8str};
+
+/// Declare `Actions` with the name of every action in the audit log, the
+/// actions marked `public` take no token.
+macro_rules! actions {
+    ($($variant:ident($action:ident) = $name:literal $(, $public:ident)?;)*) => {
+        #[derive(Serialize, Deserialize, Debug)]
+        pub enum Actions {
+            $($variant($action),)*
+        }
+
+        impl Actions {
+            /// The name of every action and whether it takes a token.
+            pub const NAMES: &'static [(&'static str, bool)] =
+                &[$(($name, actions!(@takes_token $($public)?)),)*];
+
+            /// The token authenticating the action, `None` for the public actions.
+            pub fn token(&self) -> Option<&KVSToken> {
+                match self {
+                    $(Actions::$variant(_action) => actions!(@token _action $($public)?),)*
+                }
+            }
+
+            /// The name of the action in the audit log.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Actions::$variant(_) => $name,)*
+                }
+            }
+        }
+    };
+    (@takes_token public) => { false };
+    (@takes_token) => { true };
+    (@token $action:ident public) => { None };
+    (@token $action:ident) => { Some(&$action.token) };
+}
+
+actions! {
+    FetchToken(FetchTokenAction) = "login", public;
+    CreateKeyValue(CreateAction) = "create";
+    CatAction(ReadAction) = "read";
+    DeleteAction(DeleteAction) = "delete";
+    UpdateAction(UpdateAction) = "update";
+    RemoteVersionAction(RemoteVersionAction) = "remote", public;
+    ListAction(ListAction) = "list";
+    PubKeyAction(PubKeyAction) = "pub_key";
+    ShareAction(ShareAction) = "share";
+    UnshareAction(UnshareAction) = "unshare";
+    TeamCreateAction(TeamCreateAction) = "team_create";
+    TeamInfoAction(TeamInfoAction) = "team_info";
+    TeamUpdateAction(TeamUpdateAction) = "team_update";
+    AclAction(AclAction) = "acl";
+    AuditAction(AuditAction) = "audit";
+    TokenCreateAction(TokenCreateAction) = "token_create";
+    SigningAction(SigningAction) = "signing";
+    Signed(SignedRequest) = "signed", public;
+    RotateAction(RotateAction) = "rotate";
+    ForwardAction(ForwardAction) = "forward", public;
+    RewrapAction(RewrapAction) = "rewrap";
+    ListStampAction(ListStampAction) = "list_stamp";
+    InboundAction(InboundAction) = "inbound";
+}
+
+impl Actions {
+    /// The names of the actions taking a token.
+    pub fn token_names() -> impl Iterator<Item = &'static str> {
+        Actions::NAMES
+            .iter()
+            .filter(|(_, takes_token)| *takes_token)
+            .map(|(name, _)| *name)
+    }
+
+    /// The scope claimed by the action, the token is not verified yet.
+    pub fn scope(&self) -> Option<String> {
+        match self {
+            Actions::FetchToken(FetchTokenAction { pub_key, .. }) => Some(to_addr(pub_key)),
+            _ => self.token().map(|token| token.get_addr()),
+        }
+    }
+
+    /// The key the action works on.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Actions::CreateKeyValue(CreateAction { key, .. })
+            | Actions::CatAction(ReadAction { key, .. })
+            | Actions::DeleteAction(DeleteAction { key, .. })
+            | Actions::UpdateAction(UpdateAction { key, .. })
+            | Actions::ShareAction(ShareAction { key, .. })
+            | Actions::UnshareAction(UnshareAction { key, .. })
+            | Actions::AclAction(AclAction { key, .. }) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// The hash of the key the action works on, as stored on the server.
+    pub fn key_hash(&self) -> Option<String> {
+        self.key().map(|key| to_u8str(&sha256(key.as_bytes())))
+    }
 }
@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::get_or_create_identities_dir,
+    errors::{KVSError, KVSResult},
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, Session},
+    utils::is_scope_addr,
+};
+
+use super::{Actions, KVSToken};
+
+/// Look up the public key a scope registered when it last logged in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PubKeyAction {
+    pub token: KVSToken,
+    pub scope: String,
+}
+
+impl KVSAction<Vec<u8>> for PubKeyAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<Vec<u8>> {
+        let PubKeyAction { scope, .. } = self;
+        if !is_scope_addr(scope) {
+            return Err(KVSError::LogicError(format!("Illegal scope: {}", scope)));
+        }
+        let pub_key_file_path = get_or_create_identities_dir()?.join(&scope);
+        if !pub_key_file_path.exists() {
+            return Err(KVSError::LogicError(format!(
+                "The scope: `{}` has no registered public key.",
+                scope
+            )));
+        }
+        Ok(std::fs::read(pub_key_file_path)?)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<Vec<u8>> {
+        session.write(&Actions::PubKeyAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<Vec<u8>>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(pub_key) => Ok(pub_key),
+        }
+    }
+}
@@ -3,51 +3,110 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     actions::KeyMeta,
-    config::{get_or_create_data_dir, get_or_create_secret},
+    at_rest,
+    config::{get_or_create_data_dir, get_or_create_identities_dir, get_or_create_secret},
     errors::{KVSError, KVSResult},
     kv_session::{KVSSession, NONCE},
+    secret::Secret,
     spec::{KVPayloadResult, KVSAction, Session},
-    utils::{sha256, to_u8str},
+    utils::{is_scope_addr, sha256, to_addr, to_u8str},
 };
 
-use super::{Actions, KVSToken};
+use super::{acl_allows, forwarded_to, namespace_dir, Actions, KVSToken, TeamMeta, TeamRole};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ReadAction {
     pub token: KVSToken,
     pub key: String,
     pub scope: Option<String>,
+    pub team: Option<String>,
+    /// Skip the check of the publisher signature of public values.
+    #[serde(skip)]
+    pub no_verify: bool,
+    /// The identity of the client, the identity of the profile when `None`.
+    #[serde(skip)]
+    pub identity: Option<Secret>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CatReply {
     meta: KeyMeta,
     content: Vec<u8>,
+    /// The team key wrapped for the reader when reading a team key.
+    team_key: Option<Vec<u8>>,
+    /// The registered public key of the owner of a public value.
+    owner_pub_key: Option<Vec<u8>>,
 }
 
 impl CatReply {
-    pub fn content(&self) -> &Vec<u8> {
-        &self.content
+    pub fn into_parts(self) -> (Vec<u8>, KeyMeta) {
+        (self.content, self.meta)
+    }
+}
+
+impl ReadAction {
+    /// Check that a public value is signed by the scope it was read from,
+    /// the server can not forge the signature of another identity.
+    fn verify_publisher(&self, reply: &CatReply) -> KVSResult<()> {
+        let unverified = |reason: String| {
+            KVSError::LogicError(format!(
+                "The key: `{}` {}, use --no-verify to read it anyway.",
+                self.key, reason
+            ))
+        };
+        let owner = format!("0x{}", to_u8str(&reply.meta.owner));
+        let publisher = match (&self.scope, &self.team) {
+            (Some(scope), _) => Some(scope.clone()),
+            (None, None) => Some(self.token.get_addr()),
+            (None, Some(_)) => None,
+        };
+        if publisher.is_some_and(|publisher| publisher != owner) || reply.meta.name != self.key {
+            return Err(unverified(format!("is not published by {}", owner)));
+        }
+        if reply.meta.signature.is_none() {
+            return Err(unverified("has no publisher signature".to_string()));
+        }
+        let pub_key = match &reply.owner_pub_key {
+            Some(pub_key) if to_addr(pub_key) == owner => pub_key,
+            _ => return Err(unverified(format!("has no public key of {}", owner))),
+        };
+        if !reply.meta.verify(pub_key, &reply.content)? {
+            return Err(unverified(format!("has a bad signature of {}", owner)));
+        }
+        Ok(())
     }
 }
 
 impl KVSAction<CatReply> for ReadAction {
     fn serve(&mut self, _: &mut impl Session) -> KVSResult<CatReply> {
-        let ReadAction { key, token, scope } = self;
+        let ReadAction {
+            key,
+            token,
+            scope,
+            team,
+            ..
+        } = self;
         let KVSToken { id, .. } = token;
-        let id_str = ["0x", &to_u8str(&id)].concat();
+        let id_str = ["0x", &to_u8str(id)].concat();
         let o_key = key.clone();
         let key = to_u8str(&sha256(key.as_bytes()));
 
-        let data_dir_path = get_or_create_data_dir()?;
-
-        let scope = match scope {
-            Some(scope) => scope,
-            None => &id_str,
+        let data_user_dir_path = match (&scope, &team) {
+            (Some(scope), None) => {
+                if !is_scope_addr(scope) {
+                    return Err(KVSError::LogicError(format!("Illegal scope: {}", scope)));
+                }
+                if let Some(record) = forwarded_to(scope)? {
+                    return Err(KVSError::LogicError(format!(
+                        "The scope: `{}` moved to {}.",
+                        scope, record.to
+                    )));
+                }
+                get_or_create_data_dir()?.join(scope)
+            }
+            _ => namespace_dir(token, team, TeamRole::Reader)?,
         };
-
-        let data_user_dir_path = data_dir_path.clone().join(scope);
-        let kv_path = data_user_dir_path.clone().join(&key);
+        let kv_path = data_user_dir_path.join(&key);
 
         if !kv_path.exists() {
             Err(KVSError::LogicError(format!(
@@ -59,40 +118,86 @@ impl KVSAction<CatReply> for ReadAction {
             let content_file_path = kv_path.clone().join("value");
             tracing::debug!("want cat value in: {}", content_file_path.display());
             let meta_file_path = kv_path.clone().join("meta");
-            let meta = KeyMeta::from_file(meta_file_path)?;
-
-            // check owner
-            if meta.rand.is_some() {
-                if meta.owner != token.id {
+            let mut meta = KeyMeta::from_file(meta_file_path)?;
+
+            let team_key = match team {
+                Some(team) => TeamMeta::from_dir(team)?
+                    .member(&id_str)
+                    .map(|member| member.key.clone()),
+                None => None,
+            };
+
+            // check owner or shares, team members share the team key
+            if meta.rand.is_some() && meta.owner != token.id && team.is_none() {
+                if !meta.shares.iter().any(|share| share.scope == id_str) {
                     return Err(KVSError::LogicError(format!(
                         "The key: `{}` is private.",
                         o_key
                     )));
                 }
+                meta.shares.retain(|share| share.scope == id_str);
             }
 
-            let content = std::fs::read(content_file_path)?;
-            let send_content = CatReply { meta, content };
+            // check the access list of public values
+            if meta.rand.is_none() && meta.owner != token.id && team.is_none() {
+                if let Some(acl) = &meta.acl {
+                    if !acl_allows(acl, &id_str)? {
+                        return Err(KVSError::LogicError(format!(
+                            "The key: `{}` is not readable by {}.",
+                            o_key, id_str
+                        )));
+                    }
+                }
+                meta.acl = None;
+            }
+
+            let owner_pub_key_file_path =
+                get_or_create_identities_dir()?.join(format!("0x{}", to_u8str(&meta.owner)));
+            let owner_pub_key = match meta.rand.is_none() && owner_pub_key_file_path.exists() {
+                true => Some(std::fs::read(owner_pub_key_file_path)?),
+                false => None,
+            };
+
+            let content = at_rest::read(content_file_path)?;
+            let send_content = CatReply {
+                meta,
+                content,
+                team_key,
+                owner_pub_key,
+            };
             Ok(send_content)
         }
     }
 
     fn request(&mut self, session: &mut impl Session) -> KVSResult<CatReply> {
-        let secret = get_or_create_secret()?;
+        let secret = match &self.identity {
+            Some(identity) => identity.clone(),
+            None => get_or_create_secret()?,
+        };
         session.write(&Actions::CatAction(self.clone()))?;
         let bytes = session.read_vec()?;
 
         let reply = KVSSession::to::<KVPayloadResult<CatReply>>(&bytes)?;
         match reply {
-            KVPayloadResult::Err(error) => Err(KVSError::LogicError(error.to_string())),
+            KVPayloadResult::Err(error) => Err(error.into()),
             KVPayloadResult::Ok(mut reply) => {
-                if let Some(rand) = reply.meta.rand.clone() {
-                    let key = Key::from_slice(&secret.priv_key_bits[..32]);
-                    let cipher = Aes256Gcm::new(key);
-                    let rand = cipher.decrypt(Nonce::from_slice(NONCE), rand.as_slice())?;
-                    let key = Key::from_slice(rand.as_slice());
-                    let cipher = Aes256Gcm::new(key);
-                    reply.content = cipher.decrypt(Nonce::from_slice(NONCE), &*reply.content)?;
+                let rand = match &reply.team_key {
+                    Some(team_key) => {
+                        let team_key =
+                            Secret::decrypt_width_priv_key_bits(&secret.priv_key_bits, team_key)?;
+                        reply.meta.unwrap_team_rand(&team_key)?
+                    }
+                    None => reply.meta.unwrap_rand(&secret, &self.token.get_addr())?,
+                };
+                match rand {
+                    Some(rand) => {
+                        let key = Key::from_slice(rand.as_slice());
+                        let cipher = Aes256Gcm::new(key);
+                        reply.content =
+                            cipher.decrypt(Nonce::from_slice(NONCE), &*reply.content)?;
+                    }
+                    None if !self.no_verify => self.verify_publisher(&reply)?,
+                    None => (),
                 }
                 Ok(reply)
             }
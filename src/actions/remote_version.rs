@@ -1,7 +1,6 @@
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    errors::KVSError,
     kv_session::KVSSession,
     spec::{KVPayloadResult, KVSAction},
 };
@@ -26,7 +25,7 @@ impl KVSAction<String> for RemoteVersionAction {
         let reply = KVSSession::to::<KVPayloadResult<String>>(&bytes)?;
 
         match reply {
-            KVPayloadResult::Err(error) => Err(KVSError::LogicError(error)),
+            KVPayloadResult::Err(error) => Err(error.into()),
             KVPayloadResult::Ok(version) => Ok(version),
         }
     }
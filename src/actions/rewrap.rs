@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::KVSResult,
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, Session},
+    utils::{sha256, to_u8str},
+};
+
+use super::{namespace_dir, Actions, Envelope, KVSToken, KeyMeta, TeamRole};
+
+/// The `rand` of a private value re-wrapped as `Envelope::Hkdf`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RewrappedRand {
+    pub key: String,
+    /// The wrapped `rand` it replaces, the value is skipped when it changed
+    /// in the meantime.
+    pub from: Vec<u8>,
+    pub rand: Vec<u8>,
+}
+
+/// Upgrade the envelope of private values in place, replies the number of
+/// values rewrapped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RewrapAction {
+    pub token: KVSToken,
+    pub team: Option<String>,
+    pub rands: Vec<RewrappedRand>,
+}
+
+impl KVSAction<usize> for RewrapAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<usize> {
+        let RewrapAction { token, team, rands } = self;
+        let keys_dir_path = namespace_dir(token, team, TeamRole::Writer)?;
+        let id_str = token.get_addr();
+        let mut count = 0;
+        for RewrappedRand { key, from, rand } in rands.iter() {
+            let key_hash = to_u8str(&sha256(key.as_bytes()));
+            let meta_file_path = keys_dir_path.join(&key_hash).join("meta");
+            if !meta_file_path.exists() {
+                continue;
+            }
+            let mut meta = KeyMeta::from_file(&meta_file_path)?;
+            if meta.envelope != Envelope::Legacy || meta.rand.as_ref() != Some(from) {
+                continue;
+            }
+            meta.rand = Some(rand.clone());
+            meta.envelope = Envelope::Hkdf;
+            meta.save(&meta_file_path)?;
+            count += 1;
+            tracing::info!("[{}] Rewrap Key: {} ({})", id_str, key_hash, key);
+        }
+        Ok(count)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<usize> {
+        session.write(&Actions::RewrapAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<usize>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(count) => Ok(count),
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{get_or_create_data_dir, get_or_create_identities_dir, get_or_create_jwt_secret},
+    errors::{KVSError, KVSResult},
+    kv_session::KVSSession,
+    secret::Secret,
+    spec::{KVPayloadResult, KVSAction, ReplyCode, Session},
+    utils::{is_scope_addr, sha256, to_addr, to_u8str},
+};
+
+use super::{Actions, Envelope, KVSToken, KeyMeta, TeamMeta};
+
+/// Left at a rotated scope, signed by its old identity key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardRecord {
+    pub from: String,
+    pub from_pub_key: Vec<u8>,
+    pub to: String,
+    pub to_pub_key: Vec<u8>,
+    /// Unix time in milliseconds.
+    pub time_stamp: i64,
+    pub signature: Vec<u8>,
+}
+
+impl ForwardRecord {
+    pub fn signed(from: &Secret, to: &Secret) -> KVSResult<ForwardRecord> {
+        let mut record = ForwardRecord {
+            from: to_addr(&from.pub_key_bits),
+            from_pub_key: from.pub_key_bits.clone(),
+            to: to_addr(&to.pub_key_bits),
+            to_pub_key: to.pub_key_bits.clone(),
+            time_stamp: chrono::Local::now().timestamp_millis(),
+            signature: vec![],
+        };
+        record.signature =
+            Secret::sign_with_priv_key_bits(&from.priv_key_bits, &record.payload()?)?;
+        Ok(record)
+    }
+
+    fn payload(&self) -> KVSResult<Vec<u8>> {
+        Ok(bincode::serialize(&(
+            &self.from,
+            &self.from_pub_key,
+            &self.to,
+            &self.to_pub_key,
+            self.time_stamp,
+        ))?)
+    }
+
+    /// Whether the addresses match the public keys and the old key signed the record.
+    pub fn verify(&self) -> bool {
+        let payload = match self.payload() {
+            Ok(payload) => payload,
+            Err(_) => return false,
+        };
+        to_addr(&self.from_pub_key) == self.from
+            && to_addr(&self.to_pub_key) == self.to
+            && Secret::verify_with_pub_key_bits(&self.from_pub_key, &payload, &self.signature)
+    }
+}
+
+fn forward_record_path(scope: &str) -> KVSResult<PathBuf> {
+    Ok(get_or_create_identities_dir()?.join(format!("{}.forward", scope)))
+}
+
+/// The forwarding record of a rotated scope.
+pub fn forwarded_to(scope: &str) -> KVSResult<Option<ForwardRecord>> {
+    let forward_record_path = forward_record_path(scope)?;
+    if !is_scope_addr(scope) || !forward_record_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(KVSSession::to::<ForwardRecord>(&std::fs::read(
+        forward_record_path,
+    )?)?))
+}
+
+/// A key wrapped with the public key of a scope outside of its own values.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Inbound {
+    /// The team key of a team the scope is a member of.
+    Team(String),
+    /// The `rand` of a value shared with the scope, as `(namespace, key)`
+    /// with a scope or `team:<name>` as namespace.
+    Share(String, String),
+}
+
+impl std::fmt::Display for Inbound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inbound::Team(team) => write!(f, "the team: `{}`", team),
+            Inbound::Share(namespace, key) => write!(f, "the key: `{}` of {}", key, namespace),
+        }
+    }
+}
+
+/// `(namespace, meta file)` of every stored value.
+fn key_metas() -> KVSResult<Vec<(String, PathBuf)>> {
+    let data_dir_path = get_or_create_data_dir()?;
+    let mut key_dirs = vec![];
+    for entry in std::fs::read_dir(&data_dir_path)?.filter_map(|p| p.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_scope_addr(&name) {
+            key_dirs.push((name, entry.path()));
+        }
+    }
+    for team in team_names()? {
+        key_dirs.push((
+            format!("team:{}", team),
+            TeamMeta::team_dir(&team)?.join("keys"),
+        ));
+    }
+    let mut metas = vec![];
+    for (namespace, key_dir_path) in key_dirs {
+        for key_dir in std::fs::read_dir(key_dir_path)?.filter_map(|p| p.ok()) {
+            metas.push((namespace.clone(), key_dir.path().join("meta")));
+        }
+    }
+    Ok(metas)
+}
+
+fn team_names() -> KVSResult<Vec<String>> {
+    let teams_dir_path = get_or_create_data_dir()?.join("teams");
+    if !teams_dir_path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(std::fs::read_dir(teams_dir_path)?
+        .filter_map(|p| p.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect())
+}
+
+/// List the team keys and the shares wrapped for the token's scope, which
+/// `RotateAction` needs re-wrapped for the new identity.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InboundAction {
+    pub token: KVSToken,
+}
+
+impl KVSAction<Vec<(Inbound, Vec<u8>)>> for InboundAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<Vec<(Inbound, Vec<u8>)>> {
+        if self.token.claims.is_some() {
+            return Err(KVSError::LogicError(
+                "Only a full token can rotate the identity.".to_string(),
+            ));
+        }
+        let scope = self.token.get_addr();
+        let mut inbound = vec![];
+        for name in team_names()? {
+            if let Some(member) = TeamMeta::from_dir(&name)?.member(&scope) {
+                inbound.push((Inbound::Team(name), member.key.clone()));
+            }
+        }
+        for (namespace, meta_file_path) in key_metas()? {
+            let meta = KeyMeta::from_file(&meta_file_path)?;
+            for share in meta.shares.iter().filter(|share| share.scope == scope) {
+                let share_of = Inbound::Share(namespace.clone(), meta.name.clone());
+                inbound.push((share_of, share.rand.clone()));
+            }
+        }
+        Ok(inbound)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<Vec<(Inbound, Vec<u8>)>> {
+        session.write(&Actions::InboundAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<Vec<(Inbound, Vec<u8>)>>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(inbound) => Ok(inbound),
+        }
+    }
+}
+
+/// Points the shares and the ACL entries of the old scope at the new one.
+struct Migration<'a> {
+    old_scope: &'a str,
+    new_scope: &'a str,
+    inbound: &'a [(Inbound, Vec<u8>)],
+    /// What the new identity could not read after the rotation.
+    missing: Vec<Inbound>,
+}
+
+impl Migration<'_> {
+    fn rewrapped(&mut self, inbound: Inbound) -> Option<Vec<u8>> {
+        match self.inbound.iter().find(|(of, _)| *of == inbound) {
+            Some((_, key)) => Some(key.clone()),
+            None => {
+                self.missing.push(inbound);
+                None
+            }
+        }
+    }
+
+    /// Whether the meta changed.
+    fn meta(&mut self, namespace: &str, meta: &mut KeyMeta) -> bool {
+        let mut changed = false;
+        for index in 0..meta.shares.len() {
+            if meta.shares[index].scope != self.old_scope {
+                continue;
+            }
+            let share_of = Inbound::Share(namespace.to_string(), meta.name.clone());
+            if let Some(rand) = self.rewrapped(share_of) {
+                meta.shares[index].scope = self.new_scope.to_string();
+                meta.shares[index].rand = rand;
+                changed = true;
+            }
+        }
+        for entry in meta.acl.iter_mut().flatten() {
+            if entry == self.old_scope {
+                *entry = self.new_scope.to_string();
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Whether the team changed.
+    fn team(&mut self, team: &mut TeamMeta) -> bool {
+        let key = match team.member(self.old_scope) {
+            Some(_) => self.rewrapped(Inbound::Team(team.name.clone())),
+            None => return false,
+        };
+        match key {
+            Some(key) => {
+                for member in team.members.iter_mut() {
+                    if member.scope == self.old_scope {
+                        member.scope = self.new_scope.to_string();
+                        member.key = key.clone();
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Copy the keys of a scope into `temp_dir_path` with their new metas and
+/// move it to `new_dir_path` in one rename.
+fn move_scope_dir(
+    metas: Vec<(PathBuf, KeyMeta)>,
+    temp_dir_path: &Path,
+    new_dir_path: &Path,
+) -> KVSResult<()> {
+    if temp_dir_path.exists() {
+        remove_dir_all::remove_dir_all(temp_dir_path)?;
+    }
+    std::fs::create_dir_all(temp_dir_path)?;
+    for (meta_file_path, meta) in metas {
+        let key_dir_path = meta_file_path.parent().unwrap();
+        let temp_key_dir_path = temp_dir_path.join(key_dir_path.file_name().unwrap());
+        std::fs::create_dir(&temp_key_dir_path)?;
+        for file in std::fs::read_dir(key_dir_path)?.filter_map(|p| p.ok()) {
+            if file.file_name() != "meta" {
+                std::fs::copy(file.path(), temp_key_dir_path.join(file.file_name()))?;
+            }
+        }
+        meta.save(temp_key_dir_path.join("meta"))?;
+    }
+    if new_dir_path.exists() {
+        std::fs::remove_dir(new_dir_path)?;
+    }
+    std::fs::rename(temp_dir_path, new_dir_path)?;
+    Ok(())
+}
+
+/// Move every key of the token's scope to the scope of `new_token` and
+/// retire the old scope with a forwarding record.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RotateAction {
+    pub token: KVSToken,
+    pub new_token: KVSToken,
+    /// `(key, rand wrapped with the new identity key)`
+    pub rands: Vec<(String, Vec<u8>)>,
+    /// `(key, signature of the new identity)` of the public values
+    pub signatures: Vec<(String, Vec<u8>)>,
+    /// What `InboundAction` listed, wrapped with the new identity key.
+    pub inbound: Vec<(Inbound, Vec<u8>)>,
+    pub forward: ForwardRecord,
+}
+
+impl KVSAction<ReplyCode> for RotateAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
+        let RotateAction {
+            token,
+            new_token,
+            rands,
+            signatures,
+            inbound,
+            forward,
+        } = self;
+        if token.claims.is_some() || new_token.claims.is_some() {
+            return Err(KVSError::LogicError(
+                "Only a full token can rotate the identity.".to_string(),
+            ));
+        }
+        let jwt_secret = get_or_create_jwt_secret(false)?;
+        let sign = KVSToken::sign(&new_token.id, new_token.time_stamp, &None, &jwt_secret)?;
+        if sign != new_token.sign {
+            return Err(KVSError::LogicError("Illegal Token".to_string()));
+        }
+        let (old_scope, new_scope) = (token.get_addr(), new_token.get_addr());
+        let registered_pub_key = std::fs::read(get_or_create_identities_dir()?.join(&new_scope))?;
+        if old_scope == new_scope
+            || forward.from != old_scope
+            || forward.to != new_scope
+            || forward.to_pub_key != registered_pub_key
+            || !forward.verify()
+        {
+            return Err(KVSError::LogicError(
+                "Illegal forwarding record".to_string(),
+            ));
+        }
+
+        let data_dir_path = get_or_create_data_dir()?;
+        let (old_dir_path, new_dir_path) = (
+            data_dir_path.join(&old_scope),
+            data_dir_path.join(&new_scope),
+        );
+        if new_dir_path.exists() && std::fs::read_dir(&new_dir_path)?.next().is_some() {
+            return Err(KVSError::LogicError(format!(
+                "The scope: `{}` is not empty.",
+                new_scope
+            )));
+        }
+        let mut migration = Migration {
+            old_scope: &old_scope,
+            new_scope: &new_scope,
+            inbound,
+            missing: vec![],
+        };
+        let (mut own_metas, mut other_metas, mut teams) = (vec![], vec![], vec![]);
+        for (namespace, meta_file_path) in key_metas()? {
+            let mut meta = KeyMeta::from_file(&meta_file_path)?;
+            let changed = migration.meta(&namespace, &mut meta);
+            if namespace != old_scope {
+                if changed {
+                    other_metas.push((meta_file_path, meta));
+                }
+                continue;
+            }
+            if meta.rand.is_some() {
+                match rands.iter().find(|(key, _)| *key == meta.name) {
+                    Some((_, rand)) => {
+                        meta.rand = Some(rand.clone());
+                        meta.envelope = Envelope::Hkdf;
+                    }
+                    None => {
+                        return Err(KVSError::LogicError(format!(
+                            "The key: `{}` was not re-wrapped, retry.",
+                            meta.name
+                        )))
+                    }
+                }
+            } else {
+                meta.signature = signatures
+                    .iter()
+                    .find(|(key, _)| *key == meta.name)
+                    .map(|(_, signature)| signature.clone());
+            }
+            meta.owner = new_token.id.clone();
+            own_metas.push((meta_file_path, meta));
+        }
+        for name in team_names()? {
+            let mut team = TeamMeta::from_dir(&name)?;
+            if migration.team(&mut team) {
+                teams.push(team);
+            }
+        }
+        if !migration.missing.is_empty() {
+            let missing = migration
+                .missing
+                .iter()
+                .map(|inbound| inbound.to_string())
+                .collect::<Vec<_>>();
+            return Err(KVSError::LogicError(format!(
+                "The new identity could not read {}, retry.",
+                missing.join(", ")
+            )));
+        }
+
+        if old_dir_path.exists() {
+            let temp_dir_path = data_dir_path.join(format!("{}.rotating", new_scope));
+            move_scope_dir(own_metas, &temp_dir_path, &new_dir_path)?;
+            remove_dir_all::remove_dir_all(&old_dir_path)?;
+        }
+        for (meta_file_path, meta) in other_metas {
+            meta.save(&meta_file_path)?;
+            let key_hash = to_u8str(&sha256(meta.name.as_bytes()));
+            tracing::info!("[{}] Migrate Key: {} ({})", old_scope, key_hash, meta.name);
+        }
+        for team in teams {
+            team.save()?;
+            tracing::info!("[{}] Migrate Team: {}", old_scope, team.name);
+        }
+
+        let identities_dir_path = get_or_create_identities_dir()?;
+        let signed_marker_path = identities_dir_path.join(format!("{}.signed", old_scope));
+        if signed_marker_path.exists() {
+            std::fs::rename(
+                signed_marker_path,
+                identities_dir_path.join(format!("{}.signed", new_scope)),
+            )?;
+        }
+        std::fs::write(
+            forward_record_path(&old_scope)?,
+            bincode::serialize(forward)?,
+        )?;
+        tracing::info!("[{}] Rotate Identity: {}", old_scope, new_scope);
+        Ok(ReplyCode::Ok)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+        session.write(&Actions::RotateAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<ReplyCode>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
+        }
+    }
+}
+
+/// Look up the forwarding record of a scope, `None` when it is not rotated.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardAction {
+    pub scope: String,
+}
+
+impl KVSAction<Option<ForwardRecord>> for ForwardAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<Option<ForwardRecord>> {
+        forwarded_to(&self.scope)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<Option<ForwardRecord>> {
+        session.write(&Actions::ForwardAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<Option<ForwardRecord>>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(record) => Ok(record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ForwardRecord;
+    use crate::secret::Secret;
+
+    #[test]
+    fn test_forward_record() {
+        let (from, to) = (Secret::default(), Secret::default());
+        let mut record = ForwardRecord::signed(&from, &to).unwrap();
+        assert!(record.verify());
+        record.to_pub_key = from.pub_key_bits.clone();
+        assert!(!record.verify());
+    }
+}
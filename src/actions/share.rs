@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::get_or_create_data_dir,
+    errors::{KVSError, KVSResult},
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, ReplyCode, Session},
+    utils::{is_scope_addr, sha256, to_u8str},
+};
+
+use super::{Actions, KVSToken, KeyMeta, KeyShare};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShareAction {
+    pub token: KVSToken,
+    pub key: String,
+    pub scope: String,
+    /// The value's `rand` wrapped with the recipient's public key.
+    pub rand: Vec<u8>,
+}
+
+impl KVSAction<ReplyCode> for ShareAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
+        let ShareAction {
+            token,
+            key,
+            scope,
+            rand,
+        } = self;
+        if !is_scope_addr(scope) {
+            return Err(KVSError::LogicError(format!("Illegal scope: {}", scope)));
+        }
+        let id_str = token.get_addr();
+        let o_key = key.clone();
+        let key = to_u8str(&sha256(key.as_bytes()));
+        let kv_path = get_or_create_data_dir()?.join(&id_str).join(&key);
+        if !kv_path.exists() {
+            return Err(KVSError::LogicError(format!(
+                "The key: `{}` is not exists.",
+                o_key
+            )));
+        }
+        let meta_file_path = kv_path.join("meta");
+        let mut meta = KeyMeta::from_file(&meta_file_path)?;
+        if meta.rand.is_none() {
+            return Err(KVSError::LogicError(format!(
+                "The key: `{}` is public.",
+                o_key
+            )));
+        }
+        meta.shares.retain(|share| share.scope != *scope);
+        meta.shares.push(KeyShare {
+            scope: scope.clone(),
+            rand: rand.clone(),
+        });
+        meta.save(&meta_file_path)?;
+        tracing::info!("[{}] Share Key: {} ({}) with {}", id_str, key, o_key, scope);
+        Ok(ReplyCode::Ok)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+        session.write(&Actions::ShareAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<ReplyCode>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnshareAction {
+    pub token: KVSToken,
+    pub key: String,
+    pub scope: String,
+}
+
+impl KVSAction<ReplyCode> for UnshareAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
+        let UnshareAction { token, key, scope } = self;
+        let id_str = token.get_addr();
+        let o_key = key.clone();
+        let key = to_u8str(&sha256(key.as_bytes()));
+        let kv_path = get_or_create_data_dir()?.join(&id_str).join(&key);
+        if !kv_path.exists() {
+            return Err(KVSError::LogicError(format!(
+                "The key: `{}` is not exists.",
+                o_key
+            )));
+        }
+        let meta_file_path = kv_path.join("meta");
+        let mut meta = KeyMeta::from_file(&meta_file_path)?;
+        let shares_count = meta.shares.len();
+        meta.shares.retain(|share| share.scope != *scope);
+        if meta.shares.len() == shares_count {
+            return Err(KVSError::LogicError(format!(
+                "The key: `{}` is not shared with {}.",
+                o_key, scope
+            )));
+        }
+        meta.save(&meta_file_path)?;
+        tracing::info!(
+            "[{}] Unshare Key: {} ({}) with {}",
+            id_str,
+            key,
+            o_key,
+            scope
+        );
+        Ok(ReplyCode::Ok)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+        session.write(&Actions::UnshareAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<ReplyCode>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
+        }
+    }
+}
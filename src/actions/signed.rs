@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::get_or_create_identities_dir,
+    errors::{KVSError, KVSResult},
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, ReplyCode, Session},
+};
+
+use super::{Actions, KVSToken};
+
+/// An action signed with the identity key of the client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedRequest {
+    /// The serialized `Actions`.
+    pub action: Vec<u8>,
+    /// Unix time in milliseconds.
+    pub time_stamp: i64,
+    pub nonce: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedRequest {
+    /// The signed content, `action || time_stamp || nonce`.
+    pub fn payload(action: &[u8], time_stamp: i64, nonce: &[u8]) -> Vec<u8> {
+        [action, &time_stamp.to_be_bytes(), nonce].concat()
+    }
+}
+
+fn signatures_marker_path(scope: &str) -> KVSResult<PathBuf> {
+    Ok(get_or_create_identities_dir()?.join(format!("{}.signed", scope)))
+}
+
+/// Whether the server refuses unsigned requests of `scope`.
+pub fn requires_signatures(scope: &str) -> KVSResult<bool> {
+    Ok(signatures_marker_path(scope)?.exists())
+}
+
+/// Refuse the unsigned requests of `scope` from now on.
+pub(crate) fn require_signatures(scope: &str) -> KVSResult<()> {
+    std::fs::write(signatures_marker_path(scope)?, b"")?;
+    Ok(())
+}
+
+/// Turn on or off the refusal of unsigned requests for the token's scope.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SigningAction {
+    pub token: KVSToken,
+    pub required: bool,
+}
+
+impl KVSAction<ReplyCode> for SigningAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
+        let SigningAction { token, required } = self;
+        let scope = token.get_addr();
+        let marker_path = signatures_marker_path(&scope)?;
+        if *required {
+            if !get_or_create_identities_dir()?.join(&scope).exists() {
+                return Err(KVSError::LogicError(format!(
+                    "The scope: `{}` has no registered public key.",
+                    scope
+                )));
+            }
+            require_signatures(&scope)?;
+        } else if marker_path.exists() {
+            // unsigned requests never get here while signatures are required
+            std::fs::remove_file(marker_path)?;
+        }
+        tracing::info!("[{}] Require Signatures: {}", scope, required);
+        Ok(ReplyCode::Ok)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+        session.write(&Actions::SigningAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<ReplyCode>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    at_rest,
+    config::get_or_create_data_dir,
+    errors::{KVSError, KVSResult},
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, ReplyCode, Session},
+    utils::is_scope_addr,
+};
+
+use super::{Actions, Envelope, KVSToken, KeyMeta};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TeamRole {
+    Reader,
+    Writer,
+    Owner,
+}
+
+impl std::str::FromStr for TeamRole {
+    type Err = KVSError;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "owner" => Ok(TeamRole::Owner),
+            "writer" => Ok(TeamRole::Writer),
+            "reader" => Ok(TeamRole::Reader),
+            _ => Err(KVSError::LogicError(format!("Illegal team role: {}", role))),
+        }
+    }
+}
+
+impl std::fmt::Display for TeamRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TeamRole::Owner => "owner",
+            TeamRole::Writer => "writer",
+            TeamRole::Reader => "reader",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TeamMember {
+    pub scope: String,
+    pub role: TeamRole,
+    /// The team key wrapped with the member's public key.
+    pub key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TeamMeta {
+    pub name: String,
+    pub members: Vec<TeamMember>,
+    pub key_version: u32,
+}
+
+impl TeamMeta {
+    pub fn member(&self, scope: &str) -> Option<&TeamMember> {
+        self.members.iter().find(|member| member.scope == scope)
+    }
+
+    pub fn team_dir(name: &str) -> KVSResult<PathBuf> {
+        let legal_name = !name.is_empty()
+            && name.len() <= 64
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !legal_name {
+            return Err(KVSError::LogicError(format!("Illegal team name: {}", name)));
+        }
+        Ok(get_or_create_data_dir()?.join("teams").join(name))
+    }
+
+    pub fn from_dir(name: &str) -> KVSResult<TeamMeta> {
+        let team_file_path = TeamMeta::team_dir(name)?.join("team");
+        if !team_file_path.exists() {
+            return Err(KVSError::LogicError(format!(
+                "The team: `{}` is not exists.",
+                name
+            )));
+        }
+        KVSSession::to::<TeamMeta>(&std::fs::read(team_file_path)?)
+    }
+
+    pub fn save(&self) -> KVSResult<()> {
+        let team_dir_path = TeamMeta::team_dir(&self.name)?;
+        std::fs::create_dir_all(team_dir_path.join("keys"))?;
+        at_rest::replace(&team_dir_path.join("team"), &bincode::serialize(self)?)
+    }
+
+    /// Check that `scope` holds at least `role` in the team and return the
+    /// directory holding the team's keys.
+    pub fn authorize(name: &str, scope: &str, role: TeamRole) -> KVSResult<PathBuf> {
+        let team = TeamMeta::from_dir(name)?;
+        match team.member(scope) {
+            Some(member) if member.role >= role => Ok(TeamMeta::team_dir(name)?.join("keys")),
+            Some(_) => Err(KVSError::LogicError(format!(
+                "The team: `{}` requires the {} role.",
+                name, role
+            ))),
+            None => Err(KVSError::LogicError(format!(
+                "You are not a member of the team: `{}`.",
+                name
+            ))),
+        }
+    }
+}
+
+/// The directory holding the keys of the token's scope or of `team`.
+pub fn namespace_dir(
+    token: &KVSToken,
+    team: &Option<String>,
+    role: TeamRole,
+) -> KVSResult<PathBuf> {
+    match team {
+        Some(team) => TeamMeta::authorize(team, &token.get_addr(), role),
+        None => Ok(get_or_create_data_dir()?.join(token.get_addr())),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TeamCreateAction {
+    pub token: KVSToken,
+    pub name: String,
+    /// The team key wrapped with the creator's public key.
+    pub key: Vec<u8>,
+}
+
+impl KVSAction<ReplyCode> for TeamCreateAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
+        let TeamCreateAction { token, name, key } = self;
+        if TeamMeta::team_dir(name)?.exists() {
+            return Err(KVSError::LogicError(format!(
+                "The team: `{}` arealy exists.",
+                name
+            )));
+        }
+        TeamMeta {
+            name: name.clone(),
+            members: vec![TeamMember {
+                scope: token.get_addr(),
+                role: TeamRole::Owner,
+                key: key.clone(),
+            }],
+            key_version: 1,
+        }
+        .save()?;
+        tracing::info!("[{}] Create Team: {}", token.get_addr(), name);
+        Ok(ReplyCode::Ok)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+        session.write(&Actions::TeamCreateAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<ReplyCode>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TeamInfoAction {
+    pub token: KVSToken,
+    pub name: String,
+}
+
+impl KVSAction<TeamMeta> for TeamInfoAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<TeamMeta> {
+        let TeamInfoAction { token, name } = self;
+        TeamMeta::authorize(name, &token.get_addr(), TeamRole::Reader)?;
+        TeamMeta::from_dir(name)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<TeamMeta> {
+        session.write(&Actions::TeamInfoAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<TeamMeta>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(team) => Ok(team),
+        }
+    }
+}
+
+/// Replace the member list of a team. When `key_version` moves forward the
+/// team key is rotated and `rands` must re-wrap every private team key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TeamUpdateAction {
+    pub token: KVSToken,
+    pub name: String,
+    pub members: Vec<TeamMember>,
+    pub key_version: u32,
+    /// `(key, rand wrapped with the new team key)`
+    pub rands: Vec<(String, Vec<u8>)>,
+}
+
+impl KVSAction<ReplyCode> for TeamUpdateAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
+        let TeamUpdateAction {
+            token,
+            name,
+            members,
+            key_version,
+            rands,
+        } = self;
+        let keys_dir_path = TeamMeta::authorize(name, &token.get_addr(), TeamRole::Owner)?;
+        let mut team = TeamMeta::from_dir(name)?;
+        if !members.iter().any(|member| member.role == TeamRole::Owner) {
+            return Err(KVSError::LogicError(format!(
+                "The team: `{}` must keep at least one owner.",
+                name
+            )));
+        }
+        if let Some(member) = members.iter().find(|member| !is_scope_addr(&member.scope)) {
+            return Err(KVSError::LogicError(format!(
+                "Illegal scope: {}",
+                member.scope
+            )));
+        }
+
+        let mut metas = vec![];
+        if *key_version != team.key_version {
+            if *key_version != team.key_version + 1 {
+                return Err(KVSError::LogicError(format!(
+                    "The team: `{}` is at key version {}, retry.",
+                    name, team.key_version
+                )));
+            }
+            let key_dirs = std::fs::read_dir(&keys_dir_path)?
+                .filter_map(|p| p.ok())
+                .collect::<Vec<_>>();
+            for key_dir in key_dirs {
+                let meta_file_path = key_dir.path().join("meta");
+                let mut meta = KeyMeta::from_file(&meta_file_path)?;
+                if meta.rand.is_none() {
+                    continue;
+                }
+                match rands.iter().find(|(key, _)| *key == meta.name) {
+                    Some((_, rand)) => {
+                        meta.rand = Some(rand.clone());
+                        meta.envelope = Envelope::Hkdf;
+                    }
+                    None => {
+                        return Err(KVSError::LogicError(format!(
+                            "The key: `{}` was not re-wrapped, retry.",
+                            meta.name
+                        )))
+                    }
+                }
+                metas.push((meta_file_path, meta));
+            }
+        } else if members.len() != team.members.len()
+            || members
+                .iter()
+                .any(|member| team.member(&member.scope).is_none())
+        {
+            return Err(KVSError::LogicError(format!(
+                "Changing the members of the team: `{}` must rotate its key.",
+                name
+            )));
+        }
+
+        for (meta_file_path, meta) in metas {
+            meta.save(meta_file_path)?;
+        }
+        team.members = members.clone();
+        team.key_version = *key_version;
+        team.save()?;
+        tracing::info!(
+            "[{}] Update Team: {} ({} members, key version {})",
+            token.get_addr(),
+            name,
+            team.members.len(),
+            team.key_version
+        );
+        Ok(ReplyCode::Ok)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+        session.write(&Actions::TeamUpdateAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<ReplyCode>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(reply) => Ok(reply),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TeamCreateAction, TeamInfoAction, TeamMember, TeamRole, TeamUpdateAction};
+    use crate::{
+        client::KvsClient,
+        kv_server::test::test_repository,
+        secret::{KeyType, Secret},
+    };
+
+    fn member(client: &KvsClient, role: TeamRole) -> TeamMember {
+        TeamMember {
+            scope: client.token().unwrap().get_addr(),
+            role,
+            key: vec![],
+        }
+    }
+
+    #[test]
+    fn test_team_roles() {
+        let repository = test_repository();
+        let [owner, writer, reader] = [(); 3]
+            .map(|_| KvsClient::connect(&repository, Secret::new(KeyType::Ed25519)).unwrap());
+        let team_key = rand::random::<[u8; 32]>();
+        owner
+            .request(&TeamCreateAction {
+                token: owner.token().unwrap(),
+                name: "roles".to_string(),
+                key: Secret::encrypt_with_pub_key_bits(&owner.identity().pub_key_bits, &team_key)
+                    .unwrap(),
+            })
+            .unwrap();
+        owner
+            .create("team:roles:secret", b"secret", false, "text/plain")
+            .unwrap();
+        let team_info = |client: &KvsClient| {
+            client.request(&TeamInfoAction {
+                token: client.token().unwrap(),
+                name: "roles".to_string(),
+            })
+        };
+        let team = team_info(&owner).unwrap();
+        let members = vec![
+            team.members[0].clone(),
+            member(&writer, TeamRole::Writer),
+            member(&reader, TeamRole::Reader),
+        ];
+        owner.rotate_team_key(&team, members).unwrap();
+        let team = team_info(&reader).unwrap();
+        assert_eq!(team.key_version, 2);
+        assert_eq!(
+            owner
+                .fetch_team(&owner.token().unwrap(), "roles")
+                .unwrap()
+                .1
+                .len(),
+            32
+        );
+
+        assert_eq!(reader.read("team:roles:secret").unwrap().0, b"secret");
+        assert!(reader
+            .create("team:roles:reader", b"", false, "text/plain")
+            .is_err());
+        writer
+            .create("team:roles:writer", b"writer", false, "text/plain")
+            .unwrap();
+        let update = |client: &KvsClient, members: Vec<TeamMember>, key_version| {
+            client.request(&TeamUpdateAction {
+                token: client.token().unwrap(),
+                name: "roles".to_string(),
+                members,
+                key_version,
+                rands: vec![],
+            })
+        };
+        // only owners manage the members
+        assert!(update(&writer, team.members.clone(), 2).is_err());
+        let mut no_owner = team.members.clone();
+        no_owner[0].role = TeamRole::Writer;
+        assert!(update(&owner, no_owner, 2).is_err());
+        // changing the members rotates the key and re-wraps every private key
+        assert!(update(&owner, team.members[..2].to_vec(), 2).is_err());
+        assert!(update(&owner, team.members[..2].to_vec(), 3).is_err());
+        assert!(update(&owner, team.members.clone(), 4).is_err());
+        let mut promoted = team.members.clone();
+        promoted[2].role = TeamRole::Writer;
+        update(&owner, promoted, 2).unwrap();
+        reader
+            .create("team:roles:reader", b"reader", false, "text/plain")
+            .unwrap();
+
+        owner
+            .rotate_team_key(&team, team.members[..2].to_vec())
+            .unwrap();
+        assert!(reader.read("team:roles:secret").is_err());
+        for key in [
+            "team:roles:secret",
+            "team:roles:writer",
+            "team:roles:reader",
+        ] {
+            assert!(writer.read(key).is_ok());
+        }
+        assert_eq!(team_info(&owner).unwrap().key_version, 3);
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::get_or_create_jwt_secret,
+    errors::{KVSError, KVSResult},
+    kv_session::KVSSession,
+    spec::{KVPayloadResult, KVSAction, Session},
+};
+
+use super::{Actions, KVSToken};
+
+/// The actions a `--read-only` token may run.
+pub const READ_ONLY_ACTIONS: [&str; 5] = ["read", "list", "list_stamp", "pub_key", "audit"];
+
+/// The limits signed into a derived token.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TokenClaims {
+    /// The allowed action names, all actions when `None`.
+    pub actions: Option<Vec<String>>,
+    /// The allowed key prefixes, all keys when empty.
+    pub prefixes: Vec<String>,
+    /// Unix time in milliseconds.
+    pub expires_at: Option<i64>,
+}
+
+impl TokenClaims {
+    pub fn allows_action(&self, action: &str) -> bool {
+        match &self.actions {
+            Some(actions) => actions.iter().any(|allowed| allowed == action),
+            None => true,
+        }
+    }
+
+    pub fn allows_key(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// Refuse the action names no request would match.
+    pub fn check_actions(&self) -> KVSResult<()> {
+        for action in self.actions.iter().flatten() {
+            if !Actions::token_names().any(|name| name == action) {
+                return Err(KVSError::LogicError(format!(
+                    "Illegal action: {}, one of {}",
+                    action,
+                    Actions::token_names().collect::<Vec<_>>().join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mint a token limited by `claims` from a full token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenCreateAction {
+    pub token: KVSToken,
+    pub claims: TokenClaims,
+}
+
+impl KVSAction<KVSToken> for TokenCreateAction {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<KVSToken> {
+        let TokenCreateAction { token, claims } = self;
+        if token.claims.is_some() {
+            return Err(KVSError::LogicError(
+                "A derived token can not create tokens.".to_string(),
+            ));
+        }
+        claims.check_actions()?;
+        let jwt_secret = get_or_create_jwt_secret(false)?;
+        let derived_token = KVSToken::signed(
+            token.id.clone(),
+            chrono::Local::now().timestamp_millis(),
+            Some(claims.clone()),
+            &jwt_secret,
+        )?;
+        tracing::info!("[{}] Create Token: {:?}", token.get_addr(), claims);
+        Ok(derived_token)
+    }
+
+    fn request(&mut self, session: &mut impl Session) -> KVSResult<KVSToken> {
+        session.write(&Actions::TokenCreateAction(self.clone()))?;
+        let bytes = session.read_vec()?;
+        match KVSSession::to::<KVPayloadResult<KVSToken>>(&bytes)? {
+            KVPayloadResult::Err(error) => Err(error.into()),
+            KVPayloadResult::Ok(token) => Ok(token),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TokenClaims, READ_ONLY_ACTIONS};
+
+    #[test]
+    fn test_token_claims() {
+        let claims = TokenClaims {
+            actions: Some(vec!["read".to_string()]),
+            prefixes: vec!["deploy/".to_string()],
+            expires_at: None,
+        };
+        assert!(claims.allows_action("read"));
+        assert!(!claims.allows_action("delete"));
+        assert!(claims.allows_key("deploy/app.env"));
+        assert!(!claims.allows_key("secrets/app.env"));
+        assert!(TokenClaims::default().allows_key("secrets/app.env"));
+    }
+
+    #[test]
+    fn test_check_actions() {
+        let claims = |actions: &[&str]| TokenClaims {
+            actions: Some(actions.iter().map(|action| action.to_string()).collect()),
+            ..TokenClaims::default()
+        };
+        assert!(claims(&READ_ONLY_ACTIONS).check_actions().is_ok());
+        assert!(claims(&["create", "rewrap"]).check_actions().is_ok());
+        assert!(claims(&["read", "cat"]).check_actions().is_err());
+        assert!(claims(&["login"]).check_actions().is_err());
+        assert!(claims(&["forward"]).check_actions().is_err());
+        assert!(claims(&["inbound"]).check_actions().is_ok());
+        assert!(TokenClaims::default().check_actions().is_ok());
+    }
+}
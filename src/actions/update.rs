@@ -1,16 +1,18 @@
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{get_or_create_data_dir, get_or_create_secret},
+    at_rest,
+    config::get_or_create_secret,
     errors::{KVSError, KVSResult},
     kv_session::{KVSSession, NONCE},
+    secret::Secret,
     spec::{KVPayloadResult, KVSAction, ReplyCode, Session},
     utils::{sha256, to_u8str},
 };
 
 use aes_gcm::{aead::Aead, Aes256Gcm, Key, NewAead, Nonce};
 
-use super::{Actions, KVSToken, KeyMeta};
+use super::{namespace_dir, Actions, Envelope, KVSToken, KeyMeta, TeamRole};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UpdateAction {
@@ -18,15 +20,25 @@ pub struct UpdateAction {
     pub key: String,
     pub meta: KeyMeta,
     pub value: Vec<u8>,
+    pub team: Option<String>,
+    /// Client side key material wrapping `meta.rand`, the private key of the
+    /// identity when `None`.
+    #[serde(skip)]
+    pub wrap_key: Option<Vec<u8>>,
+    /// The identity of the client, the identity of the profile when `None`.
+    #[serde(skip)]
+    pub identity: Option<Secret>,
 }
 
 impl KVSAction<ReplyCode> for UpdateAction {
-    fn serve(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
+    fn serve(&mut self, _: &mut impl Session) -> KVSResult<ReplyCode> {
         let UpdateAction {
             token,
             key,
             value,
             meta,
+            team,
+            ..
         } = self;
         let KVSToken { id, .. } = token;
         meta.owner = id.clone();
@@ -34,41 +46,63 @@ impl KVSAction<ReplyCode> for UpdateAction {
         let id_str = ["0x", &to_u8str(&token.id)].concat();
         let o_key = key.clone();
         let key = to_u8str(&sha256(key.as_bytes()));
-        let data_dir_path = get_or_create_data_dir()?;
-        let data_user_dir_path = data_dir_path.clone().join(&id_str);
-        let kv_path = data_user_dir_path.clone().join(&key);
+        let kv_path = namespace_dir(token, team, TeamRole::Writer)?.join(&key);
         if !kv_path.exists() {
             return Err(KVSError::LogicError(format!(
                 "The key: `{}` is not exists.",
                 o_key
             )));
         } else {
-            std::fs::write(kv_path.join("meta"), bincode::serialize(&meta)?)?;
-            std::fs::write(kv_path.join("value"), value)?;
+            let current = KeyMeta::from_file(kv_path.join("meta"))?;
+            // the access list outlives updates of the value
+            meta.acl = current.acl;
+            // the shares do too, re-wrapped by the client for the new rand
+            match meta.rand {
+                Some(_) => {
+                    let dropped = current
+                        .shares
+                        .iter()
+                        .find(|share| !meta.shares.iter().any(|new| new.scope == share.scope));
+                    if let Some(share) = dropped {
+                        return Err(KVSError::LogicError(format!(
+                            "The key: `{}` is shared with {}, update it with its share.",
+                            o_key, share.scope
+                        )));
+                    }
+                }
+                None => meta.shares.clear(),
+            }
+            meta.save(kv_path.join("meta"))?;
+            at_rest::write(kv_path.join("value"), value)?;
             tracing::info!("[{}] Update Key: {} ({})", id_str, key, o_key);
-            session.write(&KVPayloadResult::Ok(ReplyCode::Ok))?
         }
         Ok(ReplyCode::Ok)
     }
 
     fn request(&mut self, session: &mut impl Session) -> KVSResult<ReplyCode> {
-        let secret = get_or_create_secret()?;
+        let secret = match &self.identity {
+            Some(identity) => identity.clone(),
+            None => get_or_create_secret()?,
+        };
         if let Some(rand) = &self.meta.rand {
             let key = Key::from_slice(rand.as_slice());
             let cipher = Aes256Gcm::new(key);
             self.value = cipher.encrypt(Nonce::from_slice(NONCE), &*self.value)?;
-            let key = Key::from_slice(&secret.priv_key_bits[..32]);
-            let cipher = Aes256Gcm::new(key);
-            self.meta.rand = Some(cipher.encrypt(Nonce::from_slice(NONCE), rand.as_slice())?);
+            let key_material = match &self.wrap_key {
+                Some(wrap_key) => wrap_key,
+                None => &secret.priv_key_bits,
+            };
+            self.meta.rand = Some(KeyMeta::wrap_rand(key_material, rand)?);
+            self.meta.envelope = Envelope::Hkdf;
+        } else {
+            self.meta.owner = self.token.id.clone();
+            self.meta.sign(&secret)?;
         }
 
         session.write(&Actions::UpdateAction(self.clone()))?;
         let reply = session.read_vec()?;
         match KVSSession::to::<KVPayloadResult<ReplyCode>>(&reply)? {
-            KVPayloadResult::Err(error) => {
-                tracing::error!("{}", error);
-                Ok(ReplyCode::Ok)
-            }
+            KVPayloadResult::Err(error) => Err(error.into()),
             KVPayloadResult::Ok(_) => Ok(ReplyCode::Ok),
         }
     }
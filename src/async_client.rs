@@ -0,0 +1,419 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::{
+    actions::{
+        CreateAction, DeleteAction, FetchTokenAction, KVSToken, KeyMeta, ListAction, PubKeyAction,
+        TeamInfoAction, TeamMeta, UpdateAction,
+    },
+    client::{
+        checked_pub_key, new_meta, parse_namespace, read_action, team_key, wrap_share, KeyAddress,
+    },
+    errors::{KVSError, KVSResult},
+    kv_session::{
+        check_handshake_head, frame_length, handshake_hello, SessionCipher, SignedSession,
+        HANDSHAKE_HEAD_LENGTH, MAX_RATE_LIMITED_RETRIES,
+    },
+    secret::{key_pair, Secret},
+    spec::{KVSAction, ReplyCode, Session},
+};
+
+/// `KvsClient` on tokio.
+///
+/// The handshake and the messages go over a tokio `TcpStream`, the crypto
+/// of an action runs on the blocking pool. Dropping a request future
+/// cancels it, and `timeout` bounds every request.
+///
+/// ```no_run
+/// use key_value_service::{AsyncKvsClient, KeyType, Secret};
+/// use std::time::Duration;
+///
+/// # async fn run() -> Result<(), key_value_service::KVSError> {
+/// let client = AsyncKvsClient::connect("127.0.0.1:8888", Secret::new(KeyType::Ed25519))
+///     .await?
+///     .timeout(Duration::from_secs(5));
+/// client.create("greeting", b"hello", false, "text/plain").await?;
+/// let (value, _) = client.read("greeting").await?;
+/// assert_eq!(value, b"hello");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AsyncKvsClient {
+    repository: String,
+    identity: Secret,
+    sign_requests: bool,
+    timeout: Option<Duration>,
+    token: Mutex<Option<KVSToken>>,
+}
+
+impl AsyncKvsClient {
+    /// A client that logs in on its first request.
+    pub fn new(repository: &str, identity: Secret) -> Self {
+        AsyncKvsClient {
+            repository: repository.to_string(),
+            identity,
+            sign_requests: true,
+            timeout: None,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// A client logged in to `repository`.
+    pub async fn connect(repository: &str, identity: Secret) -> KVSResult<Self> {
+        let client = AsyncKvsClient::new(repository, identity);
+        client.login().await?;
+        Ok(client)
+    }
+
+    /// A client using a token fetched before, e.g. by `login`.
+    pub fn with_token(repository: &str, identity: Secret, token: KVSToken) -> Self {
+        AsyncKvsClient {
+            token: Mutex::new(Some(token)),
+            ..AsyncKvsClient::new(repository, identity)
+        }
+    }
+
+    /// Sign every request with the identity key, on by default. Scopes
+    /// registered since signatures are the default refuse unsigned requests.
+    pub fn sign_requests(mut self, sign_requests: bool) -> Self {
+        self.sign_requests = sign_requests;
+        self
+    }
+
+    /// Fail a request with `TimedOut` when it takes longer, including the
+    /// waits while rate limited.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn repository(&self) -> &str {
+        &self.repository
+    }
+
+    pub fn identity(&self) -> &Secret {
+        &self.identity
+    }
+
+    /// Fetch a new token by proving the identity to the repository.
+    pub async fn login(&self) -> KVSResult<KVSToken> {
+        let token = self
+            .request(FetchTokenAction {
+                pub_key: self.identity.pub_key_bits.clone(),
+                priv_key_bits: Some(self.identity.priv_key_bits.clone()),
+            })
+            .await?;
+        *self.token.lock().unwrap() = Some(token.clone());
+        Ok(token)
+    }
+
+    /// The current token, logging in when there is none.
+    pub async fn token(&self) -> KVSResult<KVSToken> {
+        let token = self.token.lock().unwrap().clone();
+        match token {
+            Some(token) => Ok(token),
+            None => self.login().await,
+        }
+    }
+
+    pub(crate) async fn request<R, A>(&self, action: A) -> KVSResult<R>
+    where
+        R: serde::Serialize + Send + 'static,
+        A: KVSAction<R> + Clone + Send + 'static,
+    {
+        let request = async {
+            let mut retries = 0;
+            loop {
+                match self.exchange(action.clone()).await {
+                    Err(KVSError::RateLimited(retry_after))
+                        if retries < MAX_RATE_LIMITED_RETRIES =>
+                    {
+                        tracing::debug!("rate limited, retry after {} ms", retry_after);
+                        tokio::time::sleep(Duration::from_millis(retry_after)).await;
+                        retries += 1;
+                    }
+                    result => return result,
+                }
+            }
+        };
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request)
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?,
+            None => request.await,
+        }
+    }
+
+    /// Run the action on one session. Its `request` runs on the blocking
+    /// pool and talks to the stream through channels.
+    async fn exchange<R, A>(&self, mut action: A) -> KVSResult<R>
+    where
+        R: serde::Serialize + Send + 'static,
+        A: KVSAction<R> + Send + 'static,
+    {
+        let mut stream = TcpStream::connect(&self.repository).await?;
+        let cipher = handshake(&mut stream).await?;
+        let (mut reader, mut writer) = stream.into_split();
+        let (outgoing, mut outgoing_receiver) = unbounded_channel();
+        let (incoming_sender, incoming) = unbounded_channel();
+        let priv_key_bits = match self.sign_requests {
+            true => Some(self.identity.priv_key_bits.clone()),
+            false => None,
+        };
+        let mut requesting = tokio::task::spawn_blocking(move || {
+            let mut session = ChannelSession {
+                cipher,
+                outgoing,
+                incoming,
+            };
+            match priv_key_bits {
+                Some(priv_key_bits) => {
+                    action.request(&mut SignedSession::new(session, priv_key_bits))
+                }
+                None => action.request(&mut session),
+            }
+        });
+        let reading = async move {
+            while let Some(payload) = read_frame(&mut reader).await? {
+                if incoming_sender.send(payload).is_err() {
+                    break;
+                }
+            }
+            Ok::<_, KVSError>(())
+        };
+        let writing = async move {
+            while let Some(payload) = outgoing_receiver.recv().await {
+                write_frame(&mut writer, &payload).await?;
+            }
+            Ok::<_, KVSError>(())
+        };
+        let transport = async { tokio::try_join!(reading, writing) };
+        tokio::select! {
+            result = &mut requesting => result.map_err(|error| std::io::Error::other(error.to_string()))?,
+            Err(error) = transport => Err(error),
+        }
+    }
+
+    /// Fetch a team and unwrap its key with the identity.
+    pub(crate) async fn fetch_team(
+        &self,
+        token: &KVSToken,
+        name: &str,
+    ) -> KVSResult<(TeamMeta, Vec<u8>)> {
+        let team = self
+            .request(TeamInfoAction {
+                token: token.clone(),
+                name: name.to_string(),
+            })
+            .await?;
+        let team_key = team_key(&self.identity, token, &team)?;
+        Ok((team, team_key))
+    }
+
+    /// The key wrapping the private values of a team.
+    async fn wrap_key(
+        &self,
+        token: &KVSToken,
+        team: &Option<String>,
+        public: bool,
+    ) -> KVSResult<Option<Vec<u8>>> {
+        match team {
+            Some(team) if !public => Ok(Some(self.fetch_team(token, team).await?.1)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Create a key, private values are encrypted before they are sent.
+    /// `mime` must be a mime type, `detect_mime` guesses one.
+    pub async fn create(&self, key: &str, value: &[u8], public: bool, mime: &str) -> KVSResult<()> {
+        let token = self.token().await?;
+        let (team, key) = KeyAddress::writable(key, "write")?;
+        self.request::<ReplyCode, _>(CreateAction {
+            wrap_key: self.wrap_key(&token, &team, public).await?,
+            meta: new_meta(&token, &key, value, public, mime)?,
+            token,
+            key,
+            value: value.to_vec(),
+            team,
+            identity: Some(self.identity.clone()),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Replace the value of a key, keeping its access list and the scopes
+    /// it is shared with.
+    pub async fn update(&self, key: &str, value: &[u8], public: bool, mime: &str) -> KVSResult<()> {
+        let token = self.token().await?;
+        let (team, key) = KeyAddress::writable(key, "write")?;
+        let mut meta = new_meta(&token, &key, value, public, mime)?;
+        if let (None, Some(rand)) = (&team, &meta.rand) {
+            // the shares wrap the replaced rand
+            let (_, current) = self.read_unverified(&key).await?;
+            for share in current.shares {
+                let pub_key = self.pub_key(&share.scope).await?;
+                meta.shares.push(wrap_share(&share.scope, &pub_key, rand)?);
+            }
+        }
+        self.request::<ReplyCode, _>(UpdateAction {
+            wrap_key: self.wrap_key(&token, &team, public).await?,
+            meta,
+            token,
+            key,
+            value: value.to_vec(),
+            team,
+            identity: Some(self.identity.clone()),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Read and decrypt a value, public values must carry a valid signature
+    /// of their publisher.
+    pub async fn read(&self, key: &str) -> KVSResult<(Vec<u8>, KeyMeta)> {
+        let action = read_action(&self.identity, self.token().await?, key, false);
+        Ok(self.request(action).await?.into_parts())
+    }
+
+    /// `read` without checking the publisher signature of public values.
+    pub async fn read_unverified(&self, key: &str) -> KVSResult<(Vec<u8>, KeyMeta)> {
+        let action = read_action(&self.identity, self.token().await?, key, true);
+        Ok(self.request(action).await?.into_parts())
+    }
+
+    /// The public key `scope` registered when it last logged in.
+    pub async fn pub_key(&self, scope: &str) -> KVSResult<Vec<u8>> {
+        let pub_key = self
+            .request(PubKeyAction {
+                token: self.token().await?,
+                scope: scope.to_string(),
+            })
+            .await?;
+        checked_pub_key(scope, pub_key)
+    }
+
+    pub async fn delete(&self, key: &str) -> KVSResult<()> {
+        let (team, key) = KeyAddress::writable(key, "delete")?;
+        self.request::<ReplyCode, _>(DeleteAction {
+            token: self.token().await?,
+            key,
+            team,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// The metas of your keys, of the keys of a team with the namespace
+    /// `team:<name>`, or of the keys of another scope you can read with its
+    /// address as the namespace.
+    pub async fn list(&self, namespace: Option<&str>) -> KVSResult<Vec<KeyMeta>> {
+        let (team, scope) = parse_namespace(namespace)?;
+        self.request(ListAction {
+            token: self.token().await?,
+            team,
+            scope,
+        })
+        .await
+    }
+}
+
+/// The X25519 exchange of `KVSSession::new`.
+async fn handshake(stream: &mut TcpStream) -> KVSResult<SessionCipher> {
+    let (sk, pk) = key_pair();
+    stream.write_all(&handshake_hello(&pk)).await?;
+    let mut head = [0u8; HANDSHAKE_HEAD_LENGTH];
+    stream.read_exact(&mut head).await?;
+    check_handshake_head(&head)?;
+    let mut pk_bytes = [0u8; 32];
+    stream.read_exact(&mut pk_bytes).await?;
+    SessionCipher::new(sk, pk, pk_bytes)
+}
+
+/// A bincode `Vec<u8>`, `None` when the remote closed the stream.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> KVSResult<Option<Vec<u8>>> {
+    let mut length = [0u8; 8];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => (),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    let mut payload = vec![0u8; frame_length(length)?];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), payload: &[u8]) -> KVSResult<()> {
+    writer.write_all(&bincode::serialize(payload)?).await?;
+    Ok(())
+}
+
+/// The session an action's `request` sees on the blocking pool.
+struct ChannelSession {
+    cipher: SessionCipher,
+    outgoing: UnboundedSender<Vec<u8>>,
+    incoming: UnboundedReceiver<Vec<u8>>,
+}
+
+fn closed() -> KVSError {
+    std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into()
+}
+
+impl Session for ChannelSession {
+    fn read_vec(&mut self) -> KVSResult<Vec<u8>> {
+        let payload = self.incoming.blocking_recv().ok_or_else(closed)?;
+        self.cipher.open(&payload)
+    }
+
+    fn write_vec(&mut self, payload: &[u8]) -> KVSResult<()> {
+        let data = self.cipher.seal(payload)?;
+        self.outgoing.send(data).map_err(|_| closed())
+    }
+
+    fn write<T: ?Sized + serde::Serialize>(&mut self, payload: &T) -> KVSResult<()> {
+        self.write_vec(&bincode::serialize(payload)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncKvsClient;
+    use crate::{
+        kv_server::test::test_repository,
+        secret::{KeyType, Secret},
+    };
+
+    #[test]
+    fn test_async_client() {
+        let repository = test_repository();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let client = AsyncKvsClient::connect(&repository, Secret::new(KeyType::Ed25519))
+                .await
+                .unwrap();
+            client
+                .create("greeting", b"hello", false, "text/plain")
+                .await
+                .unwrap();
+            client
+                .update("greeting", b"hello again", false, "text/plain")
+                .await
+                .unwrap();
+            let (value, meta) = client.read("greeting").await.unwrap();
+            assert_eq!(value, b"hello again");
+            assert_eq!(meta.size, 11);
+            let metas = client.list(None).await.unwrap();
+            assert_eq!(metas.len(), 1);
+            client.delete("greeting").await.unwrap();
+            assert!(client.read("greeting").await.is_err());
+        });
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, Key, NewAead, Nonce};
+
+use crate::{
+    config::{
+        get_master_key_config, get_or_create_data_dir, get_or_create_master_keys_dir,
+        get_or_create_server_dir,
+    },
+    errors::{KVSError, KVSResult},
+    utils::{sha256, to_u8str},
+};
+
+/// The first bytes of every value and meta file, followed by `PLAIN` and the
+/// content, or by `SEALED`, the master key id, the data key wrapped by the
+/// master key and the encrypted content.
+const MAGIC: &[u8] = b"kvs at rest 2\0";
+const PLAIN: u8 = 0;
+const SEALED: u8 = 1;
+const KEY_ID_LENGTH: usize = 8;
+const NONCE_LENGTH: usize = 12;
+/// A 32 byte data key and its AES-GCM tag.
+const WRAPPED_KEY_LENGTH: usize = 48;
+const SEALED_LENGTH: usize = KEY_ID_LENGTH + 2 * NONCE_LENGTH + WRAPPED_KEY_LENGTH;
+
+fn key_id(master_key: &[u8]) -> String {
+    to_u8str(&sha256(master_key)[..KEY_ID_LENGTH])
+}
+
+fn master_key(master_keys_dir: &Path, id: &str) -> KVSResult<Vec<u8>> {
+    let master_key_file_path = master_keys_dir.join(id);
+    if !master_key_file_path.exists() {
+        return Err(KVSError::LogicError(format!(
+            "The master key: `{}` is not exists.",
+            id
+        )));
+    }
+    Ok(std::fs::read(master_key_file_path)?)
+}
+
+fn encrypt(key: &[u8], plain: &[u8]) -> KVSResult<Vec<u8>> {
+    let nonce = rand::random::<[u8; NONCE_LENGTH]>();
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    Ok([
+        &nonce[..],
+        &cipher.encrypt(Nonce::from_slice(&nonce), plain)?,
+    ]
+    .concat())
+}
+
+fn decrypt(key: &[u8], data: &[u8]) -> KVSResult<Vec<u8>> {
+    let (nonce, data) = data.split_at(NONCE_LENGTH);
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    Ok(cipher.decrypt(Nonce::from_slice(nonce), data)?)
+}
+
+/// Create a master key and return its id, it is not used until it is
+/// selected in the `master_key` config.
+pub fn new_master_key() -> KVSResult<String> {
+    create_master_key(&get_or_create_master_keys_dir()?)
+}
+
+fn create_master_key(master_keys_dir: &Path) -> KVSResult<String> {
+    let master_key = rand::random::<[u8; 32]>();
+    let id = key_id(&master_key);
+    let master_key_file_path = master_keys_dir.join(&id);
+    std::fs::write(&master_key_file_path, master_key)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(
+            &master_key_file_path,
+            std::fs::Permissions::from_mode(0o600),
+        )?;
+    }
+    Ok(id)
+}
+
+/// Encrypt with a new data key wrapped by the configured master key, or keep
+/// the content in plain when at-rest encryption is off.
+pub fn seal(plain: &[u8]) -> KVSResult<Vec<u8>> {
+    seal_with(
+        &get_or_create_master_keys_dir()?,
+        get_master_key_config()?.as_deref(),
+        plain,
+    )
+}
+
+fn seal_with(master_keys_dir: &Path, id: Option<&str>, plain: &[u8]) -> KVSResult<Vec<u8>> {
+    let id = match id {
+        Some(id) => id,
+        None => return Ok([MAGIC, &[PLAIN], plain].concat()),
+    };
+    let master_key = master_key(master_keys_dir, id)?;
+    let data_key = rand::random::<[u8; 32]>();
+    Ok([
+        MAGIC,
+        &[SEALED],
+        &sha256(&master_key)[..KEY_ID_LENGTH],
+        &encrypt(&master_key, &data_key)?,
+        &encrypt(&data_key, plain)?,
+    ]
+    .concat())
+}
+
+/// How a value or meta file is stored.
+enum Stored<'a> {
+    Plain(&'a [u8]),
+    /// The master key id and the wrapped data key followed by the content.
+    Sealed(String, &'a [u8]),
+}
+
+fn parse(data: &[u8]) -> KVSResult<Stored<'_>> {
+    match data.strip_prefix(MAGIC) {
+        Some([PLAIN, plain @ ..]) => Ok(Stored::Plain(plain)),
+        Some([SEALED, sealed @ ..]) if sealed.len() >= SEALED_LENGTH => Ok(Stored::Sealed(
+            to_u8str(&sealed[..KEY_ID_LENGTH]),
+            &sealed[KEY_ID_LENGTH..],
+        )),
+        Some(_) => Err(KVSError::LogicError(
+            "Illegal value or meta file".to_string(),
+        )),
+        // written in plain before files were framed
+        None => Ok(Stored::Plain(data)),
+    }
+}
+
+fn open_sealed(master_keys_dir: &Path, id: &str, data: &[u8]) -> KVSResult<Vec<u8>> {
+    let (wrapped_key, data) = data.split_at(NONCE_LENGTH + WRAPPED_KEY_LENGTH);
+    let data_key = decrypt(&master_key(master_keys_dir, id)?, wrapped_key)?;
+    decrypt(&data_key, data)
+}
+
+/// The content of a stored file and the id of the master key it is sealed
+/// with, `None` for plain content.
+fn unpack(master_keys_dir: &Path, data: &[u8]) -> KVSResult<(Vec<u8>, Option<String>)> {
+    match parse(data)? {
+        Stored::Plain(plain) => Ok((plain.to_vec(), None)),
+        Stored::Sealed(id, sealed) => Ok((open_sealed(master_keys_dir, &id, sealed)?, Some(id))),
+    }
+}
+
+/// Decrypt `seal` output with the master key it names.
+pub fn open(data: &[u8]) -> KVSResult<Vec<u8>> {
+    Ok(unpack(&get_or_create_master_keys_dir()?, data)?.0)
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> KVSResult<Vec<u8>> {
+    open(&std::fs::read(path)?)
+}
+
+pub fn write<P: AsRef<Path>>(path: P, plain: &[u8]) -> KVSResult<()> {
+    replace(path.as_ref(), &seal(plain)?)
+}
+
+/// Write to a temporary file next to `path` and rename it over `path`, so a
+/// crash leaves either the old or the new content.
+pub(crate) fn replace(path: &Path, data: &[u8]) -> KVSResult<()> {
+    let mut temp_file_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_file_name.push(".writing");
+    let temp_file_path = path.with_file_name(temp_file_name);
+    let mut temp_file = File::create(&temp_file_path)?;
+    temp_file.write_all(data)?;
+    temp_file.sync_all()?;
+    std::fs::rename(temp_file_path, path)?;
+    Ok(())
+}
+
+/// A lock on the data dir, shared by the running servers and taken alone to
+/// reseal the data dir. It is released when dropped.
+pub struct DataDirLock {
+    _file: File,
+}
+
+fn lock_file() -> KVSResult<File> {
+    Ok(File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(get_or_create_server_dir()?.join("data.lock"))?)
+}
+
+/// Held by a server while it runs.
+pub fn lock_for_server() -> KVSResult<DataDirLock> {
+    let file = lock_file()?;
+    if file.try_lock_shared().is_err() {
+        return Err(KVSError::LogicError(
+            "The data dir is being resealed.".to_string(),
+        ));
+    }
+    Ok(DataDirLock { _file: file })
+}
+
+/// Held while the master key changes, fails when a server is running.
+pub fn lock_for_reseal() -> KVSResult<DataDirLock> {
+    let file = lock_file()?;
+    if file.try_lock().is_err() {
+        return Err(KVSError::LogicError(
+            "The server is running, stop it before changing the master key.".to_string(),
+        ));
+    }
+    Ok(DataDirLock { _file: file })
+}
+
+fn stored_files(data_dir: &Path) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(data_dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| entry.file_name() == "meta" || entry.file_name() == "value")
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+/// Re-encrypt every value and meta file that is not sealed with the
+/// configured master key, replies the number of files rewritten.
+pub fn reseal_data_dir(_lock: &DataDirLock) -> KVSResult<usize> {
+    reseal(
+        &get_or_create_data_dir()?,
+        &get_or_create_master_keys_dir()?,
+        get_master_key_config()?.as_deref(),
+    )
+}
+
+fn reseal(data_dir: &Path, master_keys_dir: &Path, current: Option<&str>) -> KVSResult<usize> {
+    let mut count = 0;
+    for file_path in stored_files(data_dir) {
+        let data = std::fs::read(&file_path)?;
+        let resealed = match parse(&data)? {
+            Stored::Plain(_) => current.is_none(),
+            Stored::Sealed(id, _) => Some(id.as_str()) == current,
+        };
+        if resealed {
+            continue;
+        }
+        let (plain, _) = unpack(master_keys_dir, &data)?;
+        replace(&file_path, &seal_with(master_keys_dir, current, &plain)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Delete the master keys that are not configured and seal no file anymore,
+/// replies their ids.
+pub fn prune_master_keys(_lock: &DataDirLock) -> KVSResult<Vec<String>> {
+    prune(
+        &get_or_create_data_dir()?,
+        &get_or_create_master_keys_dir()?,
+        get_master_key_config()?,
+    )
+}
+
+fn prune(
+    data_dir: &Path,
+    master_keys_dir: &Path,
+    current: Option<String>,
+) -> KVSResult<Vec<String>> {
+    let mut used = vec![];
+    for file_path in stored_files(data_dir) {
+        let data = std::fs::read(&file_path)?;
+        let id = match parse(&data)? {
+            Stored::Plain(_) => None,
+            Stored::Sealed(id, _) => Some(id),
+        };
+        used.extend(id);
+    }
+    let mut pruned = vec![];
+    for entry in std::fs::read_dir(master_keys_dir)?.filter_map(|p| p.ok()) {
+        let id = entry.file_name().to_string_lossy().to_string();
+        if Some(&id) != current.as_ref() && !used.contains(&id) {
+            std::fs::remove_file(entry.path())?;
+            pruned.push(id);
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{
+        create_master_key, decrypt, encrypt, parse, prune, reseal, seal_with, unpack, Stored, MAGIC,
+    };
+
+    #[test]
+    fn test_envelope() {
+        let key = rand::random::<[u8; 32]>();
+        let data = encrypt(&key, b"kvs").unwrap();
+        assert_eq!(decrypt(&key, &data).unwrap(), b"kvs");
+        assert!(decrypt(&rand::random::<[u8; 32]>(), &data).is_err());
+    }
+
+    #[test]
+    fn test_reseal_magic_values() {
+        let dir = std::env::temp_dir().join(format!("kvs_at_rest_{}", std::process::id()));
+        let (data_dir, master_keys_dir) = (dir.join("data"), dir.join("master_keys"));
+        std::fs::create_dir_all(&master_keys_dir).unwrap();
+        let value_path = |key: &str| {
+            std::fs::create_dir_all(data_dir.join(key)).unwrap();
+            data_dir.join(key).join("value")
+        };
+        let read = |path: &Path| unpack(&master_keys_dir, &std::fs::read(path).unwrap()).unwrap();
+        // an uploaded value looking like a sealed file
+        let framed = [MAGIC, &[1], &[0xab; 128]].concat();
+        let framed_path = value_path("framed");
+        std::fs::write(
+            &framed_path,
+            seal_with(&master_keys_dir, None, &framed).unwrap(),
+        )
+        .unwrap();
+        // a plain value written before framing
+        let unframed = [b"kvs at rest 1\0", &[0xab; 128][..]].concat();
+        let unframed_path = value_path("unframed");
+        std::fs::write(&unframed_path, &unframed).unwrap();
+        assert_eq!(read(&framed_path), (framed.clone(), None));
+        assert_eq!(read(&unframed_path), (unframed.clone(), None));
+
+        let first = create_master_key(&master_keys_dir).unwrap();
+        let count = reseal(&data_dir, &master_keys_dir, Some(&first)).unwrap();
+        assert_eq!(count, 2);
+        let stored = std::fs::read(&framed_path).unwrap();
+        assert!(matches!(parse(&stored).unwrap(), Stored::Sealed(id, _) if id == first));
+
+        let second = create_master_key(&master_keys_dir).unwrap();
+        let count = reseal(&data_dir, &master_keys_dir, Some(&second)).unwrap();
+        assert_eq!(count, 2);
+        let count = reseal(&data_dir, &master_keys_dir, Some(&second)).unwrap();
+        assert_eq!(count, 0);
+        let pruned = prune(&data_dir, &master_keys_dir, Some(second.clone())).unwrap();
+        assert_eq!(pruned, vec![first]);
+        assert_eq!(read(&framed_path), (framed.clone(), Some(second.clone())));
+        assert_eq!(
+            read(&unframed_path),
+            (unframed.clone(), Some(second.clone()))
+        );
+
+        let count = reseal(&data_dir, &master_keys_dir, None).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            prune(&data_dir, &master_keys_dir, None).unwrap(),
+            vec![second]
+        );
+        assert_eq!(read(&framed_path), (framed, None));
+        assert_eq!(read(&unframed_path), (unframed, None));
+        assert!(!data_dir.join("framed").join("value.writing").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
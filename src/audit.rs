@@ -0,0 +1,174 @@
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{KVSError, KVSResult};
+
+/// One processed action, stored as a tab separated line in the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditRecord {
+    /// Unix time in milliseconds.
+    pub time_stamp: i64,
+    pub peer: String,
+    pub scope: Option<String>,
+    pub action: Option<String>,
+    pub key_hash: Option<String>,
+    pub result: String,
+}
+
+impl AuditRecord {
+    pub fn new(peer: &str) -> Self {
+        AuditRecord {
+            time_stamp: chrono::Local::now().timestamp_millis(),
+            peer: peer.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+fn escape(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
+
+impl Display for AuditRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_dash = |field: &Option<String>| match field {
+            Some(field) => escape(field),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.time_stamp,
+            escape(&self.peer),
+            or_dash(&self.scope),
+            or_dash(&self.action),
+            or_dash(&self.key_hash),
+            escape(&self.result)
+        )
+    }
+}
+
+impl FromStr for AuditRecord {
+    type Err = KVSError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields = line.splitn(6, '\t').collect::<Vec<_>>();
+        if fields.len() != 6 {
+            return Err(KVSError::LogicError(format!(
+                "Illegal audit record: {}",
+                line
+            )));
+        }
+        let optional = |field: &str| match field {
+            "-" => None,
+            field => Some(field.to_string()),
+        };
+        Ok(AuditRecord {
+            time_stamp: fields[0]
+                .parse()
+                .map_err(|_| KVSError::LogicError(format!("Illegal audit record: {}", line)))?,
+            peer: fields[1].to_string(),
+            scope: optional(fields[2]),
+            action: optional(fields[3]),
+            key_hash: optional(fields[4]),
+            result: fields[5].to_string(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub scope: Option<String>,
+    /// Unix time in milliseconds.
+    pub since: Option<i64>,
+    pub action: Option<String>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        let scope_matches = self.scope.is_none() || self.scope == record.scope;
+        let since_matches = match self.since {
+            Some(since) => record.time_stamp >= since,
+            None => true,
+        };
+        let action_matches = self.action.is_none() || self.action == record.action;
+        scope_matches && since_matches && action_matches
+    }
+}
+
+/// The append-only audit log of the server.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> KVSResult<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, record: &AuditRecord) {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", record).unwrap_or_else(|error| tracing::error!("{}", error));
+    }
+}
+
+pub fn query_audit_log<P: AsRef<Path>>(path: P, query: &AuditQuery) -> KVSResult<Vec<AuditRecord>> {
+    if !path.as_ref().exists() {
+        return Ok(vec![]);
+    }
+    let mut records = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        match line.parse::<AuditRecord>() {
+            Ok(record) if query.matches(&record) => records.push(record),
+            Ok(_) => (),
+            Err(error) => tracing::warn!("{}", error),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditQuery, AuditRecord};
+
+    #[test]
+    fn test_audit_record_line() {
+        let record = AuditRecord {
+            time_stamp: 1,
+            peer: "127.0.0.1".to_string(),
+            scope: Some("0x00".to_string()),
+            action: Some("create".to_string()),
+            key_hash: None,
+            result: "error: The key:\t`a` arealy exists.".to_string(),
+        };
+        let line = record.to_string();
+        assert_eq!(line.split('\t').count(), 6);
+        let parsed = line.parse::<AuditRecord>().unwrap();
+        assert_eq!(parsed.scope, record.scope);
+        assert_eq!(parsed.key_hash, None);
+        assert_eq!(parsed.result, "error: The key: `a` arealy exists.");
+        assert!(AuditQuery {
+            action: Some("create".to_string()),
+            since: Some(1),
+            ..Default::default()
+        }
+        .matches(&parsed));
+        assert!(!AuditQuery {
+            scope: Some("0x01".to_string()),
+            ..Default::default()
+        }
+        .matches(&parsed));
+    }
+}
@@ -1,9 +1,10 @@
 use clap::Parser;
-use key_value_service::Commands;
+use key_value_service::{set_profile, Commands};
 use tracing_subscriber::prelude::*;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author="zmp <zhaoqian.ipp@gmail.com>", version, about="Key Value Service", long_about = None)]
+#[allow(clippy::upper_case_acronyms)]
 struct KVS {
     #[clap(subcommand)]
     command: Commands,
@@ -13,10 +14,19 @@ struct KVS {
 
     #[clap(short, long, help = "Set Repository")]
     repository: Option<String>,
+
+    #[clap(
+        long,
+        help = "Use the profile instead of KVS_PROFILE or `kvs profile use`"
+    )]
+    profile: Option<String>,
 }
 
 fn main() {
     let kvs_cli = KVS::parse();
+    if let Some(profile) = &kvs_cli.profile {
+        set_profile(profile);
+    }
 
     tracing_subscriber::registry()
         // Filter spans based on the RUST_LOG env var.
@@ -0,0 +1,707 @@
+use std::{sync::Mutex, time::Duration};
+
+use crate::{
+    actions::{
+        CreateAction, DeleteAction, Envelope, FetchTokenAction, ForwardAction, ForwardRecord,
+        InboundAction, KVSToken, KeyMeta, KeyShare, ListAction, ListStampAction, PubKeyAction,
+        ReadAction, RotateAction, ShareAction, TeamInfoAction, TeamMember, TeamMeta,
+        TeamUpdateAction, UpdateAction,
+    },
+    errors::{KVSError, KVSResult},
+    kv_session::{request_on, KVSSession, MAX_RATE_LIMITED_RETRIES},
+    secret::Secret,
+    spec::{KVSAction, ReplyCode},
+    utils::{is_scope_addr, parse_mime, sha256, to_addr},
+};
+
+/// The forwarding records `KvsClient::current_scope` follows at most.
+const MAX_FORWARDS: usize = 16;
+
+/// `key`, `scope:key` or `team:<name>:key`
+pub(crate) enum KeyAddress {
+    Own(String),
+    Scope(String, String),
+    Team(String, String),
+}
+
+impl KeyAddress {
+    pub(crate) fn parse(key: &str) -> KeyAddress {
+        if let Some(team_key) = key.strip_prefix("team:") {
+            if let Some((team, key)) = team_key.split_once(':') {
+                return KeyAddress::Team(team.to_string(), key.to_string());
+            }
+        }
+        match key.split_once(':') {
+            Some((scope, key)) if is_scope_addr(scope) => {
+                KeyAddress::Scope(scope.to_string(), key.to_string())
+            }
+            _ => KeyAddress::Own(key.to_string()),
+        }
+    }
+
+    /// `(team, key)` of a key the client can write.
+    pub(crate) fn writable(key: &str, verb: &str) -> KVSResult<(Option<String>, String)> {
+        match KeyAddress::parse(key) {
+            KeyAddress::Own(key) => Ok((None, key)),
+            KeyAddress::Team(team, key) => Ok((Some(team), key)),
+            KeyAddress::Scope(..) => Err(KVSError::LogicError(format!(
+                "Can not {} the key of other scope: {}",
+                verb, key
+            ))),
+        }
+    }
+}
+
+/// A client of a kvs repository acting as one identity.
+///
+/// Keys are addressed as on the command line: `key`, `scope:key` to read a
+/// public or shared key of another scope, and `team:<name>:key`.
+///
+/// ```no_run
+/// use key_value_service::{KeyType, KvsClient, Secret};
+///
+/// let client = KvsClient::connect("127.0.0.1:8888", Secret::new(KeyType::Ed25519))?;
+/// client.create("greeting", b"hello", false, "text/plain")?;
+/// let (value, meta) = client.read("greeting")?;
+/// assert_eq!(value, b"hello");
+/// assert_eq!(meta.size, 5);
+/// # Ok::<(), key_value_service::KVSError>(())
+/// ```
+pub struct KvsClient {
+    repository: String,
+    identity: Secret,
+    sign_requests: bool,
+    token: Mutex<Option<KVSToken>>,
+    /// Open sessions waiting for the next request.
+    sessions: Mutex<Vec<KVSSession>>,
+}
+
+impl std::fmt::Debug for KvsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KvsClient")
+            .field("repository", &self.repository)
+            .field("identity", &self.identity)
+            .field("sign_requests", &self.sign_requests)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KvsClient {
+    /// A client that logs in on its first request.
+    pub fn new(repository: &str, identity: Secret) -> Self {
+        KvsClient {
+            repository: repository.to_string(),
+            identity,
+            sign_requests: true,
+            token: Mutex::new(None),
+            sessions: Mutex::new(vec![]),
+        }
+    }
+
+    /// A client logged in to `repository`.
+    pub fn connect(repository: &str, identity: Secret) -> KVSResult<Self> {
+        let client = KvsClient::new(repository, identity);
+        client.login()?;
+        Ok(client)
+    }
+
+    /// A client using a token fetched before, e.g. by `login`.
+    pub fn with_token(repository: &str, identity: Secret, token: KVSToken) -> Self {
+        KvsClient {
+            token: Mutex::new(Some(token)),
+            ..KvsClient::new(repository, identity)
+        }
+    }
+
+    /// Sign every request with the identity key, on by default. Scopes
+    /// registered since signatures are the default refuse unsigned requests.
+    pub fn sign_requests(mut self, sign_requests: bool) -> Self {
+        self.sign_requests = sign_requests;
+        self
+    }
+
+    pub fn repository(&self) -> &str {
+        &self.repository
+    }
+
+    pub fn identity(&self) -> &Secret {
+        &self.identity
+    }
+
+    /// Fetch a new token by proving the identity to the repository.
+    pub fn login(&self) -> KVSResult<KVSToken> {
+        let token = self.request(&FetchTokenAction {
+            pub_key: self.identity.pub_key_bits.clone(),
+            priv_key_bits: Some(self.identity.priv_key_bits.clone()),
+        })?;
+        *self.token.lock().unwrap() = Some(token.clone());
+        Ok(token)
+    }
+
+    /// The current token, logging in when there is none.
+    pub fn token(&self) -> KVSResult<KVSToken> {
+        let token = self.token.lock().unwrap().clone();
+        match token {
+            Some(token) => Ok(token),
+            None => self.login(),
+        }
+    }
+
+    pub(crate) fn request<R, A>(&self, action: &A) -> KVSResult<R>
+    where
+        R: serde::Serialize,
+        A: KVSAction<R> + Clone,
+    {
+        let priv_key_bits = match self.sign_requests {
+            true => Some(self.identity.priv_key_bits.as_slice()),
+            false => None,
+        };
+        let mut retries = 0;
+        loop {
+            let idle = self.sessions.lock().unwrap().pop();
+            let reused = idle.is_some();
+            let mut session = match idle {
+                Some(session) => session,
+                None => KVSSession::connect(&self.repository)?,
+            };
+            let counts = session.counts();
+            let result = request_on(&mut session, priv_key_bits, action);
+            match &result {
+                // the exchange is complete, the session can take the next request
+                Ok(_) | Err(KVSError::LogicError(_)) | Err(KVSError::RateLimited(_)) => {
+                    self.sessions.lock().unwrap().push(session)
+                }
+                // the remote closed the idle session, nothing was served
+                Err(_) if reused && session.closed_before(counts) => continue,
+                Err(_) => (),
+            }
+            match result {
+                Err(KVSError::RateLimited(retry_after)) if retries < MAX_RATE_LIMITED_RETRIES => {
+                    tracing::debug!("rate limited, retry after {} ms", retry_after);
+                    std::thread::sleep(Duration::from_millis(retry_after));
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The key wrapping the private values of a team.
+    fn wrap_key(
+        &self,
+        token: &KVSToken,
+        team: &Option<String>,
+        public: bool,
+    ) -> KVSResult<Option<Vec<u8>>> {
+        match team {
+            Some(team) if !public => Ok(Some(self.fetch_team(token, team)?.1)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetch a team and unwrap its key with the identity.
+    pub(crate) fn fetch_team(
+        &self,
+        token: &KVSToken,
+        name: &str,
+    ) -> KVSResult<(TeamMeta, Vec<u8>)> {
+        let team = self.request(&TeamInfoAction {
+            token: token.clone(),
+            name: name.to_string(),
+        })?;
+        let team_key = team_key(&self.identity, token, &team)?;
+        Ok((team, team_key))
+    }
+
+    /// Create a key, private values are encrypted before they are sent.
+    /// `mime` must be a mime type, `detect_mime` guesses one.
+    pub fn create(&self, key: &str, value: &[u8], public: bool, mime: &str) -> KVSResult<()> {
+        let token = self.token()?;
+        let (team, key) = KeyAddress::writable(key, "write")?;
+        self.request::<ReplyCode, _>(&CreateAction {
+            wrap_key: self.wrap_key(&token, &team, public)?,
+            meta: new_meta(&token, &key, value, public, mime)?,
+            token,
+            key,
+            value: value.to_vec(),
+            team,
+            identity: Some(self.identity.clone()),
+        })?;
+        Ok(())
+    }
+
+    /// Replace the value of a key, keeping its access list and the scopes
+    /// it is shared with.
+    pub fn update(&self, key: &str, value: &[u8], public: bool, mime: &str) -> KVSResult<()> {
+        let token = self.token()?;
+        let (team, key) = KeyAddress::writable(key, "write")?;
+        let mut meta = new_meta(&token, &key, value, public, mime)?;
+        if let (None, Some(rand)) = (&team, &meta.rand) {
+            // the shares wrap the replaced rand
+            let (_, current) = self.read_unverified(&key)?;
+            for share in current.shares {
+                let pub_key = self.pub_key(&share.scope)?;
+                meta.shares.push(wrap_share(&share.scope, &pub_key, rand)?);
+            }
+        }
+        self.request::<ReplyCode, _>(&UpdateAction {
+            wrap_key: self.wrap_key(&token, &team, public)?,
+            meta,
+            token,
+            key,
+            value: value.to_vec(),
+            team,
+            identity: Some(self.identity.clone()),
+        })?;
+        Ok(())
+    }
+
+    /// Read and decrypt a value, public values must carry a valid signature
+    /// of their publisher.
+    pub fn read(&self, key: &str) -> KVSResult<(Vec<u8>, KeyMeta)> {
+        let action = read_action(&self.identity, self.token()?, key, false);
+        Ok(self.request(&action)?.into_parts())
+    }
+
+    /// `read` without checking the publisher signature of public values.
+    pub fn read_unverified(&self, key: &str) -> KVSResult<(Vec<u8>, KeyMeta)> {
+        let action = read_action(&self.identity, self.token()?, key, true);
+        Ok(self.request(&action)?.into_parts())
+    }
+
+    /// The public key `scope` registered when it last logged in.
+    pub fn pub_key(&self, scope: &str) -> KVSResult<Vec<u8>> {
+        let pub_key = self.request(&PubKeyAction {
+            token: self.token()?,
+            scope: scope.to_string(),
+        })?;
+        checked_pub_key(scope, pub_key)
+    }
+
+    /// `(scope, public key)` of a scope or a base64 public key.
+    pub(crate) fn resolve_pub_key(&self, scope_or_pub_key: &str) -> KVSResult<(String, Vec<u8>)> {
+        if is_scope_addr(scope_or_pub_key) {
+            return Ok((
+                scope_or_pub_key.to_string(),
+                self.pub_key(scope_or_pub_key)?,
+            ));
+        }
+        match base64::decode(scope_or_pub_key) {
+            Ok(pub_key) if Secret::is_pub_key(&pub_key) => Ok((to_addr(&pub_key), pub_key)),
+            _ => Err(KVSError::LogicError(format!(
+                "Illegal scope or public key: {}",
+                scope_or_pub_key
+            ))),
+        }
+    }
+
+    /// Let the scope or base64 public key `to` read your private key.
+    pub fn share(&self, key: &str, to: &str) -> KVSResult<()> {
+        let token = self.token()?;
+        let (scope, pub_key) = self.resolve_pub_key(to)?;
+        let (_, meta) = self.read_unverified(key)?;
+        let rand = match meta.unwrap_rand(&self.identity, &token.get_addr())? {
+            Some(rand) => rand,
+            None => {
+                return Err(KVSError::LogicError(format!(
+                    "The key: `{}` is public.",
+                    key
+                )))
+            }
+        };
+        let share = wrap_share(&scope, &pub_key, &rand)?;
+        self.request::<ReplyCode, _>(&ShareAction {
+            token,
+            key: key.to_string(),
+            scope: share.scope,
+            rand: share.rand,
+        })?;
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) -> KVSResult<()> {
+        let (team, key) = KeyAddress::writable(key, "delete")?;
+        self.request::<ReplyCode, _>(&DeleteAction {
+            token: self.token()?,
+            key,
+            team,
+        })?;
+        Ok(())
+    }
+
+    /// A stamp of your keys, it changes whenever `list` would.
+    pub(crate) fn list_stamp(&self) -> KVSResult<Vec<u8>> {
+        self.request(&ListStampAction {
+            token: self.token()?,
+            team: None,
+        })
+    }
+
+    /// The metas of your keys, of the keys of a team with the namespace
+    /// `team:<name>`, or of the keys of another scope you can read with its
+    /// address as the namespace.
+    pub fn list(&self, namespace: Option<&str>) -> KVSResult<Vec<KeyMeta>> {
+        let (team, scope) = parse_namespace(namespace)?;
+        self.request(&ListAction {
+            token: self.token()?,
+            team,
+            scope,
+        })
+    }
+
+    /// Give the team a new key wrapped for `members` and re-wrap every
+    /// private team value with it. The values keep their `rand`, a removed
+    /// member who read one before keeps reading it until it is updated.
+    pub(crate) fn rotate_team_key(
+        &self,
+        team: &TeamMeta,
+        members: Vec<TeamMember>,
+    ) -> KVSResult<()> {
+        let token = self.token()?;
+        let (_, team_key) = self.fetch_team(&token, &team.name)?;
+        let new_team_key = rand::random::<[u8; 32]>().to_vec();
+        let mut rands = vec![];
+        for meta in self.list(Some(&format!("team:{}", team.name)))? {
+            if let Some(rand) = meta.unwrap_team_rand(&team_key)? {
+                rands.push((meta.name.clone(), KeyMeta::wrap_rand(&new_team_key, &rand)?));
+            }
+        }
+        let mut new_members = vec![];
+        for member in members {
+            let pub_key = self.pub_key(&member.scope)?;
+            new_members.push(TeamMember {
+                key: Secret::encrypt_with_pub_key_bits(&pub_key, &new_team_key)?,
+                ..member
+            });
+        }
+        self.request(&TeamUpdateAction {
+            token,
+            name: team.name.clone(),
+            members: new_members,
+            key_version: team.key_version + 1,
+            rands,
+        })?;
+        Ok(())
+    }
+
+    /// The scope `scope` moved to, following its forwarding records.
+    pub(crate) fn current_scope(&self, scope: &str) -> KVSResult<String> {
+        let mut scope = scope.to_string();
+        for _ in 0..MAX_FORWARDS {
+            match self.request(&ForwardAction {
+                scope: scope.clone(),
+            })? {
+                None => return Ok(scope),
+                Some(record) if record.from == scope && record.verify() => scope = record.to,
+                Some(_) => {
+                    return Err(KVSError::LogicError(format!(
+                        "The forwarding record of {} is not signed by its key.",
+                        scope
+                    )))
+                }
+            }
+        }
+        Err(KVSError::LogicError(format!(
+            "The scope: `{}` is forwarded too many times.",
+            scope
+        )))
+    }
+
+    /// Move the keys of the scope to `new_identity`, along with its team
+    /// memberships and the values shared with it, and leave a forwarding
+    /// record at the old scope. Replies the token of the new identity.
+    pub fn rotate(&self, new_identity: &Secret) -> KVSResult<KVSToken> {
+        let token = self.token()?;
+        if token.claims.is_some() {
+            return Err(KVSError::LogicError(
+                "Only a full token can rotate the identity.".to_string(),
+            ));
+        }
+        let new_token = self.request(&FetchTokenAction {
+            pub_key: new_identity.pub_key_bits.clone(),
+            priv_key_bits: Some(new_identity.priv_key_bits.clone()),
+        })?;
+        let (mut rands, mut signatures) = (vec![], vec![]);
+        for mut meta in self.list(None)? {
+            match meta.unwrap_rand(&self.identity, &token.get_addr())? {
+                Some(rand) => {
+                    let rand = KeyMeta::wrap_rand(&new_identity.priv_key_bits, &rand)?;
+                    rands.push((meta.name.clone(), rand));
+                }
+                None => {
+                    meta.owner = new_token.id.clone();
+                    meta.sign(new_identity)?;
+                    signatures.push((meta.name.clone(), meta.signature.unwrap()));
+                }
+            }
+        }
+        let (mut inbound, mut unreadable) = (vec![], vec![]);
+        for (of, key) in self.request(&InboundAction {
+            token: token.clone(),
+        })? {
+            match Secret::decrypt_width_priv_key_bits(&self.identity.priv_key_bits, &key) {
+                Ok(key) => {
+                    let key = Secret::encrypt_with_pub_key_bits(&new_identity.pub_key_bits, &key)?;
+                    inbound.push((of, key));
+                }
+                Err(_) => unreadable.push(of.to_string()),
+            }
+        }
+        if !unreadable.is_empty() {
+            return Err(KVSError::LogicError(format!(
+                "Can not read {}, nothing was rotated.",
+                unreadable.join(", ")
+            )));
+        }
+        self.request(&RotateAction {
+            token,
+            new_token: new_token.clone(),
+            rands,
+            signatures,
+            inbound,
+            forward: ForwardRecord::signed(&self.identity, new_identity)?,
+        })?;
+        Ok(new_token)
+    }
+}
+
+/// A new value's meta, `rand` is the key of private values.
+pub(crate) fn new_meta(
+    token: &KVSToken,
+    key: &str,
+    value: &[u8],
+    public: bool,
+    mime: &str,
+) -> KVSResult<KeyMeta> {
+    Ok(KeyMeta {
+        mime: parse_mime(mime)?,
+        size: value.len() as u64,
+        owner: token.id.clone(),
+        name: key.to_string(),
+        rand: match public {
+            true => None,
+            false => Some(rand::random::<[u8; 32]>().to_vec()),
+        },
+        original_hash: sha256(value),
+        shares: vec![],
+        acl: None,
+        envelope: Envelope::Legacy,
+        signature: None,
+    })
+}
+
+/// A registered public key, the repository can not swap it for another.
+pub(crate) fn checked_pub_key(scope: &str, pub_key: Vec<u8>) -> KVSResult<Vec<u8>> {
+    if to_addr(&pub_key) != scope {
+        return Err(KVSError::LogicError(format!(
+            "The public key of {} does not match its scope",
+            scope
+        )));
+    }
+    Ok(pub_key)
+}
+
+/// The `rand` of a private value wrapped for `scope`.
+pub(crate) fn wrap_share(scope: &str, pub_key: &[u8], rand: &[u8]) -> KVSResult<KeyShare> {
+    Ok(KeyShare {
+        scope: scope.to_string(),
+        rand: Secret::encrypt_with_pub_key_bits(pub_key, rand)?,
+    })
+}
+
+/// Unwrap the key of `team` with the identity.
+pub(crate) fn team_key(identity: &Secret, token: &KVSToken, team: &TeamMeta) -> KVSResult<Vec<u8>> {
+    match team.member(&token.get_addr()) {
+        Some(member) => Secret::decrypt_width_priv_key_bits(&identity.priv_key_bits, &member.key),
+        None => Err(KVSError::LogicError(format!(
+            "You are not a member of the team: `{}`.",
+            team.name
+        ))),
+    }
+}
+
+pub(crate) fn read_action(
+    identity: &Secret,
+    token: KVSToken,
+    key: &str,
+    no_verify: bool,
+) -> ReadAction {
+    let (scope, team, key) = match KeyAddress::parse(key) {
+        KeyAddress::Own(key) => (None, None, key),
+        KeyAddress::Scope(scope, key) => (Some(scope), None, key),
+        KeyAddress::Team(team, key) => (None, Some(team), key),
+    };
+    ReadAction {
+        token,
+        key,
+        scope,
+        team,
+        no_verify,
+        identity: Some(identity.clone()),
+    }
+}
+
+/// `(team, scope)` of a `team:<name>` or scope namespace, both `None` for
+/// your own keys.
+pub(crate) fn parse_namespace(
+    namespace: Option<&str>,
+) -> KVSResult<(Option<String>, Option<String>)> {
+    match namespace {
+        Some(namespace) => match namespace.strip_prefix("team:") {
+            Some(team) => Ok((Some(team.to_string()), None)),
+            None if is_scope_addr(namespace) => Ok((None, Some(namespace.to_string()))),
+            None => Err(KVSError::LogicError(format!(
+                "Illegal namespace: {}",
+                namespace
+            ))),
+        },
+        None => Ok((None, None)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KvsClient;
+    use crate::{
+        actions::{AclAction, AclOp, SigningAction, TeamCreateAction},
+        kv_server::test::test_repository,
+        secret::{KeyType, Secret},
+        spec::ReplyCode,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let client = KvsClient::connect(&test_repository(), Secret::new(KeyType::Ed25519)).unwrap();
+        let stamp = client.list_stamp().unwrap();
+        client
+            .create("round/private", b"v1", false, "text/plain")
+            .unwrap();
+        client
+            .create("round/public", b"{}", true, "application/json")
+            .unwrap();
+        assert!(client
+            .create("round/public", b"{}", true, "application/json")
+            .is_err());
+        assert_ne!(client.list_stamp().unwrap(), stamp);
+
+        let (value, meta) = client.read("round/private").unwrap();
+        assert_eq!((value.as_slice(), meta.size), (&b"v1"[..], 2));
+        assert!(meta.rand.is_some());
+        let (value, meta) = client.read("round/public").unwrap();
+        assert_eq!(
+            (value.as_slice(), meta.mime.as_str()),
+            (&b"{}"[..], "application/json")
+        );
+        assert!(meta.rand.is_none() && meta.signature.is_some());
+
+        client
+            .update("round/private", b"v2", false, "text/plain")
+            .unwrap();
+        assert_eq!(client.read("round/private").unwrap().0, b"v2");
+        let mut names = client
+            .list(None)
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["round/private", "round/public"]);
+
+        client.delete("round/private").unwrap();
+        assert!(client.read("round/private").is_err());
+        assert_eq!(client.list(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_update_keeps_shares() {
+        let repository = test_repository();
+        let owner = KvsClient::connect(&repository, Secret::new(KeyType::Ed25519)).unwrap();
+        let reader = KvsClient::connect(&repository, Secret::new(KeyType::Ed25519)).unwrap();
+        let reader_scope = reader.token().unwrap().get_addr();
+        let address = format!("{}:shared", owner.token().unwrap().get_addr());
+        owner.create("shared", b"v1", false, "text/plain").unwrap();
+        owner.share("shared", &reader_scope).unwrap();
+        assert_eq!(reader.read(&address).unwrap().0, b"v1");
+
+        owner.update("shared", b"v2", false, "text/plain").unwrap();
+        assert_eq!(reader.read(&address).unwrap().0, b"v2");
+        assert_eq!(owner.read("shared").unwrap().0, b"v2");
+    }
+
+    #[test]
+    fn test_request_retries_closed_sessions() {
+        let client = KvsClient::connect(&test_repository(), Secret::new(KeyType::Ed25519)).unwrap();
+        let stamp = client.list_stamp().unwrap();
+        client.sessions.lock().unwrap()[0].shutdown();
+        assert_eq!(client.list_stamp().unwrap(), stamp);
+        assert_eq!(client.sessions.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_new_scopes_require_signatures() {
+        let repository = test_repository();
+        let identity = Secret::new(KeyType::Ed25519);
+        let client = KvsClient::connect(&repository, identity.clone()).unwrap();
+        let token = client.token().unwrap();
+        client.list_stamp().unwrap();
+        let unsigned =
+            KvsClient::with_token(&repository, identity, token.clone()).sign_requests(false);
+        assert!(unsigned.list_stamp().is_err());
+
+        client
+            .request::<ReplyCode, _>(&SigningAction {
+                token,
+                required: false,
+            })
+            .unwrap();
+        unsigned.list_stamp().unwrap();
+    }
+
+    #[test]
+    fn test_rotate_moves_inbound_keys() {
+        let repository = test_repository();
+        let rotating = KvsClient::connect(&repository, Secret::new(KeyType::Ed25519)).unwrap();
+        let other = KvsClient::connect(&repository, Secret::new(KeyType::Ed25519)).unwrap();
+        let old_scope = rotating.token().unwrap().get_addr();
+        let other_scope = other.token().unwrap().get_addr();
+        rotating.create("own", b"own", false, "text/plain").unwrap();
+        other
+            .create("shared", b"shared", false, "text/plain")
+            .unwrap();
+        other.share("shared", &old_scope).unwrap();
+        other
+            .create("listed", b"listed", true, "text/plain")
+            .unwrap();
+        other
+            .request(&AclAction {
+                token: other.token().unwrap(),
+                key: "listed".to_string(),
+                op: AclOp::Set(vec![old_scope.clone()]),
+            })
+            .unwrap();
+        let team_key = rand::random::<[u8; 32]>();
+        rotating
+            .request(&TeamCreateAction {
+                token: rotating.token().unwrap(),
+                name: "rotating".to_string(),
+                key: Secret::encrypt_with_pub_key_bits(
+                    &rotating.identity().pub_key_bits,
+                    &team_key,
+                )
+                .unwrap(),
+            })
+            .unwrap();
+
+        let new_identity = Secret::new(KeyType::Ed25519);
+        let new_token = rotating.rotate(&new_identity).unwrap();
+        let rotated = KvsClient::with_token(&repository, new_identity, new_token.clone());
+        assert_eq!(rotated.read("own").unwrap().0, b"own");
+        let shared = format!("{}:shared", other_scope);
+        assert_eq!(rotated.read(&shared).unwrap().0, b"shared");
+        let listed = format!("{}:listed", other_scope);
+        assert_eq!(rotated.read(&listed).unwrap().0, b"listed");
+        let (team, key) = rotated.fetch_team(&new_token, "rotating").unwrap();
+        assert_eq!(team.members[0].scope, new_token.get_addr());
+        assert_eq!(key, team_key);
+    }
+}
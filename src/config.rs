@@ -1,63 +1,147 @@
-use std::{io::Write, net::TcpStream, path::PathBuf, time::Duration};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
 use crate::{
-    actions::{FetchTokenAction, KVSToken},
-    errors::KVSResult,
-    kv_session::KVSSession,
+    actions::KVSToken,
+    client::KvsClient,
+    errors::{KVSError, KVSResult},
+    rate_limit::RateLimit,
     secret::Secret,
-    spec::KVSAction,
 };
 
-pub fn get_or_create_token(
-    repository: &String,
-    force_create: bool,
-) -> KVSResult<(KVSToken, String)> {
-    let user_token_file_path = &get_or_create_user_config_dir()?.join("token");
-
-    if user_token_file_path.exists() && force_create == false {
-        Ok((
-            bincode::deserialize(&std::fs::read(user_token_file_path)?)?,
-            user_token_file_path.display().to_string(),
-        ))
+pub fn get_or_create_token(repository: &str, force_create: bool) -> KVSResult<(KVSToken, String)> {
+    let user_tokens_dir_path = get_or_create_user_config_dir()?.join("tokens");
+    std::fs::create_dir_all(&user_tokens_dir_path)?;
+    let repository_file_name = repository
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect::<String>();
+    let user_token_file_path = &user_tokens_dir_path.join(repository_file_name);
+
+    if let (Ok(token), false) = (std::env::var("KVS_TOKEN"), force_create) {
+        let token = base64::decode(token.trim())
+            .map_err(|_| KVSError::LogicError("Illegal KVS_TOKEN".to_string()))?;
+        return Ok((bincode::deserialize(&token)?, "KVS_TOKEN".to_string()));
+    }
+    // tokens saved before claims were added do not deserialize, fetch a new one
+    let saved_token = match user_token_file_path.exists() && !force_create {
+        true => bincode::deserialize::<KVSToken>(&std::fs::read(user_token_file_path)?).ok(),
+        false => None,
+    };
+    if let Some(token) = saved_token {
+        Ok((token, user_token_file_path.display().to_string()))
     } else {
-        let secret = get_or_create_secret()?;
-        let mut session = {
-            let stream = TcpStream::connect(repository)?;
-            stream.set_read_timeout(Some(Duration::from_millis(1000)))?;
-            KVSSession::new(stream)
-        }?;
-        let token = FetchTokenAction {
-            pub_key: secret.pub_key_bits.to_vec(),
-        }
-        .request(&mut session)?;
+        let token = KvsClient::new(repository, get_or_create_secret()?)
+            .sign_requests(get_sign_requests_config()?)
+            .login()?;
 
         let token_bytes = bincode::serialize(&token)?;
-        std::fs::write(&user_token_file_path, &token_bytes)?;
+        std::fs::write(user_token_file_path, &token_bytes)?;
         Ok((token, user_token_file_path.display().to_string()))
     }
 }
 
+static SECRET: OnceLock<Secret> = OnceLock::new();
+
 pub fn get_or_create_secret() -> KVSResult<Secret> {
+    if let Some(secret) = SECRET.get() {
+        return Ok(secret.clone());
+    }
     let user_kvs_config_dir_path = get_or_create_user_config_dir()?;
     let user_secret_file_path = user_kvs_config_dir_path.join("secret");
-    if user_secret_file_path.exists() {
-        Ok(Secret::from(std::fs::read_to_string(
-            user_secret_file_path,
-        )?))
+    let secret = if user_secret_file_path.exists() {
+        let content = std::fs::read_to_string(user_secret_file_path)?;
+        if Secret::is_encrypted(&content) {
+            Secret::from_encrypted_string(
+                &content,
+                &get_passphrase("Passphrase of your identity: ")?,
+            )?
+        } else {
+            Secret::from(content)
+        }
     } else {
         let secret = Secret::default();
         std::fs::create_dir_all(user_kvs_config_dir_path)?;
-        let mut file = std::fs::File::create(user_secret_file_path)?;
-        file.write_all(&secret.to_string().as_bytes())?;
-        Ok(secret)
+        write_secret_file(&user_secret_file_path, &secret.to_string())?;
+        secret
+    };
+    Ok(SECRET.get_or_init(|| secret).clone())
+}
+
+/// Write a secret file only its owner can read.
+pub fn write_secret_file(secret_file_path: &Path, content: &str) -> KVSResult<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // the mode is only applied to new files
+        if secret_file_path.exists() {
+            std::fs::set_permissions(secret_file_path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options
+        .open(secret_file_path)?
+        .write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// `secret` as the content of a file replacing `secret_file_path`, encrypted
+/// with the passphrase `passphrase` replies when the replaced file is.
+pub fn secret_file_content(
+    secret_file_path: &Path,
+    secret: &Secret,
+    passphrase: impl FnOnce() -> KVSResult<String>,
+) -> KVSResult<String> {
+    let encrypted = secret_file_path.exists()
+        && Secret::is_encrypted(&std::fs::read_to_string(secret_file_path)?);
+    match encrypted {
+        true => secret.to_encrypted_string(&passphrase()?),
+        false => Ok(secret.to_string()),
+    }
+}
+
+/// The passphrase of the secret file from `KVS_PASSPHRASE`, the agent or a prompt.
+pub fn get_passphrase(prompt: &str) -> KVSResult<String> {
+    if let Ok(passphrase) = std::env::var("KVS_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    #[cfg(unix)]
+    {
+        use std::io::Read;
+        let agent_socket_path = get_agent_socket_path()?;
+        if agent_socket_path.exists() {
+            if let Ok(mut stream) = std::os::unix::net::UnixStream::connect(agent_socket_path) {
+                let mut passphrase = String::new();
+                stream.read_to_string(&mut passphrase)?;
+                return Ok(passphrase);
+            }
+        }
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+/// `KVS_AGENT_SOCK` or `agent.sock` in the profile directory.
+pub fn get_agent_socket_path() -> KVSResult<PathBuf> {
+    match std::env::var("KVS_AGENT_SOCK") {
+        Ok(path) => Ok(PathBuf::from(path)),
+        Err(_) => Ok(get_or_create_user_config_dir()?.join("agent.sock")),
     }
 }
 
 pub fn get_or_create_jwt_secret(froce_create: bool) -> KVSResult<Vec<u8>> {
-    let user_kvs_config_dir_path = get_or_create_user_config_dir()?;
-    let jwt_secret_file_path = user_kvs_config_dir_path.join("jwt_secret");
+    let jwt_secret_file_path = get_or_create_server_dir()?.join("jwt_secret");
 
-    if jwt_secret_file_path.exists() && froce_create == false {
+    if jwt_secret_file_path.exists() && !froce_create {
         Ok(std::fs::read(jwt_secret_file_path)?)
     } else {
         let jwt_secret = (0..256).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
@@ -66,10 +150,102 @@ pub fn get_or_create_jwt_secret(froce_create: bool) -> KVSResult<Vec<u8>> {
     }
 }
 
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Select the profile for this process, before `KVS_PROFILE` and `kvs profile use`.
+pub fn set_profile(name: &str) {
+    PROFILE.set(name.to_string()).ok();
+}
+
+pub fn get_or_create_kvs_home_dir() -> KVSResult<PathBuf> {
+    let kvs_home_dir_path = dirs::home_dir().unwrap().join(".kvs");
+    if !kvs_home_dir_path.exists() {
+        std::fs::create_dir_all(&kvs_home_dir_path)?;
+    }
+    Ok(kvs_home_dir_path)
+}
+
+/// The directory of the server state, `~/.kvs` whatever the profile.
+pub fn get_or_create_server_dir() -> KVSResult<PathBuf> {
+    get_or_create_kvs_home_dir()
+}
+
+/// The config keys read by the server, kept in the server directory.
+pub const SERVER_CONFIG_KEYS: [&str; 3] = ["rate_limit_ip", "rate_limit_scope", "master_key"];
+
+/// The file of a config key, in the server directory for `SERVER_CONFIG_KEYS`
+/// and in the selected profile for the others.
+pub fn get_config_file_path(key: &str) -> KVSResult<PathBuf> {
+    let config_dir_path = match SERVER_CONFIG_KEYS.contains(&key) {
+        true => get_or_create_server_dir()?.join("config"),
+        false => get_or_create_user_config_kv_dir()?,
+    };
+    std::fs::create_dir_all(&config_dir_path)?;
+    Ok(config_dir_path.join(key))
+}
+
+/// The name of the selected profile.
+pub fn get_profile() -> KVSResult<String> {
+    if let Some(name) = PROFILE.get() {
+        return Ok(name.to_string());
+    }
+    if let Ok(name) = std::env::var("KVS_PROFILE") {
+        return Ok(name);
+    }
+    let profile_file_path = get_or_create_kvs_home_dir()?.join("profile");
+    if profile_file_path.exists() {
+        return Ok(std::fs::read_to_string(profile_file_path)?
+            .trim()
+            .to_string());
+    }
+    Ok(DEFAULT_PROFILE.to_string())
+}
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// The directory of a profile, `~/.kvs` itself for the default profile.
+pub fn get_profile_dir(name: &str) -> KVSResult<PathBuf> {
+    if name == DEFAULT_PROFILE {
+        return get_or_create_kvs_home_dir();
+    }
+    let legal_name = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !legal_name {
+        return Err(KVSError::LogicError(format!(
+            "Illegal profile name: {}",
+            name
+        )));
+    }
+    Ok(get_or_create_kvs_home_dir()?.join("profiles").join(name))
+}
+
+/// The names of the default profile and all created profiles.
+pub fn list_profiles() -> KVSResult<Vec<String>> {
+    let profiles_dir_path = get_or_create_kvs_home_dir()?.join("profiles");
+    let mut profiles = vec![];
+    if profiles_dir_path.exists() {
+        profiles = std::fs::read_dir(profiles_dir_path)?
+            .filter_map(|p| p.ok())
+            .map(|p| p.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        profiles.sort();
+    }
+    profiles.insert(0, DEFAULT_PROFILE.to_string());
+    Ok(profiles)
+}
+
+/// The directory of the selected profile.
 pub fn get_or_create_user_config_dir() -> KVSResult<PathBuf> {
-    let user_kvs_config_dir_path = dirs::home_dir().unwrap().join(".kvs");
+    let profile = get_profile()?;
+    let user_kvs_config_dir_path = get_profile_dir(&profile)?;
     if !user_kvs_config_dir_path.exists() {
-        std::fs::create_dir_all(&user_kvs_config_dir_path)?;
+        return Err(KVSError::LogicError(format!(
+            "The profile: `{}` is not exists.",
+            profile
+        )));
     }
     Ok(user_kvs_config_dir_path)
 }
@@ -82,6 +258,14 @@ pub fn get_or_create_data_dir() -> KVSResult<PathBuf> {
     Ok(user_kvs_config_dir_path)
 }
 
+pub fn get_or_create_identities_dir() -> KVSResult<PathBuf> {
+    let identities_dir_path = get_or_create_data_dir()?.join("identities");
+    if !identities_dir_path.exists() {
+        std::fs::create_dir_all(&identities_dir_path)?;
+    }
+    Ok(identities_dir_path)
+}
+
 pub fn get_or_create_user_config_kv_dir() -> KVSResult<PathBuf> {
     let user_config_dir = get_or_create_user_config_dir()?;
     let user_config_kv_dir = user_config_dir.join("config");
@@ -102,3 +286,120 @@ pub fn get_or_create_repository_config() -> KVSResult<String> {
         user_config_kv_repository_file_path,
     )?)
 }
+
+/// Read a server rate limit from the config key, `off` disables it.
+pub fn get_rate_limit_config(key: &str, default: &str) -> KVSResult<Option<RateLimit>> {
+    let rate_limit_file_path = get_config_file_path(key)?;
+    let content = if rate_limit_file_path.exists() {
+        std::fs::read_to_string(rate_limit_file_path)?
+    } else {
+        default.to_string()
+    };
+    match content.trim() {
+        "off" => Ok(None),
+        content => Ok(Some(content.parse()?)),
+    }
+}
+
+pub fn get_audit_log_path() -> KVSResult<PathBuf> {
+    Ok(get_or_create_server_dir()?.join("audit.log"))
+}
+
+pub fn get_or_create_master_keys_dir() -> KVSResult<PathBuf> {
+    let master_keys_dir_path = get_or_create_server_dir()?.join("master_keys");
+    if !master_keys_dir_path.exists() {
+        std::fs::create_dir_all(&master_keys_dir_path)?;
+    }
+    Ok(master_keys_dir_path)
+}
+
+/// The states of the directories synced by the profile.
+pub fn get_or_create_sync_state_dir() -> KVSResult<PathBuf> {
+    let sync_state_dir_path = get_or_create_user_config_dir()?.join("sync_state");
+    if !sync_state_dir_path.exists() {
+        std::fs::create_dir_all(&sync_state_dir_path)?;
+    }
+    Ok(sync_state_dir_path)
+}
+
+/// The id of the master key sealing the value and meta files of the server,
+/// `None` when at-rest encryption is off.
+pub fn get_master_key_config() -> KVSResult<Option<String>> {
+    let master_key_file_path = get_config_file_path("master_key")?;
+    if !master_key_file_path.exists() {
+        return Ok(None);
+    }
+    match std::fs::read_to_string(master_key_file_path)?.trim() {
+        "" | "off" => Ok(None),
+        id => Ok(Some(id.to_string())),
+    }
+}
+
+/// Whether the client signs every request with its identity key, until
+/// `signing off`.
+pub fn get_sign_requests_config() -> KVSResult<bool> {
+    let sign_requests_file_path = get_or_create_user_config_kv_dir()?.join("sign_requests");
+    Ok(!sign_requests_file_path.exists()
+        || std::fs::read_to_string(sign_requests_file_path)?.trim() == "true")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        get_config_file_path, get_or_create_kvs_home_dir, get_or_create_server_dir, get_profile,
+        get_profile_dir, secret_file_content, write_secret_file, DEFAULT_PROFILE,
+    };
+    use crate::{kv_server::test::test_repository, secret::Secret};
+
+    #[test]
+    fn test_profile_dirs() {
+        // the home of the test server
+        test_repository();
+        let home = get_or_create_kvs_home_dir().unwrap();
+        assert_eq!(get_profile().unwrap(), DEFAULT_PROFILE);
+        assert_eq!(get_profile_dir(DEFAULT_PROFILE).unwrap(), home);
+        assert_eq!(
+            get_profile_dir("work-2_a").unwrap(),
+            home.join("profiles").join("work-2_a")
+        );
+        for name in ["", "../default", "a/b", "a.b", &"a".repeat(65)] {
+            assert!(get_profile_dir(name).is_err());
+        }
+        assert_eq!(get_or_create_server_dir().unwrap(), home);
+        assert_eq!(
+            get_config_file_path("rate_limit_ip").unwrap(),
+            home.join("config").join("rate_limit_ip")
+        );
+        assert_eq!(
+            get_config_file_path("repository").unwrap(),
+            home.join("config").join("repository")
+        );
+    }
+
+    #[test]
+    fn test_secret_file() {
+        let secret_file_path =
+            std::env::temp_dir().join(format!("kvs_secret_{}", std::process::id()));
+        let secret = Secret::default();
+        std::fs::write(&secret_file_path, "").unwrap();
+        write_secret_file(&secret_file_path, &secret.to_string()).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(&secret_file_path).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        let passphrase = || Ok("correct horse".to_string());
+        assert!(!Secret::is_encrypted(
+            &secret_file_content(&secret_file_path, &secret, passphrase).unwrap()
+        ));
+
+        // a protected identity is replaced by one under a passphrase
+        let content = secret.to_encrypted_string("correct horse").unwrap();
+        write_secret_file(&secret_file_path, &content).unwrap();
+        let content =
+            secret_file_content(&secret_file_path, &Secret::default(), passphrase).unwrap();
+        assert!(Secret::from_encrypted_string(&content, "correct horse").is_ok());
+        std::fs::remove_file(secret_file_path).unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 use thiserror::Error;
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum KVSError {
     #[error("IO error: {0}")]
-    IOError(#[from]std::io::Error),
+    IOError(#[from] std::io::Error),
     #[error("AESGcmError Error: {0}")]
     AESGcmError(aes_gcm::Error),
     #[error("TryFromSlice Error: {0}")]
@@ -14,8 +15,12 @@ pub enum KVSError {
     LogicError(String),
     #[error("RSA Error: {0}")]
     BincodeError(#[from] Box<bincode::ErrorKind>),
+    #[error("Rate limited, retry after {0} ms")]
+    RateLimited(u64),
     #[error("MimeFromStr Error: {0}")]
     MimeFromStrError(#[from] mime::FromStrError),
+    #[error("Ignore Error: {0}")]
+    IgnoreError(#[from] ignore::Error),
 }
 
 pub type KVSResult<T> = Result<T, KVSError>;
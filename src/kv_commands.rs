@@ -1,9 +1,6 @@
-use indicatif::ProgressIterator;
 use std::{
-    collections::HashMap,
-    io::Read,
-    net::{TcpListener, TcpStream},
-    time::Duration,
+    io::{Read, Write},
+    net::TcpListener,
 };
 use xshell::{cmd, Shell};
 
@@ -11,22 +8,38 @@ use clap::Subcommand;
 
 use crate::{
     actions::{
-        CreateAction, DeleteAction, KeyMeta, ListAction, LocalFileMeta, ReadAction,
-        RemoteVersionAction, UpdateAction,
+        AclAction, AclOp, AuditAction, CreateAction, Envelope, FileFilter, ForwardAction, KeyMeta,
+        RemoteVersionAction, RewrapAction, RewrappedRand, ShareAction, SigningAction,
+        TeamCreateAction, TeamInfoAction, TeamMember, TeamRole, TeamUpdateAction, TokenClaims,
+        TokenCreateAction, UnshareAction, READ_ONLY_ACTIONS,
     },
+    at_rest,
+    audit::{query_audit_log, AuditLog, AuditQuery, AuditRecord},
+    client::{wrap_share, KeyAddress, KvsClient},
     config::{
-        get_or_create_data_dir, get_or_create_jwt_secret, get_or_create_repository_config,
-        get_or_create_secret, get_or_create_token, get_or_create_user_config_dir,
-        get_or_create_user_config_kv_dir,
+        get_agent_socket_path, get_audit_log_path, get_config_file_path, get_or_create_data_dir,
+        get_or_create_jwt_secret, get_or_create_kvs_home_dir, get_or_create_repository_config,
+        get_or_create_secret, get_or_create_server_dir, get_or_create_token,
+        get_or_create_user_config_dir, get_or_create_user_config_kv_dir, get_passphrase,
+        get_profile, get_profile_dir, get_rate_limit_config, get_sign_requests_config,
+        list_profiles, secret_file_content, write_secret_file, DEFAULT_PROFILE,
     },
     errors::{KVSError, KVSResult},
-    kv_server::service,
-    kv_session::KVSSession,
-    secret::Secret,
-    spec::KVSAction,
-    utils::{sha256, to_addr},
+    kv_server::{serve_connection, ReplayGuard, ServerContext},
+    kv_session::request,
+    rate_limit::RateLimiter,
+    secret::{KeyType, Secret},
+    shamir::{self, Commitment, ShamirManifest},
+    signature::DetachedSignature,
+    sync::{pull_dir, sync_dir, SyncOptions},
+    utils::{detect_mime, is_scope_addr, parse_duration, parse_mime, parse_since, sha256, to_addr},
 };
 
+/// 500 requests burst, then 100 requests per second.
+const DEFAULT_IP_RATE_LIMIT: &str = "500,100";
+/// 200 requests burst, then 50 requests per second.
+const DEFAULT_SCOPE_RATE_LIMIT: &str = "200,50";
+
 #[derive(Debug, Subcommand, Clone)]
 pub enum Commands {
     #[clap(long_about = "Start kvs server")]
@@ -56,8 +69,12 @@ pub enum Commands {
 
         #[clap(short, long, help = "As public key")]
         public: bool,
-        #[clap(short, long, help = "Value Type", default_value = "text/plain")]
-        value_type: String,
+        #[clap(
+            short,
+            long,
+            help = "Value Type, detected from the content and the file name or key when not given"
+        )]
+        value_type: Option<String>,
     },
 
     #[clap(long_about = "Update key value")]
@@ -72,15 +89,68 @@ pub enum Commands {
         #[clap(short, long, help = "As public key")]
         public: bool,
 
-        #[clap(short, long, help = "Value Type", default_value = "text/plain")]
-        value_type: String,
+        #[clap(
+            short,
+            long,
+            help = "Value Type, detected from the content and the file name or key when not given"
+        )]
+        value_type: Option<String>,
     },
 
     #[clap(long_about = "Read key content")]
-    Read { key: String },
+    Read {
+        key: String,
+
+        #[clap(
+            long,
+            help = "Skip the check of the publisher signature of public keys"
+        )]
+        no_verify: bool,
+    },
     #[clap(long_about = "Delete key")]
     Delete { key: String },
 
+    #[clap(long_about = "Share a private key with the scope or base64 public key")]
+    Share { key: String, to: String },
+
+    #[clap(long_about = "Revoke the access of the scope or base64 public key to a private key")]
+    Unshare { key: String, to: String },
+
+    #[clap(
+        long_about = "Split a key into shares for trustees, any threshold of them can rebuild it with `kvs combine`"
+    )]
+    Split {
+        key: String,
+
+        #[clap(short, long, help = "The number of shares that rebuild the value")]
+        threshold: u8,
+
+        #[clap(
+            long,
+            value_delimiter = ',',
+            required = true,
+            help = "The scopes or base64 public keys of the trustees, one share each"
+        )]
+        to: Vec<String>,
+
+        #[clap(long, help = "Delete the key once it is split")]
+        delete: bool,
+    },
+
+    #[clap(
+        long_about = "Rebuild a split key from the shares readable by you, or release your share to the scope rebuilding it"
+    )]
+    Combine {
+        #[clap(help = "The split key, `scope:key` for the key of other scope")]
+        key: String,
+
+        #[clap(long, help = "Release your share to the scope or base64 public key")]
+        release_to: Option<String>,
+
+        #[clap(short, long, help = "Write to the file instead of stdout")]
+        out: Option<String>,
+    },
+
     #[clap(
         long_about = "Upload all file in current directory and use the relative directory as key"
     )]
@@ -90,48 +160,237 @@ pub enum Commands {
 
         #[clap(short, long, help = "As public key")]
         public: bool,
+
+        #[clap(
+            short,
+            long,
+            help = "Number of concurrent uploads",
+            default_value = "4"
+        )]
+        jobs: usize,
+
+        #[clap(
+            long,
+            help = "Prefix of the keys, e.g. `docs` syncs `a.txt` to `docs/a.txt`",
+            default_value = ""
+        )]
+        prefix: String,
+
+        #[clap(long, help = "Delete the remote keys under the prefix missing locally")]
+        delete: bool,
+
+        #[clap(
+            long,
+            help = "Print what would be created, updated and deleted, change nothing"
+        )]
+        dry_run: bool,
+
+        #[clap(long, help = "Only sync the files matching this glob, can be repeated")]
+        include: Vec<String>,
+
+        #[clap(
+            long,
+            help = "Do not sync the files matching this glob, can be repeated"
+        )]
+        exclude: Vec<String>,
+
+        #[clap(long, help = "Honor `.gitignore` files as well as `.kvsignore` files")]
+        gitignore: bool,
+    },
+
+    #[clap(
+        long_about = "Download the keys under a prefix into a directory and use the rest of the key as the relative path"
+    )]
+    Pull {
+        #[clap(help = "`prefix`, `scope:prefix` or `team:<name>:prefix`, `/` for all keys")]
+        source: String,
+
+        #[clap(help = "Dir path")]
+        path: String,
+
+        #[clap(
+            short,
+            long,
+            help = "Number of concurrent downloads",
+            default_value = "4"
+        )]
+        jobs: usize,
+
+        #[clap(
+            long,
+            help = "Skip the check of the publisher signature of public keys"
+        )]
+        no_verify: bool,
     },
 
     #[clap(long_about = "List all keys info")]
     List {
+        #[clap(help = "List the keys of `team:<name>`, or the keys of another scope you can read")]
+        namespace: Option<String>,
+
         #[clap(short, long, help = "add scope in public key")]
         public: bool,
     },
 
+    #[clap(
+        long_about = "Re-wrap the private keys saved by older versions with a key derived by HKDF"
+    )]
+    Rewrap {
+        #[clap(help = "Re-wrap the keys of `team:<name>` instead of your scope")]
+        namespace: Option<String>,
+    },
+
+    #[clap(long_about = "Manage who can read a public key")]
+    Acl {
+        #[clap(subcommand)]
+        command: AclCommands,
+    },
+
+    #[clap(long_about = "Manage team namespaces")]
+    Team {
+        #[clap(subcommand)]
+        command: TeamCommands,
+    },
+
+    #[clap(
+        long_about = "Manage local identities, each with its own secret, tokens and repository"
+    )]
+    Profile {
+        #[clap(subcommand)]
+        command: ProfileCommands,
+    },
+
+    #[clap(long_about = "Manage your identity secret")]
+    Identity {
+        #[clap(subcommand)]
+        command: IdentityCommands,
+    },
+
+    #[clap(long_about = "Mint limited tokens for CI jobs and other services")]
+    Token {
+        #[clap(subcommand)]
+        command: TokenCommands,
+    },
+
+    #[clap(long_about = "Sign every request with your identity key")]
+    Signing {
+        #[clap(subcommand)]
+        command: SigningCommands,
+    },
+
+    #[clap(long_about = "Show the audit log of your scope")]
+    Audit {
+        #[clap(short, long, help = "e.g. 30m, 12h, 7d, 2022-06-01 or a RFC 3339 time")]
+        since: Option<String>,
+
+        #[clap(short, long, help = "Only show this action, e.g. create")]
+        action: Option<String>,
+    },
+
+    #[clap(long_about = "Manage the kvs server, run on the server")]
+    Admin {
+        #[clap(subcommand)]
+        command: AdminCommands,
+    },
+
     #[clap(long_about = "Show remote info")]
     Remote,
     #[clap(long_about = "Show local info")]
     Local,
 
-    #[clap(long_about = "Set client config")]
+    #[clap(long_about = "Set client config, or server config for the server keys")]
     Set { key: String, value: String },
 
-    #[clap(long_about = "Get client config")]
+    #[clap(long_about = "Get client config, or server config for the server keys")]
     Get { key: String },
 
     #[clap(long_about = "Remote data dir")]
     Clear,
 
-    #[clap(long_about = "Encrypt content with pub_key,input text, output base64")]
-    En { content: String },
+    #[clap(
+        long_about = "Encrypt content with pub_key, input text or file, output base64. The content is encrypted with a random AES-GCM key wrapped for the recipient"
+    )]
+    En {
+        content: Option<String>,
+
+        #[clap(short, long, help = "Use file content")]
+        file: Option<Option<String>>,
+
+        #[clap(
+            short,
+            long,
+            help = "Encrypt for the scope or base64 public key instead of yourself"
+        )]
+        to: Option<String>,
+
+        #[clap(short, long, help = "Write to the file instead of stdout")]
+        out: Option<String>,
+    },
+
+    #[clap(long_about = "Decrypt content with priv_key, input base64 text or file, output text")]
+    De {
+        content: Option<String>,
+
+        #[clap(short, long, help = "Use file content")]
+        file: Option<Option<String>>,
+
+        #[clap(short, long, help = "Write to the file instead of stdout")]
+        out: Option<String>,
+    },
+
+    #[clap(long_about = "Sign content with priv_key, output a detached signature")]
+    Sign {
+        content: Option<String>,
+
+        #[clap(short, long, help = "Use file content")]
+        file: Option<Option<String>>,
+
+        #[clap(
+            short,
+            long,
+            help = "Write the signature to the file instead of stdout"
+        )]
+        out: Option<String>,
+    },
+
+    #[clap(
+        long_about = "Verify a detached signature of `kvs sign`, the signer is named by its public key or scope"
+    )]
+    Verify {
+        content: Option<String>,
+
+        #[clap(short, long, help = "Use file content")]
+        file: Option<Option<String>>,
+
+        #[clap(short, long, help = "The signature file")]
+        signature: String,
+
+        #[clap(long, help = "The base64 public key of the signer")]
+        pubkey: Option<String>,
 
-    #[clap(long_about = "Decrypt content with priv_key,input base64, output text")]
-    De { content: String },
+        #[clap(long, help = "The scope of the signer")]
+        scope: Option<String>,
+    },
 }
 
 impl Commands {
     pub fn run(&self, repository: &Option<String>) -> KVSResult<()> {
-        let repository = &match repository {
-            Some(repository) => repository.to_string(),
-            None => get_or_create_repository_config()?,
-        };
-        let get_kvs_session = || -> KVSResult<KVSSession> {
-            let stream = TcpStream::connect(repository)?;
-            stream
-                .set_read_timeout(Some(Duration::from_millis(1000)))
-                .expect("fail");
-
-            KVSSession::new(stream)
+        // the selected profile may not exist yet
+        if let Commands::Profile { command } = self {
+            return command.run(repository);
+        }
+        let repository = &match (repository, self) {
+            (Some(repository), _) => repository.to_string(),
+            // the server commands do not depend on the profile
+            (
+                None,
+                Commands::Start { .. }
+                | Commands::Stop
+                | Commands::Restart { .. }
+                | Commands::Clear
+                | Commands::Admin { .. },
+            ) => String::new(),
+            (None, _) => get_or_create_repository_config()?,
         };
 
         match self {
@@ -141,16 +400,15 @@ impl Commands {
             } => {
                 let repository = "0.0.0.0:8888";
                 if *detach {
-                    let args = std::env::args().map(|x| x).collect::<Vec<String>>();
+                    let args = std::env::args().collect::<Vec<String>>();
                     let detach_command_args = args[1..]
-                        .to_vec()
-                        .into_iter()
-                        .filter(|arg| *arg != "-d" && *arg != "--detach")
+                        .iter()
+                        .filter(|arg| arg.as_str() != "-d" && arg.as_str() != "--detach")
                         .map(|item| {
                             if item == "restart" {
                                 "start".to_string()
                             } else {
-                                item
+                                item.clone()
                             }
                         })
                         .collect::<Vec<String>>();
@@ -175,7 +433,7 @@ impl Commands {
                             .spawn();
                     match child {
                         Ok(child) => {
-                            let kvs_pid_file_path = get_or_create_user_config_dir()?.join("pid");
+                            let kvs_pid_file_path = get_or_create_server_dir()?.join("pid");
                             std::fs::write(kvs_pid_file_path, child.id().to_string())?;
                             tracing::info!("kvs started PID: {}", child.id());
                             tracing::info!("The logs saved to ./kvs.log and ./kvs.errors.log");
@@ -186,7 +444,20 @@ impl Commands {
                     return Ok(());
                 }
 
-                let jwt_secret = get_or_create_jwt_secret(*reset_jwt_secret)?;
+                let _lock = at_rest::lock_for_server()?;
+                let context = ServerContext {
+                    jwt_secret: get_or_create_jwt_secret(*reset_jwt_secret)?,
+                    ip_rate_limiter: RateLimiter::new(get_rate_limit_config(
+                        "rate_limit_ip",
+                        DEFAULT_IP_RATE_LIMIT,
+                    )?),
+                    scope_rate_limiter: RateLimiter::new(get_rate_limit_config(
+                        "rate_limit_scope",
+                        DEFAULT_SCOPE_RATE_LIMIT,
+                    )?),
+                    audit_log: AuditLog::open(get_audit_log_path()?)?,
+                    replay_guard: ReplayGuard::default(),
+                };
 
                 let listener = TcpListener::bind(repository)?;
                 tracing::info!("starting with {} successfully!", repository);
@@ -194,23 +465,24 @@ impl Commands {
                     .num_threads(8)
                     .build()
                     .unwrap();
-                for stream in listener.incoming() {
-                    match stream {
-                        Err(e) => {
-                            tracing::error!("{}", e)
-                        }
-                        Ok(stream) => {
-                            let jwt_secret = jwt_secret.clone();
-                            stream.set_read_timeout(Some(Duration::from_millis(1000)))?;
-                            let mut session = KVSSession::new(stream)?;
-                            pool.install(move || service(&mut session, &jwt_secret));
+                let context = &context;
+                pool.in_place_scope(|scope| {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Err(e) => {
+                                tracing::error!("{}", e)
+                            }
+                            Ok(stream) => scope.spawn(move |_| {
+                                serve_connection(stream, context)
+                                    .unwrap_or_else(|error| tracing::error!("{}", error))
+                            }),
                         }
                     }
-                }
+                });
             }
             Commands::Stop => {
-                let kvs_pid_file_path = get_or_create_user_config_dir()?.clone().join("pid");
-                if *&kvs_pid_file_path.exists() {
+                let kvs_pid_file_path = get_or_create_server_dir()?.join("pid");
+                if kvs_pid_file_path.exists() {
                     let pid = std::fs::read_to_string(&kvs_pid_file_path)?;
                     tracing::info!("kvs PID: {}", pid);
                     let sh = Shell::new().unwrap();
@@ -239,51 +511,9 @@ impl Commands {
                 value_type,
                 file,
             } => {
-                let (token, _) = get_or_create_token(repository, false)?;
-                let mut session = get_kvs_session()?;
-
-                let value = match value {
-                    Some(value) => value.as_bytes().to_vec(),
-                    None => match file {
-                        Some(file_path) => match file_path {
-                            Some(file_path) => std::fs::read(file_path)?,
-                            None => {
-                                let mut buf = Vec::<u8>::new();
-                                std::io::stdin().read_to_end(&mut buf)?;
-                                buf
-                            }
-                        },
-                        None => {
-                            return Err(KVSError::LogicError(
-                                "value params and file option can not to None in same time"
-                                    .to_string(),
-                            ))
-                        }
-                    },
-                };
-
-                let size = value.len() as u64;
-                let owner = token.id.clone();
-
-                let rand = if *public == false {
-                    Some((0..32).map(|_| rand::random::<u8>()).collect::<Vec<u8>>())
-                } else {
-                    None
-                };
-                CreateAction {
-                    token: token.clone(),
-                    key: key.to_string(),
-                    value: value.to_vec(),
-                    meta: KeyMeta {
-                        mime: value_type.to_string(),
-                        size,
-                        owner,
-                        name: key.to_string(),
-                        rand,
-                        original_hash: sha256(&value[..]),
-                    },
-                }
-                .request(&mut session)?
+                let value = read_value(value, file)?;
+                let mime = value_mime(key, &value, value_type, file)?;
+                client(repository)?.create(key, &value, *public, &mime)?
             }
             Commands::Update {
                 key,
@@ -292,98 +522,239 @@ impl Commands {
                 value_type,
                 file,
             } => {
-                let (token, _) = get_or_create_token(repository, false)?;
-                let mut session = get_kvs_session()?;
-                let value = match value {
-                    Some(value) => value.as_bytes().to_vec(),
-                    None => match file {
-                        Some(file_path) => match file_path {
-                            Some(file_path) => std::fs::read(file_path)?,
-                            None => {
-                                let mut buf = Vec::<u8>::new();
-                                std::io::stdin().read_to_end(&mut buf)?;
-                                buf
-                            }
-                        },
-                        None => {
-                            return Err(KVSError::LogicError(
-                                "value params and file option can not to None in same time"
-                                    .to_string(),
-                            ))
-                        }
-                    },
-                };
-                let size = *&value.len() as u64;
-                let owner = token.id.clone();
+                let value = read_value(value, file)?;
+                let mime = value_mime(key, &value, value_type, file)?;
+                client(repository)?.update(key, &value, *public, &mime)?
+            }
 
-                let rand = if *public == false {
-                    Some((0..32).map(|_| rand::random::<u8>()).collect::<Vec<u8>>())
-                } else {
-                    None
+            Commands::Read { key, no_verify } => {
+                let client = client(repository)?;
+                let (content, _) = match no_verify {
+                    true => client.read_unverified(key)?,
+                    false => client.read(key)?,
                 };
-                UpdateAction {
-                    token: token.clone(),
-                    key: key.to_string(),
-                    value: value.to_vec(),
-                    meta: KeyMeta {
-                        mime: value_type.to_string(),
-                        size,
-                        owner,
-                        name: key.to_string(),
-                        rand,
-                        original_hash: sha256(&value[..]),
-                    },
+                match String::from_utf8(content) {
+                    Ok(content_str) => println!("{}", content_str),
+                    Err(error) => std::io::stdout().write_all(error.as_bytes())?,
                 }
-                .request(&mut session)?;
             }
-
-            Commands::Read { key } => {
-                let (token, _) = get_or_create_token(&repository, false)?;
-                let mut session = get_kvs_session()?;
-
-                let scope = match key.find(':') {
-                    Some(_) => Some(key.split(":").next().unwrap().to_string()),
-                    None => None,
+            Commands::Delete { key } => client(repository)?.delete(key)?,
+            Commands::Share { key, to } => {
+                client(repository)?.share(key, to)?;
+                tracing::info!("shared {} with {}", key, to);
+            }
+            Commands::Split {
+                key,
+                threshold,
+                to,
+                delete,
+            } => {
+                let client = client(repository)?;
+                if to.len() > u8::MAX as usize {
+                    return Err(KVSError::LogicError(format!(
+                        "At most {} trustees are supported.",
+                        u8::MAX
+                    )));
+                }
+                let names = client
+                    .list(None)?
+                    .into_iter()
+                    .map(|meta| meta.name)
+                    .collect::<Vec<_>>();
+                if names.contains(&ShamirManifest::manifest_key(key)) {
+                    return Err(KVSError::LogicError(format!(
+                        "The key: `{}` is already split.",
+                        key
+                    )));
+                }
+                let (value, _) = client.read(key)?;
+                let mut trustees = vec![];
+                for to in to {
+                    trustees.push(client.resolve_pub_key(to)?);
+                }
+                let (commitment, secret) = Commitment::new(&value);
+                let shares = shamir::split(&secret, *threshold, trustees.len() as u8)?;
+                for (i, (share, trustee)) in shares.iter().zip(trustees.iter()).enumerate() {
+                    let share_key = ShamirManifest::share_key(key, i + 1);
+                    if names.contains(&share_key) {
+                        client.delete(&share_key)?;
+                    }
+                    create_shared_key(
+                        &client,
+                        &share_key,
+                        Secret::hybrid_encrypt_with_pub_key_bits(&trustee.1, share)?,
+                        std::slice::from_ref(trustee),
+                    )?;
+                }
+                let manifest = ShamirManifest {
+                    threshold: *threshold,
+                    commitment,
+                    trustees: trustees.iter().map(|(scope, _)| scope.clone()).collect(),
+                };
+                create_shared_key(
+                    &client,
+                    &ShamirManifest::manifest_key(key),
+                    manifest.to_string().into_bytes(),
+                    &trustees,
+                )?;
+                tracing::info!(
+                    "split {} into {} shares, {} of them rebuild it",
+                    key,
+                    shares.len(),
+                    threshold
+                );
+                if *delete {
+                    client.delete(key)?;
+                    tracing::info!("deleted {}", key);
+                }
+            }
+            Commands::Combine {
+                key,
+                release_to,
+                out,
+            } => {
+                let client = client(repository)?;
+                let token = client.token()?;
+                let secret = client.identity();
+                let me = token.get_addr();
+                let (owner, key) = match KeyAddress::parse(key) {
+                    KeyAddress::Own(key) => (me.clone(), key),
+                    KeyAddress::Scope(scope, key) => (scope, key),
+                    KeyAddress::Team(..) => {
+                        return Err(KVSError::LogicError(format!(
+                            "Can not combine the key of a team: {}",
+                            key
+                        )))
+                    }
                 };
-                let key = match key.split(":").nth(1) {
-                    Some(key) => key.to_string(),
-                    None => key.to_string(),
+                let read = |scope: &str, key: String| {
+                    client
+                        .read(&format!("{}:{}", scope, key))
+                        .map(|(content, _)| content)
                 };
-                let scope = scope.clone();
-                let reply = ReadAction {
-                    token: token.clone(),
-                    key: key.to_string(),
-                    scope,
+                // the owner may have rotated since the split
+                let owner = client.current_scope(&owner)?;
+                let manifest = read(&owner, ShamirManifest::manifest_key(&key))?;
+                let manifest = String::from_utf8_lossy(&manifest).parse::<ShamirManifest>()?;
+                match release_to {
+                    Some(to) => {
+                        let index = match manifest.trustees.iter().position(|scope| *scope == me) {
+                            Some(position) => position + 1,
+                            None => {
+                                return Err(KVSError::LogicError(format!(
+                                    "{} is not a trustee of the key: `{}`.",
+                                    me, key
+                                )))
+                            }
+                        };
+                        let share = Secret::hybrid_decrypt_with_priv_key_bits(
+                            &secret.priv_key_bits,
+                            &read(&owner, ShamirManifest::share_key(&key, index))?,
+                        )?;
+                        let recipient = client.resolve_pub_key(to)?;
+                        let scope = &recipient.0;
+                        // the manifest is only readable by the owner and the trustees
+                        if *scope != owner && !manifest.trustees.contains(scope) {
+                            return Err(KVSError::LogicError(format!(
+                                "{} can not read the manifest of the key: `{}`, release your share to a trustee or the owner.",
+                                scope, key
+                            )));
+                        }
+                        let released_key = manifest.released_share_key(&key, index);
+                        // replace an earlier release
+                        let released = client
+                            .list(None)?
+                            .iter()
+                            .any(|meta| meta.name == released_key);
+                        if released {
+                            client.delete(&released_key)?;
+                        }
+                        create_shared_key(
+                            &client,
+                            &released_key,
+                            Secret::hybrid_encrypt_with_pub_key_bits(&recipient.1, &share)?,
+                            std::slice::from_ref(&recipient),
+                        )?;
+                        tracing::info!("released share {} of {} to {}", index, key, scope);
+                    }
+                    None => {
+                        let mut shares = vec![];
+                        for (i, trustee) in manifest.trustees.iter().enumerate() {
+                            if shares.len() == manifest.threshold as usize {
+                                break;
+                            }
+                            let reply = match *trustee == me {
+                                true => read(&owner, ShamirManifest::share_key(&key, i + 1)),
+                                false => read(trustee, manifest.released_share_key(&key, i + 1)),
+                            };
+                            let share = reply.and_then(|reply| {
+                                Secret::hybrid_decrypt_with_priv_key_bits(
+                                    &secret.priv_key_bits,
+                                    &reply,
+                                )
+                            });
+                            match share {
+                                Ok(share) => shares.push(share),
+                                Err(error) => {
+                                    tracing::debug!("share {} of {}: {:?}", i + 1, trustee, error)
+                                }
+                            }
+                        }
+                        if shares.len() < manifest.threshold as usize {
+                            return Err(KVSError::LogicError(format!(
+                                "{} of {} shares of the key: `{}` are readable, the trustees release theirs with `kvs combine {}:{} --release-to {}`.",
+                                shares.len(),
+                                manifest.threshold,
+                                key,
+                                owner,
+                                key,
+                                me
+                            )));
+                        }
+                        let value = match manifest.commitment.open(&shamir::combine(&shares)?) {
+                            Some(value) => value,
+                            None => {
+                                return Err(KVSError::LogicError(format!(
+                                    "The shares of the key: `{}` rebuild a different value.",
+                                    key
+                                )))
+                            }
+                        };
+                        match out {
+                            Some(out) => std::fs::write(out, value)?,
+                            None => match std::str::from_utf8(&value) {
+                                Ok(value) => println!("{}", value),
+                                Err(_) => std::io::stdout().write_all(&value)?,
+                            },
+                        }
+                    }
                 }
-                .request(&mut session)?;
-                let content = reply.content();
-                let content_str = String::from_utf8(content.to_vec()).unwrap();
-                println!("{}", content_str);
             }
-            Commands::Delete { key } => {
-                let (token, _) = get_or_create_token(&repository, false)?;
-                let mut session = get_kvs_session()?;
-                DeleteAction {
-                    token: token.clone(),
+            Commands::Unshare { key, to } => {
+                let client = client(repository)?;
+                let scope = match is_scope_addr(to) {
+                    true => to.to_string(),
+                    false => client.resolve_pub_key(to)?.0,
+                };
+                client.request(&UnshareAction {
+                    token: client.token()?,
                     key: key.to_string(),
-                }
-                .request(&mut session)?;
+                    scope: scope.clone(),
+                })?;
+                tracing::info!("unshared {} with {}", key, scope);
             }
             Commands::Set { key, value } => {
-                let user_config_kv_dir = get_or_create_user_config_kv_dir()?;
-                let key_config_file_path = user_config_kv_dir.join(key);
+                let key_config_file_path = get_config_file_path(key)?;
                 std::fs::write(key_config_file_path, value.as_bytes())?
             }
             Commands::Get { key } => {
-                let user_config_kv_dir = get_or_create_user_config_kv_dir()?;
-                let key_config_file_path = user_config_kv_dir.join(key);
+                let key_config_file_path = get_config_file_path(key)?;
                 if key_config_file_path.exists() {
                     println!("{}", std::fs::read_to_string(key_config_file_path)?)
                 }
             }
             Commands::Remote => {
-                let mut session = get_kvs_session()?;
-                let version = RemoteVersionAction.request(&mut session)?;
+                let version = request(repository, &RemoteVersionAction)?;
                 println!("{}", version);
             }
             Commands::Local => {
@@ -393,116 +764,1052 @@ impl Commands {
                 println!("scope: {}", scope);
                 println!("pub key: {}", pub_key);
             }
-            Commands::Sync { path, public } => {
-                let (token, _) = get_or_create_token(&repository, false)?;
-                let all_files_meta = LocalFileMeta::get_all_files_meta(path)?;
-                tracing::info!("analysis remote files");
-                let mut session = get_kvs_session()?;
-                let remote_key_meta_list = ListAction { token }.request(&mut session)?;
-                let remote_key_meta_mapper = HashMap::<String, &KeyMeta>::from_iter(
-                    remote_key_meta_list
-                        .iter()
-                        .map(|meta| (meta.name.clone(), meta)),
+            Commands::Sync {
+                path,
+                public,
+                jobs,
+                prefix,
+                delete,
+                dry_run,
+                include,
+                exclude,
+                gitignore,
+            } => {
+                let options = SyncOptions {
+                    prefix: prefix.clone(),
+                    public: *public,
+                    jobs: *jobs,
+                    delete: *delete,
+                    dry_run: *dry_run,
+                    filter: FileFilter {
+                        include: include.clone(),
+                        exclude: exclude.clone(),
+                        gitignore: *gitignore,
+                    },
+                };
+                let summary = sync_dir(&client(repository)?, path, &options)?;
+                if *dry_run {
+                    for name in summary.created.iter() {
+                        println!("create\t{}", name);
+                    }
+                    for name in summary.updated.iter() {
+                        println!("update\t{}", name);
+                    }
+                    for name in summary.deleted.iter() {
+                        println!("delete\t{}", name);
+                    }
+                }
+                tracing::info!(
+                    "created {} keys, updated {} keys, deleted {} keys, {} unchanged",
+                    summary.created.len(),
+                    summary.updated.len(),
+                    summary.deleted.len(),
+                    summary.unchanged
                 );
-                let need_create_keys = all_files_meta
-                    .iter()
-                    .filter(|meta| !remote_key_meta_mapper.contains_key(&meta.name))
-                    .collect::<Vec<_>>();
-
-                if need_create_keys.len() > 0 {
-                    tracing::info!("need create keys {}", need_create_keys.len());
-
-                    need_create_keys
-                        .iter()
-                        .progress_count(need_create_keys.len() as u64)
-                        .for_each(|meta| {
-                            Commands::Create {
-                                key: meta.name.to_string(),
-                                value: None,
-                                file: Some(Some(meta.path.to_string())),
-                                public: *public,
-                                value_type: "bin".to_string(),
-                            }
-                            .run(&Some(repository.clone()))
-                            .unwrap_or_else(|error| tracing::error!("{:?}", error));
-                        });
-                    tracing::info!("created {} keys successfully", need_create_keys.len());
+                for (name, error) in summary.failed.iter() {
+                    tracing::error!("{}: {}", name, error);
                 }
-                let need_update_keys = all_files_meta
-                    .iter()
-                    .filter(|meta| match remote_key_meta_mapper.get(&meta.name) {
-                        Some(target) => {
-                            let no_same_hash = target.original_hash != meta.original_hash;
-                            let no_same_public = target.rand.is_none() != *public;
-                            no_same_hash || no_same_public
-                        }
-                        None => false,
-                    })
-                    .collect::<Vec<_>>();
-                if need_update_keys.len() > 0 {
-                    tracing::info!("need update files {}", need_update_keys.len());
-                    need_update_keys
-                        .iter()
-                        .progress_count(need_update_keys.len() as u64)
-                        .for_each(|meta| {
-                            Commands::Update {
-                                key: meta.name.to_string(),
-                                value: None,
-                                file: Some(Some(meta.path.to_string())),
-                                public: *public,
-                                value_type: "bin".to_string(),
-                            }
-                            .run(&Some(repository.clone()))
-                            .unwrap_or_else(|error| tracing::error!("{:?}", error));
-                        });
-                    tracing::info!("upload {} keys successfully", need_update_keys.len());
+                if !summary.failed.is_empty() {
+                    return Err(KVSError::LogicError(format!(
+                        "{} files failed to sync",
+                        summary.failed.len()
+                    )));
                 }
                 tracing::info!("sync finish")
             }
-            Commands::List { public } => {
-                let (token, _) = get_or_create_token(&repository, false)?;
-                let secret = get_or_create_secret()?;
-                let scope = to_addr(&secret.pub_key_bits);
-                let mut session = get_kvs_session()?;
-                let key_meta_list = ListAction { token }.request(&mut session)?;
+            Commands::Pull {
+                source,
+                path,
+                jobs,
+                no_verify,
+            } => {
+                let summary = pull_dir(&client(repository)?, source, path, *jobs, *no_verify)?;
+                tracing::info!(
+                    "created {} files, updated {} files, {} unchanged",
+                    summary.created.len(),
+                    summary.updated.len(),
+                    summary.unchanged
+                );
+                for (name, error) in summary.failed.iter() {
+                    tracing::error!("{}: {}", name, error);
+                }
+                if !summary.failed.is_empty() {
+                    return Err(KVSError::LogicError(format!(
+                        "{} keys failed to pull",
+                        summary.failed.len()
+                    )));
+                }
+                tracing::info!("pull finish")
+            }
+            Commands::List { namespace, public } => {
+                let client = client(repository)?;
+                let key_meta_list = client.list(namespace.as_deref())?;
+                let scope = match namespace {
+                    Some(namespace) => namespace.clone(),
+                    None => to_addr(&client.identity().pub_key_bits),
+                };
                 key_meta_list.iter().for_each(|meta| {
                     println!(
-                        "{} {}\t{}",
+                        "{} {} {}\t{}",
                         if meta.rand.is_none() {
                             "public"
                         } else {
                             "private"
                         },
                         meta.size,
+                        meta.mime,
                         if meta.rand.is_none() && *public {
-                            format!("{}:{}", scope.to_string(), meta.name).to_string()
+                            format!("{}:{}", scope, meta.name).to_string()
                         } else {
                             meta.name.to_string()
                         },
                     );
                 });
             }
+            Commands::Audit { since, action } => {
+                let client = client(repository)?;
+                let since = since.as_deref().map(parse_since).transpose()?;
+                let records = client.request(&AuditAction {
+                    token: client.token()?,
+                    since,
+                    action: action.clone(),
+                })?;
+                print_audit_records(&records);
+            }
+            Commands::Admin { command } => command.run()?,
+            Commands::Profile { .. } => unreachable!(),
+            Commands::Identity { command } => command.run(repository)?,
+            Commands::Token { command } => command.run(repository)?,
+            Commands::Signing { command } => command.run(repository)?,
+            Commands::Rewrap { namespace } => {
+                let client = client(repository)?;
+                let token = client.token()?;
+                let secret = client.identity();
+                let team = match namespace {
+                    Some(namespace) => match namespace.strip_prefix("team:") {
+                        Some(team) => Some(team.to_string()),
+                        None => {
+                            return Err(KVSError::LogicError(format!(
+                                "Illegal namespace: {}",
+                                namespace
+                            )))
+                        }
+                    },
+                    None => None,
+                };
+                let team_key = match &team {
+                    Some(team) => Some(client.fetch_team(&token, team)?.1),
+                    None => None,
+                };
+                let key_meta_list = client.list(namespace.as_deref())?;
+                let mut rands = vec![];
+                for meta in key_meta_list {
+                    if meta.envelope != Envelope::Legacy {
+                        continue;
+                    }
+                    let (from, rand) = match (&meta.rand, &team_key) {
+                        (None, _) => continue,
+                        (Some(from), Some(team_key)) => {
+                            (from, meta.unwrap_team_rand(team_key)?.unwrap())
+                        }
+                        (Some(from), None) => {
+                            (from, meta.unwrap_rand(secret, &token.get_addr())?.unwrap())
+                        }
+                    };
+                    rands.push(RewrappedRand {
+                        key: meta.name.clone(),
+                        from: from.clone(),
+                        rand: KeyMeta::wrap_rand(
+                            team_key.as_ref().unwrap_or(&secret.priv_key_bits),
+                            &rand,
+                        )?,
+                    });
+                }
+                let count = match rands.is_empty() {
+                    true => 0,
+                    false => client.request(&RewrapAction { token, team, rands })?,
+                };
+                tracing::info!("rewrapped {} keys", count);
+            }
+            Commands::Acl { command } => command.run(repository)?,
+            Commands::Team { command } => command.run(repository)?,
             Commands::Clear => {
                 let data_dir = get_or_create_data_dir()?;
                 remove_dir_all::remove_dir_all(data_dir)?;
             }
-            Commands::En { content } => {
-                if content == "pplove" {
+            Commands::En {
+                content,
+                file,
+                to,
+                out,
+            } => {
+                if content.as_deref() == Some("pplove") {
                     crate::letter::print_letter();
                     return Ok(());
                 }
+                let pub_key = match to {
+                    Some(to) if is_scope_addr(to) => client(repository)?.resolve_pub_key(to)?.1,
+                    Some(to) => match base64::decode(to) {
+                        Ok(pub_key) if Secret::is_pub_key(&pub_key) => pub_key,
+                        _ => {
+                            return Err(KVSError::LogicError(format!(
+                                "Illegal scope or public key: {}",
+                                to
+                            )))
+                        }
+                    },
+                    None => get_or_create_secret()?.pub_key_bits,
+                };
+                let content = read_content(content, file)?;
+                let result = Secret::hybrid_encrypt_with_pub_key_bits(&pub_key, &content)?;
+                match out {
+                    Some(out) => std::fs::write(out, base64::encode(result))?,
+                    None => println!("{}", base64::encode(result)),
+                }
+            }
+            Commands::De { content, file, out } => {
                 let secret = get_or_create_secret()?;
+                let content = String::from_utf8_lossy(&read_content(content, file)?).to_string();
+                let data = base64::decode(content.trim())
+                    .map_err(|_| KVSError::LogicError("Illegal base64 content".to_string()))?;
                 let result =
-                    Secret::encrypt_with_pub_key_bits(&secret.pub_key_bits, content.as_bytes());
-                println!("{}", base64::encode(result));
+                    Secret::hybrid_decrypt_with_priv_key_bits(&secret.priv_key_bits, &data)?;
+                match out {
+                    Some(out) => std::fs::write(out, result)?,
+                    None => match std::str::from_utf8(&result) {
+                        Ok(result) => println!("{}", result),
+                        Err(_) => std::io::stdout().write_all(&result)?,
+                    },
+                }
             }
-            Commands::De { content } => {
+            Commands::Sign { content, file, out } => {
                 let secret = get_or_create_secret()?;
-                let data = base64::decode(content).unwrap();
-                let result = Secret::decrypt_width_priv_key_bits(&secret.priv_key_bits, &data)?;
-                println!("{}", String::from_utf8(result).unwrap());
+                let content = read_content(content, file)?;
+                let signature = DetachedSignature::sign(&secret, &content)?;
+                match out {
+                    Some(out) => std::fs::write(out, signature.to_string())?,
+                    None => print!("{}", signature),
+                }
+            }
+            Commands::Verify {
+                content,
+                file,
+                signature,
+                pubkey,
+                scope,
+            } => {
+                let signature = std::fs::read_to_string(signature)?.parse::<DetachedSignature>()?;
+                let signer_matches = match (pubkey, scope) {
+                    (Some(pubkey), _) => match base64::decode(pubkey) {
+                        Ok(pubkey) if pubkey.len() == 32 => {
+                            Secret::from_ed25519_pub_key(&pubkey).ok()
+                                == Some(signature.pub_key.clone())
+                        }
+                        Ok(pubkey) => pubkey == signature.pub_key,
+                        Err(_) => false,
+                    },
+                    (None, Some(scope)) => *scope == signature.scope,
+                    (None, None) => {
+                        return Err(KVSError::LogicError(
+                            "Name the signer with --pubkey or --scope.".to_string(),
+                        ))
+                    }
+                };
+                if !signer_matches {
+                    return Err(KVSError::LogicError(format!(
+                        "The signature is made by {}",
+                        signature.scope
+                    )));
+                }
+                signature.verify(&read_content(content, file)?)?;
+                println!("Good signature from {}", signature.scope);
+            }
+        };
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum TeamCommands {
+    #[clap(long_about = "Create a team owned by you")]
+    Create { name: String },
+
+    #[clap(long_about = "Show the members of a team")]
+    Show { name: String },
+
+    #[clap(long_about = "Add a member to a team or change the role of a member")]
+    Add {
+        name: String,
+
+        #[clap(help = "The scope or base64 public key of the member")]
+        member: String,
+
+        #[clap(
+            short,
+            long,
+            help = "owner, writer or reader",
+            default_value = "writer"
+        )]
+        role: String,
+    },
+
+    #[clap(long_about = "Remove a member from a team and rotate the team key")]
+    Remove { name: String, member: String },
+}
+
+impl TeamCommands {
+    pub fn run(&self, repository: &str) -> KVSResult<()> {
+        let client = client(repository)?;
+        let token = client.token()?;
+        match self {
+            TeamCommands::Create { name } => {
+                let team_key = (0..32).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+                client.request(&TeamCreateAction {
+                    token,
+                    name: name.to_string(),
+                    key: Secret::encrypt_with_pub_key_bits(
+                        &client.identity().pub_key_bits,
+                        &team_key,
+                    )?,
+                })?;
+                tracing::info!("created team {}", name);
+            }
+            TeamCommands::Show { name } => {
+                let team = client.request(&TeamInfoAction {
+                    token,
+                    name: name.to_string(),
+                })?;
+                println!("team: {} (key version {})", team.name, team.key_version);
+                team.members.iter().for_each(|member| {
+                    println!("{}\t{}", member.role, member.scope);
+                });
+            }
+            TeamCommands::Add { name, member, role } => {
+                let role = role.parse::<TeamRole>()?;
+                let (scope, _) = client.resolve_pub_key(member)?;
+                let team = client.request(&TeamInfoAction {
+                    token: token.clone(),
+                    name: name.to_string(),
+                })?;
+                let mut members = team.members.clone();
+                match members.iter_mut().find(|member| member.scope == scope) {
+                    Some(member) => {
+                        member.role = role;
+                        client.request(&TeamUpdateAction {
+                            token,
+                            name: name.to_string(),
+                            members,
+                            key_version: team.key_version,
+                            rands: vec![],
+                        })?;
+                    }
+                    None => {
+                        members.push(TeamMember {
+                            scope: scope.clone(),
+                            role,
+                            key: vec![],
+                        });
+                        client.rotate_team_key(&team, members)?;
+                    }
+                }
+                tracing::info!("{} is the {} of team {}", scope, role, name);
+            }
+            TeamCommands::Remove { name, member } => {
+                let scope = if is_scope_addr(member) {
+                    member.to_string()
+                } else {
+                    client.resolve_pub_key(member)?.0
+                };
+                let team = client.request(&TeamInfoAction {
+                    token,
+                    name: name.to_string(),
+                })?;
+                if team.member(&scope).is_none() {
+                    return Err(KVSError::LogicError(format!(
+                        "{} is not a member of team {}",
+                        scope, name
+                    )));
+                }
+                let members = team
+                    .members
+                    .iter()
+                    .filter(|member| member.scope != scope)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                client.rotate_team_key(&team, members)?;
+                tracing::info!("removed {} from team {}", scope, name);
             }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum AclCommands {
+    #[clap(long_about = "Show the access list of a public key")]
+    Get { key: String },
+
+    #[clap(long_about = "Replace the access list of a public key, no entries removes it")]
+    Set {
+        key: String,
+        #[clap(help = "Scopes or `team:<name>`")]
+        entries: Vec<String>,
+    },
+
+    #[clap(long_about = "Allow scopes or teams to read a public key")]
+    Add {
+        key: String,
+        #[clap(help = "Scopes or `team:<name>`", required = true)]
+        entries: Vec<String>,
+    },
+
+    #[clap(long_about = "Disallow scopes or teams to read a public key")]
+    Remove {
+        key: String,
+        #[clap(help = "Scopes or `team:<name>`", required = true)]
+        entries: Vec<String>,
+    },
+}
+
+impl AclCommands {
+    pub fn run(&self, repository: &str) -> KVSResult<()> {
+        let client = client(repository)?;
+        let (key, op) = match self {
+            AclCommands::Get { key } => (key, AclOp::Get),
+            AclCommands::Set { key, entries } => (key, AclOp::Set(entries.clone())),
+            AclCommands::Add { key, entries } => (key, AclOp::Add(entries.clone())),
+            AclCommands::Remove { key, entries } => (key, AclOp::Remove(entries.clone())),
         };
+        let acl = client.request(&AclAction {
+            token: client.token()?,
+            key: key.to_string(),
+            op,
+        })?;
+        match acl {
+            Some(acl) if acl.is_empty() => println!("owner only"),
+            Some(acl) => acl.iter().for_each(|entry| println!("{}", entry)),
+            None => println!("everyone"),
+        }
         Ok(())
     }
 }
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum IdentityCommands {
+    #[clap(
+        long_about = "Set, change or remove the passphrase encrypting your secret, an empty passphrase stores it in plain"
+    )]
+    Passwd,
+
+    #[clap(
+        long_about = "Hold the passphrase and hand it to kvs commands through a unix socket until stopped"
+    )]
+    Agent,
+
+    #[clap(
+        long_about = "Create a new identity, ed25519 signs with Ed25519 and wraps keys with X25519, rsa is the 1024 bit RSA of older clients"
+    )]
+    New {
+        #[clap(long = "type", default_value = "ed25519", help = "ed25519 or rsa")]
+        key_type: KeyType,
+
+        #[clap(short, long, help = "Replace the current identity")]
+        force: bool,
+    },
+
+    #[clap(long_about = "Print your private key as a PKCS#8 PEM document")]
+    Export {
+        #[clap(short, long, help = "Write to the file instead of stdout")]
+        out: Option<String>,
+    },
+
+    #[clap(long_about = "Use the private key of a PKCS#8 or PKCS#1 PEM document as your identity")]
+    Import {
+        #[clap(help = "The PEM file, stdin when not given")]
+        file: Option<String>,
+
+        #[clap(short, long, help = "Replace the current identity")]
+        force: bool,
+    },
+
+    #[clap(
+        long_about = "Replace your identity with a new key pair, move your keys to the new scope and leave a signed forwarding record at the old one"
+    )]
+    Rotate {
+        #[clap(long = "type", default_value = "ed25519", help = "ed25519 or rsa")]
+        key_type: KeyType,
+    },
+
+    #[clap(long_about = "Show and verify the forwarding record of a rotated scope")]
+    Forward { scope: String },
+}
+
+impl IdentityCommands {
+    pub fn run(&self, repository: &str) -> KVSResult<()> {
+        let user_config_dir_path = get_or_create_user_config_dir()?;
+        let secret_file_path = user_config_dir_path.join("secret");
+        let tokens_dir_path = user_config_dir_path.join("tokens");
+        match self {
+            IdentityCommands::Passwd => {
+                let secret = get_or_create_secret()?;
+                let passphrase = rpassword::prompt_password("New passphrase: ")?;
+                if passphrase != rpassword::prompt_password("Repeat the new passphrase: ")? {
+                    return Err(KVSError::LogicError(
+                        "The passphrases do not match.".to_string(),
+                    ));
+                }
+                let content = match passphrase.is_empty() {
+                    true => secret.to_string(),
+                    false => secret.to_encrypted_string(&passphrase)?,
+                };
+                write_secret_file(&secret_file_path, &content)?;
+                match passphrase.is_empty() {
+                    true => tracing::info!("the secret is stored in plain"),
+                    false => tracing::info!("the secret is encrypted with the new passphrase"),
+                }
+            }
+            #[cfg(unix)]
+            IdentityCommands::Agent => {
+                use std::io::Write;
+                use std::os::unix::{
+                    fs::{DirBuilderExt, PermissionsExt},
+                    net::UnixListener,
+                };
+                let content = std::fs::read_to_string(&secret_file_path)?;
+                if !Secret::is_encrypted(&content) {
+                    return Err(KVSError::LogicError(
+                        "The secret has no passphrase, run `kvs identity passwd`.".to_string(),
+                    ));
+                }
+                let passphrase = rpassword::prompt_password("Passphrase of your identity: ")?;
+                Secret::from_encrypted_string(&content, &passphrase)?;
+                let agent_socket_path = get_agent_socket_path()?;
+                if agent_socket_path.exists() {
+                    std::fs::remove_file(&agent_socket_path)?;
+                }
+                // bind in a private directory, the socket is only reachable
+                // at its path once it is 0600
+                let bind_dir_path = agent_socket_path.with_extension("binding");
+                if bind_dir_path.exists() {
+                    remove_dir_all::remove_dir_all(&bind_dir_path)?;
+                }
+                std::fs::DirBuilder::new()
+                    .mode(0o700)
+                    .create(&bind_dir_path)?;
+                let bind_path = bind_dir_path.join("agent.sock");
+                let listener = UnixListener::bind(&bind_path)?;
+                std::fs::set_permissions(&bind_path, std::fs::Permissions::from_mode(0o600))?;
+                std::fs::rename(&bind_path, &agent_socket_path)?;
+                std::fs::remove_dir(&bind_dir_path)?;
+                tracing::info!("agent listening on {}", agent_socket_path.display());
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(mut stream) => stream
+                            .write_all(passphrase.as_bytes())
+                            .unwrap_or_else(|error| tracing::error!("{}", error)),
+                        Err(error) => tracing::error!("{}", error),
+                    }
+                }
+            }
+            IdentityCommands::Export { out } => {
+                let pem = get_or_create_secret()?.to_pkcs8_pem()?;
+                match out {
+                    Some(out) => {
+                        std::fs::write(out, pem)?;
+                        #[cfg(unix)]
+                        {
+                            use std::os::unix::fs::PermissionsExt;
+                            std::fs::set_permissions(out, std::fs::Permissions::from_mode(0o600))?;
+                        }
+                    }
+                    None => print!("{}", pem),
+                }
+            }
+            IdentityCommands::New { key_type, force } => {
+                if secret_file_path.exists() && !*force {
+                    return Err(KVSError::LogicError(
+                        "An identity exists, use --force to replace it.".to_string(),
+                    ));
+                }
+                let secret = Secret::new(*key_type);
+                let content = secret_file_content(&secret_file_path, &secret, new_passphrase)?;
+                write_secret_file(&secret_file_path, &content)?;
+                if tokens_dir_path.exists() {
+                    remove_dir_all::remove_dir_all(&tokens_dir_path)?;
+                }
+                tracing::info!(
+                    "created {} identity {}",
+                    key_type,
+                    to_addr(&secret.pub_key_bits)
+                );
+            }
+            IdentityCommands::Import { file, force } => {
+                if secret_file_path.exists() && !*force {
+                    return Err(KVSError::LogicError(
+                        "An identity exists, use --force to replace it.".to_string(),
+                    ));
+                }
+                let pem = match file {
+                    Some(file) => std::fs::read_to_string(file)?,
+                    None => {
+                        let mut pem = String::new();
+                        std::io::stdin().read_to_string(&mut pem)?;
+                        pem
+                    }
+                };
+                let secret = Secret::from_pem(&pem)?;
+                let content = secret_file_content(&secret_file_path, &secret, new_passphrase)?;
+                write_secret_file(&secret_file_path, &content)?;
+                if tokens_dir_path.exists() {
+                    remove_dir_all::remove_dir_all(&tokens_dir_path)?;
+                }
+                tracing::info!("imported identity {}", to_addr(&secret.pub_key_bits));
+            }
+            IdentityCommands::Rotate { key_type } => {
+                let client = client(repository)?;
+                let token = client.token()?;
+                if token.claims.is_some() {
+                    return Err(KVSError::LogicError(
+                        "Only a full token can rotate the identity.".to_string(),
+                    ));
+                }
+                let new_secret = Secret::new(*key_type);
+                let new_content =
+                    secret_file_content(&secret_file_path, &new_secret, new_passphrase)?;
+                // keep the new identity on disk until the remote moved the keys
+                let rotating_file_path = user_config_dir_path.join("secret.rotating");
+                write_secret_file(&rotating_file_path, &new_content)?;
+
+                client.rotate(&new_secret)?;
+
+                std::fs::rename(&secret_file_path, user_config_dir_path.join("secret.old"))?;
+                std::fs::rename(&rotating_file_path, &secret_file_path)?;
+                if tokens_dir_path.exists() {
+                    remove_dir_all::remove_dir_all(&tokens_dir_path)?;
+                }
+                tracing::info!(
+                    "rotated {} to {}, the old secret is kept in {}",
+                    token.get_addr(),
+                    to_addr(&new_secret.pub_key_bits),
+                    user_config_dir_path.join("secret.old").display()
+                );
+            }
+            IdentityCommands::Forward { scope } => {
+                let record = request(
+                    repository,
+                    &ForwardAction {
+                        scope: scope.to_string(),
+                    },
+                )?
+                .ok_or_else(|| {
+                    KVSError::LogicError(format!("The scope: `{}` is not rotated.", scope))
+                })?;
+                if record.from != *scope || !record.verify() {
+                    return Err(KVSError::LogicError(format!(
+                        "The forwarding record of {} is not signed by its key.",
+                        scope
+                    )));
+                }
+                let time =
+                    chrono::TimeZone::timestamp_millis_opt(&chrono::Local, record.time_stamp)
+                        .single()
+                        .map(|time| time.to_rfc3339())
+                        .unwrap_or_else(|| record.time_stamp.to_string());
+                println!("{} moved to {} at {}", record.from, record.to, time);
+                println!("pub key: {}", base64::encode(&record.to_pub_key));
+            }
+            #[cfg(not(unix))]
+            IdentityCommands::Agent => {
+                return Err(KVSError::LogicError(
+                    "The agent needs unix sockets.".to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum ProfileCommands {
+    #[clap(long_about = "List the profiles, the selected one is marked with *")]
+    List,
+
+    #[clap(long_about = "Create a profile with a new identity, -r sets its default repository")]
+    Create {
+        name: String,
+
+        #[clap(long = "type", default_value = "ed25519", help = "ed25519 or rsa")]
+        key_type: KeyType,
+    },
+
+    #[clap(long_about = "Select the profile for the following commands")]
+    Use { name: String },
+
+    #[clap(long_about = "Delete a profile with its identity and tokens")]
+    Delete { name: String },
+}
+
+impl ProfileCommands {
+    pub fn run(&self, repository: &Option<String>) -> KVSResult<()> {
+        match self {
+            ProfileCommands::List => {
+                let selected = get_profile()?;
+                list_profiles()?.iter().for_each(|name| {
+                    println!("{} {}", if *name == selected { "*" } else { " " }, name);
+                });
+            }
+            ProfileCommands::Create { name, key_type } => {
+                let profile_dir_path = get_profile_dir(name)?;
+                if profile_dir_path.exists() {
+                    return Err(KVSError::LogicError(format!(
+                        "The profile: `{}` arealy exists.",
+                        name
+                    )));
+                }
+                std::fs::create_dir_all(profile_dir_path.join("config"))?;
+                write_secret_file(
+                    &profile_dir_path.join("secret"),
+                    &Secret::new(*key_type).to_string(),
+                )?;
+                if let Some(repository) = repository {
+                    std::fs::write(
+                        profile_dir_path.join("config").join("repository"),
+                        repository,
+                    )?;
+                }
+                tracing::info!("created profile {}", name);
+            }
+            ProfileCommands::Use { name } => {
+                if !get_profile_dir(name)?.exists() {
+                    return Err(KVSError::LogicError(format!(
+                        "The profile: `{}` is not exists.",
+                        name
+                    )));
+                }
+                std::fs::write(get_or_create_kvs_home_dir()?.join("profile"), name)?;
+                tracing::info!("using profile {}", name);
+            }
+            ProfileCommands::Delete { name } => {
+                if name == DEFAULT_PROFILE {
+                    return Err(KVSError::LogicError(
+                        "The default profile can not be deleted.".to_string(),
+                    ));
+                }
+                let profile_dir_path = get_profile_dir(name)?;
+                if !profile_dir_path.exists() {
+                    return Err(KVSError::LogicError(format!(
+                        "The profile: `{}` is not exists.",
+                        name
+                    )));
+                }
+                remove_dir_all::remove_dir_all(profile_dir_path)?;
+                let profile_file_path = get_or_create_kvs_home_dir()?.join("profile");
+                if profile_file_path.exists()
+                    && std::fs::read_to_string(&profile_file_path)?.trim() == name
+                {
+                    std::fs::remove_file(profile_file_path)?;
+                }
+                tracing::info!("deleted profile {}", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum TokenCommands {
+    #[clap(
+        long_about = "Create a derived token, use it by setting the KVS_TOKEN environment variable"
+    )]
+    Create {
+        #[clap(long, help = "Only allow read, list, pub_key and audit")]
+        read_only: bool,
+
+        #[clap(short, long, help = "Allow this action, e.g. create, repeatable")]
+        action: Vec<String>,
+
+        #[clap(
+            short,
+            long,
+            help = "Only allow keys starting with this prefix, repeatable"
+        )]
+        prefix: Vec<String>,
+
+        #[clap(short, long, help = "Expire after e.g. 30m, 12h or 7d")]
+        ttl: Option<String>,
+    },
+}
+
+impl TokenCommands {
+    pub fn run(&self, repository: &str) -> KVSResult<()> {
+        match self {
+            TokenCommands::Create {
+                read_only,
+                action,
+                prefix,
+                ttl,
+            } => {
+                let mut actions = action.clone();
+                if *read_only {
+                    actions.extend(READ_ONLY_ACTIONS.iter().map(|action| action.to_string()));
+                }
+                let expires_at = match ttl {
+                    Some(ttl) => Some(
+                        chrono::Local::now()
+                            .timestamp_millis()
+                            .checked_add(parse_duration(ttl)?)
+                            .ok_or_else(|| {
+                                KVSError::LogicError(format!("Illegal duration: {}", ttl))
+                            })?,
+                    ),
+                    None => None,
+                };
+                let claims = TokenClaims {
+                    actions: if actions.is_empty() {
+                        None
+                    } else {
+                        Some(actions)
+                    },
+                    prefixes: prefix.clone(),
+                    expires_at,
+                };
+                claims.check_actions()?;
+                let client = client(repository)?;
+                let derived_token = client.request(&TokenCreateAction {
+                    token: client.token()?,
+                    claims,
+                })?;
+                println!("{}", base64::encode(bincode::serialize(&derived_token)?));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum SigningCommands {
+    #[clap(long_about = "Sign every request and make the remote refuse unsigned requests")]
+    On,
+
+    #[clap(long_about = "Stop signing requests and accept unsigned requests again")]
+    Off,
+}
+
+impl SigningCommands {
+    pub fn run(&self, repository: &str) -> KVSResult<()> {
+        let client = client(repository)?;
+        let token = client.token()?;
+        let sign_requests_file_path = get_or_create_user_config_kv_dir()?.join("sign_requests");
+        match self {
+            SigningCommands::On => {
+                client.request(&SigningAction {
+                    token,
+                    required: true,
+                })?;
+                std::fs::write(sign_requests_file_path, b"true")?;
+                tracing::info!("requests are signed, unsigned requests are refused");
+            }
+            SigningCommands::Off => {
+                client.request(&SigningAction {
+                    token,
+                    required: false,
+                })?;
+                std::fs::write(sign_requests_file_path, b"false")?;
+                tracing::info!("requests are not signed anymore");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum AdminCommands {
+    #[clap(long_about = "Query the audit log of the server")]
+    Audit {
+        #[clap(long, help = "Only show the actions of this scope")]
+        scope: Option<String>,
+
+        #[clap(short, long, help = "e.g. 30m, 12h, 7d, 2022-06-01 or a RFC 3339 time")]
+        since: Option<String>,
+
+        #[clap(short, long, help = "Only show this action, e.g. create")]
+        action: Option<String>,
+    },
+
+    #[clap(long_about = "Manage the master key encrypting the value and meta files of the server")]
+    MasterKey {
+        #[clap(subcommand)]
+        command: MasterKeyCommands,
+    },
+}
+
+impl AdminCommands {
+    pub fn run(&self) -> KVSResult<()> {
+        match self {
+            AdminCommands::Audit {
+                scope,
+                since,
+                action,
+            } => {
+                let query = AuditQuery {
+                    scope: scope.clone(),
+                    since: since.as_deref().map(parse_since).transpose()?,
+                    action: action.clone(),
+                };
+                print_audit_records(&query_audit_log(get_audit_log_path()?, &query)?);
+            }
+            AdminCommands::MasterKey { command } => command.run()?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum MasterKeyCommands {
+    #[clap(
+        long_about = "Create a new master key, re-encrypt every value and meta file with it and delete the old keys"
+    )]
+    Rotate,
+
+    #[clap(long_about = "Decrypt every value and meta file and delete the master keys")]
+    Off,
+}
+
+impl MasterKeyCommands {
+    pub fn run(&self) -> KVSResult<()> {
+        let lock = at_rest::lock_for_reseal()?;
+        let master_key_file_path = get_config_file_path("master_key")?;
+        match self {
+            MasterKeyCommands::Rotate => {
+                let id = at_rest::new_master_key()?;
+                std::fs::write(master_key_file_path, id.as_bytes())?;
+                tracing::info!("[master key] Rotate to: {}", id);
+            }
+            MasterKeyCommands::Off => {
+                if master_key_file_path.exists() {
+                    std::fs::remove_file(master_key_file_path)?;
+                }
+                tracing::info!("[master key] Off");
+            }
+        }
+        let count = at_rest::reseal_data_dir(&lock)?;
+        tracing::info!("[master key] Reseal {} files", count);
+        for id in at_rest::prune_master_keys(&lock)? {
+            tracing::info!("[master key] Remove: {}", id);
+        }
+        Ok(())
+    }
+}
+
+fn print_audit_records(records: &[AuditRecord]) {
+    records.iter().for_each(|record| {
+        let time = chrono::TimeZone::timestamp_millis_opt(&chrono::Local, record.time_stamp)
+            .single()
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_else(|| record.time_stamp.to_string());
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            time,
+            record.peer,
+            record.scope.as_deref().unwrap_or("-"),
+            record.action.as_deref().unwrap_or("-"),
+            record.key_hash.as_deref().unwrap_or("-"),
+            record.result
+        );
+    });
+}
+
+/// A client of the repository acting as the identity of the profile.
+fn client(repository: &str) -> KVSResult<KvsClient> {
+    let secret = get_or_create_secret()?;
+    let (token, _) = get_or_create_token(repository, false)?;
+    Ok(KvsClient::with_token(repository, secret, token).sign_requests(get_sign_requests_config()?))
+}
+
+/// The passphrase of an identity replacing a protected one.
+fn new_passphrase() -> KVSResult<String> {
+    get_passphrase("Passphrase of the new identity: ")
+}
+
+/// The value given as argument, read from a file or from stdin with `-f`.
+fn read_value(value: &Option<String>, file: &Option<Option<String>>) -> KVSResult<Vec<u8>> {
+    if value.is_none() && file.is_none() {
+        return Err(KVSError::LogicError(
+            "value params and file option can not to None in same time".to_string(),
+        ));
+    }
+    read_content(value, file)
+}
+
+/// The `--value-type` checked, or else the type detected from the value and
+/// the file name, or the key without a file.
+fn value_mime(
+    key: &str,
+    value: &[u8],
+    value_type: &Option<String>,
+    file: &Option<Option<String>>,
+) -> KVSResult<String> {
+    match value_type {
+        Some(value_type) => parse_mime(value_type),
+        None => Ok(detect_mime(
+            file.as_ref()
+                .and_then(|file| file.as_deref())
+                .unwrap_or(key),
+            value,
+        )),
+    }
+}
+
+/// The content given as argument, read from a file or else from stdin.
+fn read_content(content: &Option<String>, file: &Option<Option<String>>) -> KVSResult<Vec<u8>> {
+    match (content, file) {
+        (Some(content), _) => Ok(content.as_bytes().to_vec()),
+        (None, Some(Some(file_path))) => Ok(std::fs::read(file_path)?),
+        (None, _) => {
+            let mut buf = Vec::<u8>::new();
+            std::io::stdin().read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+    }
+}
+
+/// Create a private key readable by you and the `recipients` only.
+fn create_shared_key(
+    client: &KvsClient,
+    key: &str,
+    value: Vec<u8>,
+    recipients: &[(String, Vec<u8>)],
+) -> KVSResult<()> {
+    let token = client.token()?;
+    let rand = (0..32).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+    client.request(&CreateAction {
+        token: token.clone(),
+        key: key.to_string(),
+        meta: KeyMeta {
+            mime: "application/octet-stream".to_string(),
+            size: value.len() as u64,
+            owner: token.id.clone(),
+            name: key.to_string(),
+            rand: Some(rand.clone()),
+            original_hash: sha256(&value),
+            shares: vec![],
+            acl: None,
+            envelope: Envelope::Hkdf,
+            signature: None,
+        },
+        value,
+        team: None,
+        wrap_key: None,
+        identity: Some(client.identity().clone()),
+    })?;
+    for (scope, pub_key) in recipients {
+        let share = wrap_share(scope, pub_key, &rand)?;
+        client.request(&ShareAction {
+            token: token.clone(),
+            key: key.to_string(),
+            scope: share.scope,
+            rand: share.rand,
+        })?;
+    }
+    Ok(())
+}
@@ -1,34 +1,28 @@
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
 use crate::actions::{
-    Actions, CreateAction, DeleteAction, KVSToken, ListAction, ReadAction, UpdateAction,
+    forwarded_to, requires_signatures, Actions, KVSToken, SignedRequest, TokenClaims,
 };
+use crate::audit::{AuditLog, AuditRecord};
+use crate::config::get_or_create_identities_dir;
 use crate::errors::{KVSError, KVSResult};
 use crate::kv_session::KVSSession;
-use crate::spec::{KVPayloadResult, KVSAction, Session};
-use crate::utils::sgin;
+use crate::rate_limit::RateLimiter;
+use crate::secret::Secret;
+use crate::spec::{KVPayloadResult, KVSAction, RemoteError, Session};
 
 pub fn verify_jwt_token(jwt_secret: &[u8], msg: &Actions) -> KVSResult<()> {
-    let token = match &msg {
-        Actions::FetchToken(_) | Actions::RemoteVersionAction(_) => None,
-        Actions::CreateKeyValue(CreateAction { token, .. }) => Some(token),
-        Actions::CatAction(ReadAction { token, .. }) => Some(token),
-        Actions::DeleteAction(DeleteAction { token, .. }) => Some(token),
-        Actions::UpdateAction(UpdateAction { token, .. }) => Some(token),
-        Actions::ListAction(ListAction { token }) => Some(token),
-    };
-    if let Some(token) = token {
+    if let Some(token) = msg.token() {
         let KVSToken {
             id,
             time_stamp,
             sign,
+            claims,
         } = token;
-        let s_sign = sgin(
-            &[
-                id.clone(),
-                time_stamp.to_be_bytes().to_vec(),
-                jwt_secret.to_vec(),
-            ]
-            .concat(),
-        );
+        let s_sign = KVSToken::sign(id, *time_stamp, claims, jwt_secret)?;
 
         tracing::debug!("verify_jwt_token");
         tracing::debug!("jwt_secret: {:x?}", jwt_secret);
@@ -37,14 +31,173 @@ pub fn verify_jwt_token(jwt_secret: &[u8], msg: &Actions) -> KVSResult<()> {
         if s_sign != *sign {
             return Err(KVSError::LogicError("Illegal Token".to_string()));
         }
+        if let Some(claims) = claims {
+            verify_claims(claims, msg)?;
+        }
     }
 
     Ok(())
 }
 
-pub fn handle_client(session: &mut impl Session, jwt_secret: &[u8]) -> KVSResult<Vec<u8>> {
+fn verify_claims(claims: &TokenClaims, msg: &Actions) -> KVSResult<()> {
+    if let Some(expires_at) = claims.expires_at {
+        if chrono::Local::now().timestamp_millis() > expires_at {
+            return Err(KVSError::LogicError("The token is expired.".to_string()));
+        }
+    }
+    if !claims.allows_action(msg.name()) {
+        return Err(KVSError::LogicError(format!(
+            "The token does not allow the action: {}",
+            msg.name()
+        )));
+    }
+    match msg.key() {
+        Some(key) if !claims.allows_key(key) => Err(KVSError::LogicError(format!(
+            "The token does not allow the key: `{}`",
+            key
+        ))),
+        // keyless actions except the filtered list could reach other keys
+        None if !claims.prefixes.is_empty() && msg.name() != "list" => Err(KVSError::LogicError(
+            format!("The token does not allow the action: {}", msg.name()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Signed requests older or newer than this are refused.
+const SIGNATURE_WINDOW_MS: i64 = 5 * 60 * 1000;
+
+/// Remembers the nonces of the signed requests inside the time window.
+#[derive(Default)]
+pub struct ReplayGuard {
+    nonces: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl ReplayGuard {
+    fn check(&self, nonce: &[u8], time_stamp: i64, now: i64) -> KVSResult<()> {
+        if (now - time_stamp).abs() > SIGNATURE_WINDOW_MS {
+            return Err(KVSError::LogicError(
+                "The signed request is expired, check your clock.".to_string(),
+            ));
+        }
+        let mut nonces = self.nonces.lock().unwrap();
+        if nonces.len() > 1024 {
+            nonces.retain(|_, time_stamp| (now - *time_stamp).abs() <= SIGNATURE_WINDOW_MS);
+        }
+        if nonces.insert(nonce.to_vec(), time_stamp).is_some() {
+            return Err(KVSError::LogicError(
+                "The signed request is replayed.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Check the signature of a signed request and return the action inside.
+pub fn verify_signed_request(
+    request: &SignedRequest,
+    replay_guard: &ReplayGuard,
+) -> KVSResult<Actions> {
+    let msg = KVSSession::to::<Actions>(&request.action)?;
+    if let Actions::Signed(_) = msg {
+        return Err(KVSError::LogicError("Illegal signed request".to_string()));
+    }
+    if request.nonce.len() < 16 {
+        return Err(KVSError::LogicError("Illegal signed request".to_string()));
+    }
+    if let Some(token) = msg.token() {
+        let pub_key_file_path = get_or_create_identities_dir()?.join(token.get_addr());
+        if !pub_key_file_path.exists() {
+            return Err(KVSError::LogicError(format!(
+                "The scope: `{}` has no registered public key.",
+                token.get_addr()
+            )));
+        }
+        let payload = SignedRequest::payload(&request.action, request.time_stamp, &request.nonce);
+        let pub_key = std::fs::read(pub_key_file_path)?;
+        if !Secret::verify_with_pub_key_bits(&pub_key, &payload, &request.signature) {
+            return Err(KVSError::LogicError("Illegal Signature".to_string()));
+        }
+    }
+    replay_guard.check(
+        &request.nonce,
+        request.time_stamp,
+        chrono::Local::now().timestamp_millis(),
+    )?;
+    Ok(msg)
+}
+
+pub struct ServerContext {
+    pub jwt_secret: Vec<u8>,
+    /// Limits all actions per source IP, before they are verified.
+    pub ip_rate_limiter: RateLimiter,
+    /// Limits authenticated actions per scope.
+    pub scope_rate_limiter: RateLimiter,
+    pub audit_log: AuditLog,
+    pub replay_guard: ReplayGuard,
+}
+
+/// The requests taken from the source IP by a failed signature or token check.
+const AUTH_FAILURE_COST: f64 = 50.0;
+
+/// Check the signature and the token of a request, returns the action.
+fn authenticate(
+    msg: Actions,
+    context: &ServerContext,
+    record: &mut AuditRecord,
+) -> KVSResult<Actions> {
+    let (msg, signed) = match msg {
+        Actions::Signed(request) => (
+            verify_signed_request(&request, &context.replay_guard)?,
+            true,
+        ),
+        msg => (msg, false),
+    };
+    record.action = Some(msg.name().to_string());
+    record.key_hash = msg.key_hash();
+    verify_jwt_token(&context.jwt_secret, &msg)?;
+    if let Some(token) = msg.token() {
+        if !signed && requires_signatures(&token.get_addr())? {
+            return Err(KVSError::LogicError(format!(
+                "The scope: `{}` requires signed requests.",
+                token.get_addr()
+            )));
+        }
+        // the claimed scope of a refused request stays out of its audit trail
+        record.scope = msg.scope();
+    }
+    Ok(msg)
+}
+
+pub fn handle_client(
+    session: &mut impl Session,
+    context: &ServerContext,
+    peer_ip: &str,
+    record: &mut AuditRecord,
+) -> KVSResult<Vec<u8>> {
     let msg = KVSSession::to::<Actions>(&session.read_vec()?)?;
-    verify_jwt_token(jwt_secret, &msg)?;
+    record.action = Some(msg.name().to_string());
+    // limit before the signature and token checks, they cost more than the request
+    context.ip_rate_limiter.check(peer_ip)?;
+    let msg = match authenticate(msg, context, record) {
+        Ok(msg) => msg,
+        Err(error) => {
+            context.ip_rate_limiter.charge(peer_ip, AUTH_FAILURE_COST);
+            return Err(error);
+        }
+    };
+    if let Some(scope) = msg.scope() {
+        if let Some(record) = forwarded_to(&scope)? {
+            return Err(KVSError::LogicError(format!(
+                "The scope: `{}` moved to {}.",
+                scope, record.to
+            )));
+        }
+    }
+    if let Some(token) = msg.token() {
+        context.scope_rate_limiter.check(&token.get_addr())?;
+    }
+    let scope = msg.scope();
     let reply = match msg {
         Actions::FetchToken(mut fetch_token) => fetch_token.serve_serialize(session),
         Actions::CreateKeyValue(mut create_key_value) => create_key_value.serve_serialize(session),
@@ -53,22 +206,223 @@ pub fn handle_client(session: &mut impl Session, jwt_secret: &[u8]) -> KVSResult
         Actions::UpdateAction(mut update) => update.serve_serialize(session),
         Actions::RemoteVersionAction(mut remote_version) => remote_version.serve_serialize(session),
         Actions::ListAction(mut list_action) => list_action.serve_serialize(session),
+        Actions::PubKeyAction(mut pub_key) => pub_key.serve_serialize(session),
+        Actions::ShareAction(mut share) => share.serve_serialize(session),
+        Actions::UnshareAction(mut unshare) => unshare.serve_serialize(session),
+        Actions::TeamCreateAction(mut team_create) => team_create.serve_serialize(session),
+        Actions::TeamInfoAction(mut team_info) => team_info.serve_serialize(session),
+        Actions::TeamUpdateAction(mut team_update) => team_update.serve_serialize(session),
+        Actions::AclAction(mut acl) => acl.serve_serialize(session),
+        Actions::AuditAction(mut audit) => audit.serve_serialize(session),
+        Actions::TokenCreateAction(mut token_create) => token_create.serve_serialize(session),
+        Actions::SigningAction(mut signing) => signing.serve_serialize(session),
+        Actions::RotateAction(mut rotate) => rotate.serve_serialize(session),
+        Actions::ForwardAction(mut forward) => forward.serve_serialize(session),
+        Actions::RewrapAction(mut rewrap) => rewrap.serve_serialize(session),
+        Actions::ListStampAction(mut list_stamp) => list_stamp.serve_serialize(session),
+        Actions::InboundAction(mut inbound) => inbound.serve_serialize(session),
+        Actions::Signed(_) => Err(KVSError::LogicError("Illegal signed request".to_string())),
     }?;
+    // a login proves its scope by answering the challenge
+    record.scope = scope;
     Ok(reply)
 }
 
-pub fn service(session: &mut impl Session, jwt_secret: &[u8]) {
-    match handle_client(session, jwt_secret) {
+/// Serve the actions of a connection one after the other, until the client
+/// closes it or sends nothing for the read timeout.
+pub fn serve_connection(stream: TcpStream, context: &ServerContext) -> KVSResult<()> {
+    let peer_ip = stream.peer_addr()?.ip().to_string();
+    stream.set_read_timeout(Some(Duration::from_millis(1000)))?;
+    let mut session = KVSSession::new(stream)?;
+    loop {
+        service(&mut session, context, &peer_ip);
+        if !session.has_next() {
+            return Ok(());
+        }
+    }
+}
+
+pub fn service(session: &mut impl Session, context: &ServerContext, peer_ip: &str) {
+    let mut record = AuditRecord::new(peer_ip);
+    let result = handle_client(session, context, peer_ip, &mut record);
+    record.result = match &result {
+        Ok(_) => "ok".to_string(),
+        Err(KVSError::RateLimited(_)) => "rate limited".to_string(),
+        Err(KVSError::LogicError(error)) => format!("error: {}", error),
+        Err(error) => format!("error: {}", error),
+    };
+    context.audit_log.append(&record);
+    match result {
         Ok(reply) => session
             .write_vec(&reply)
             .unwrap_or_else(|error| tracing::error!("{}", error)),
         Err(error) => match error {
             KVSError::LogicError(logic_error) => {
                 session
-                    .write(&KVPayloadResult::<()>::Err(logic_error))
+                    .write(&KVPayloadResult::<()>::Err(RemoteError::Logic(logic_error)))
+                    .unwrap_or_else(|error| tracing::error!("{}", error));
+            }
+            KVSError::RateLimited(retry_after) => {
+                tracing::warn!("[{}] rate limited for {} ms", peer_ip, retry_after);
+                session
+                    .write(&KVPayloadResult::<()>::Err(RemoteError::RateLimited {
+                        retry_after,
+                    }))
                     .unwrap_or_else(|error| tracing::error!("{}", error));
             }
             _ => tracing::error!("{}", error),
         },
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{net::TcpListener, sync::OnceLock};
+
+    use super::{handle_client, serve_connection, ReplayGuard, ServerContext, SIGNATURE_WINDOW_MS};
+    use crate::{
+        actions::{Actions, KVSToken, ListAction, RemoteVersionAction},
+        audit::{AuditLog, AuditRecord},
+        config::get_or_create_jwt_secret,
+        errors::{KVSError, KVSResult},
+        rate_limit::RateLimiter,
+        spec::Session,
+    };
+
+    /// Replies nothing, reads the queued messages.
+    struct QueuedSession(Vec<Vec<u8>>);
+
+    impl Session for QueuedSession {
+        fn read_vec(&mut self) -> KVSResult<Vec<u8>> {
+            Ok(self.0.remove(0))
+        }
+
+        fn write_vec(&mut self, _: &[u8]) -> KVSResult<()> {
+            Ok(())
+        }
+
+        fn write<T: ?Sized + serde::Serialize>(&mut self, _: &T) -> KVSResult<()> {
+            Ok(())
+        }
+    }
+
+    fn test_context(ip_rate_limit: &str) -> ServerContext {
+        let audit_log_path = std::env::temp_dir().join(format!("kvs_audit_{}", std::process::id()));
+        ServerContext {
+            jwt_secret: b"jwt secret".to_vec(),
+            ip_rate_limiter: RateLimiter::new(Some(ip_rate_limit.parse().unwrap())),
+            scope_rate_limiter: RateLimiter::new(None),
+            audit_log: AuditLog::open(audit_log_path).unwrap(),
+            replay_guard: ReplayGuard::default(),
+        }
+    }
+
+    fn forged_list() -> Vec<u8> {
+        let token = KVSToken::signed(vec![7; 20], 0, None, b"other secret").unwrap();
+        bincode::serialize(&Actions::ListAction(ListAction {
+            token,
+            team: None,
+            scope: None,
+        }))
+        .unwrap()
+    }
+
+    /// A server on a free local port, serving from a temporary home that
+    /// the tests of the process share.
+    pub(crate) fn test_repository() -> String {
+        static REPOSITORY: OnceLock<String> = OnceLock::new();
+        REPOSITORY
+            .get_or_init(|| {
+                let home = std::env::temp_dir().join(format!("kvs_test_{}", std::process::id()));
+                std::fs::create_dir_all(&home).unwrap();
+                std::env::set_var("HOME", &home);
+                std::env::remove_var("XDG_DATA_HOME");
+                std::env::remove_var("KVS_PROFILE");
+                std::env::remove_var("KVS_TOKEN");
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let repository = listener.local_addr().unwrap().to_string();
+                let context = ServerContext {
+                    jwt_secret: get_or_create_jwt_secret(false).unwrap(),
+                    ip_rate_limiter: RateLimiter::new(None),
+                    scope_rate_limiter: RateLimiter::new(None),
+                    audit_log: AuditLog::open(home.join("audit.log")).unwrap(),
+                    replay_guard: ReplayGuard::default(),
+                };
+                std::thread::spawn(move || {
+                    let context = &context;
+                    std::thread::scope(|scope| {
+                        for stream in listener.incoming().flatten() {
+                            scope.spawn(move || serve_connection(stream, context));
+                        }
+                    })
+                });
+                repository
+            })
+            .clone()
+    }
+
+    /// A new empty directory under the temp dir, as the path relative to the
+    /// current dir sync and pull take.
+    pub(crate) fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("kvs_{}_{}", name, std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        let depth = std::env::current_dir().unwrap().components().count() - 1;
+        format!(
+            "{}{}",
+            "../".repeat(depth),
+            dir.display().to_string().trim_start_matches('/')
+        )
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let guard = ReplayGuard::default();
+        assert!(guard.check(b"nonce-a", 1000, 1000).is_ok());
+        assert!(guard.check(b"nonce-a", 1000, 1001).is_err());
+        assert!(guard.check(b"nonce-b", 1000, 1001).is_ok());
+        assert!(guard
+            .check(b"nonce-c", 1000, 1001 + SIGNATURE_WINDOW_MS)
+            .is_err());
+    }
+
+    #[test]
+    fn test_auth_failures_are_rate_limited() {
+        let context = test_context("50,1");
+        let version =
+            bincode::serialize(&Actions::RemoteVersionAction(RemoteVersionAction)).unwrap();
+        let mut session = QueuedSession(vec![forged_list(), version.clone(), version.clone()]);
+        let mut serve = |peer_ip: &str| {
+            let mut record = AuditRecord::new(peer_ip);
+            handle_client(&mut session, &context, peer_ip, &mut record)
+        };
+        assert!(matches!(serve("10.0.0.1"), Err(KVSError::LogicError(_))));
+        assert!(matches!(serve("10.0.0.1"), Err(KVSError::RateLimited(_))));
+        assert!(serve("10.0.0.2").is_ok());
+    }
+
+    #[test]
+    fn test_audit_scope() {
+        // the data dir of the test server
+        test_repository();
+        let context = test_context("500,100");
+        let token = KVSToken::signed(vec![8; 20], 0, None, &context.jwt_secret).unwrap();
+        let scope = token.get_addr();
+        let list = bincode::serialize(&Actions::ListAction(ListAction {
+            token,
+            team: None,
+            scope: None,
+        }))
+        .unwrap();
+        let mut session = QueuedSession(vec![forged_list(), list]);
+        let mut record = AuditRecord::new("10.0.0.3");
+        assert!(handle_client(&mut session, &context, "10.0.0.3", &mut record).is_err());
+        assert_eq!(record.scope, None);
+        assert_eq!(record.action.as_deref(), Some("list"));
+        let mut record = AuditRecord::new("10.0.0.3");
+        assert!(handle_client(&mut session, &context, "10.0.0.3", &mut record).is_ok());
+        assert_eq!(record.scope, Some(scope));
+    }
+}
@@ -1,19 +1,158 @@
 use aes_gcm::{aead::Aead, Aes256Gcm, Key, NewAead, Nonce};
-use std::net::TcpStream;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+use x25519_dalek::{EphemeralSecret, PublicKey as DHPublicKey};
 
 use crate::{
+    actions::{Actions, SignedRequest},
+    config::{get_or_create_secret, get_sign_requests_config},
     errors::{KVSError, KVSResult},
-    secret::{key_pair, to_pub_key},
-    spec::Session,
+    secret::{key_pair, to_pub_key, Secret},
+    spec::{KVSAction, Session},
 };
 
 pub struct KVSSession {
     stream: TcpStream,
-    cipher: Aes256Gcm,
+    cipher: SessionCipher,
+    /// The remote closed the session, a write failed or a read ended.
+    closed: bool,
 }
 
+/// Both ends open a session with `HANDSHAKE_MAGIC ++ PROTOCOL_VERSION ++
+/// X25519 public key`.
+const HANDSHAKE_MAGIC: &[u8; 3] = b"kvs";
+/// Changes with the handshake or the framing of the messages, the ends of a
+/// session must speak the same version.
+pub(crate) const PROTOCOL_VERSION: u8 = 2;
+pub(crate) const HANDSHAKE_HEAD_LENGTH: usize = HANDSHAKE_MAGIC.len() + 1;
+
+/// The handshake this end sends.
+pub(crate) fn handshake_hello(pk: &DHPublicKey) -> Vec<u8> {
+    [&HANDSHAKE_MAGIC[..], &[PROTOCOL_VERSION], pk.as_bytes()].concat()
+}
+
+/// Refuse a remote speaking another protocol, before reading its key.
+pub(crate) fn check_handshake_head(head: &[u8; HANDSHAKE_HEAD_LENGTH]) -> KVSResult<()> {
+    match head.split_at(HANDSHAKE_MAGIC.len()) {
+        (magic, [version]) if magic == HANDSHAKE_MAGIC && *version == PROTOCOL_VERSION => Ok(()),
+        (magic, [version]) if magic == HANDSHAKE_MAGIC => Err(KVSError::LogicError(format!(
+            "The remote speaks the kvs protocol {}, this end speaks {}, upgrade the older one.",
+            version, PROTOCOL_VERSION
+        ))),
+        _ => Err(KVSError::LogicError(format!(
+            "The remote does not speak the kvs protocol {}, upgrade it.",
+            PROTOCOL_VERSION
+        ))),
+    }
+}
+
+/// The largest message a session sends or reads, the length of a frame is
+/// checked before it is allocated.
+pub(crate) const MAX_FRAME_LENGTH: usize = 256 * 1024 * 1024;
+
+/// The length of a frame from its bincode `u64` prefix.
+pub(crate) fn frame_length(length: [u8; 8]) -> KVSResult<usize> {
+    let length = u64::from_le_bytes(length);
+    if length > MAX_FRAME_LENGTH as u64 {
+        return Err(KVSError::LogicError(format!(
+            "The message of {} bytes is larger than {} bytes.",
+            length, MAX_FRAME_LENGTH
+        )));
+    }
+    Ok(length as usize)
+}
+
+/// The nonce of values encrypted with their own random key.
 pub const NONCE: &[u8] = b"kvskvskvskvs";
 
+/// The ciphers of a session, a key for each direction with the count of
+/// the messages sent that way as nonce. A message is framed as
+/// `count (u64 BE) ++ ciphertext` and must arrive in order.
+pub(crate) struct SessionCipher {
+    sending: Aes256Gcm,
+    receiving: Aes256Gcm,
+    sent: u64,
+    received: u64,
+}
+
+const COUNT_LENGTH: usize = 8;
+/// The AES-GCM tag after the ciphertext.
+const TAG_LENGTH: usize = 16;
+
+impl SessionCipher {
+    /// Both ends derive the two keys from the X25519 exchange, the end
+    /// with the lower public key sends with the first one.
+    pub(crate) fn new(sk: EphemeralSecret, pk: DHPublicKey, peer_pk: [u8; 32]) -> KVSResult<Self> {
+        let pk = *pk.as_bytes();
+        if pk == peer_pk {
+            return Err(KVSError::LogicError("Illegal handshake".to_string()));
+        }
+        let shared_secret = sk.diffie_hellman(&to_pub_key(peer_pk));
+        let (lower, higher) = match pk < peer_pk {
+            true => (pk, peer_pk),
+            false => (peer_pk, pk),
+        };
+        let mut keys = [0u8; 64];
+        Hkdf::<Sha256>::new(Some(&[lower, higher].concat()), shared_secret.as_bytes())
+            .expand(b"kvs session", &mut keys)
+            .map_err(|_| KVSError::LogicError("HKDF Error".to_string()))?;
+        let (lower_key, higher_key) = keys.split_at(32);
+        let (sending, receiving) = match pk < peer_pk {
+            true => (lower_key, higher_key),
+            false => (higher_key, lower_key),
+        };
+        Ok(SessionCipher {
+            sending: Aes256Gcm::new(Key::from_slice(sending)),
+            receiving: Aes256Gcm::new(Key::from_slice(receiving)),
+            sent: 0,
+            received: 0,
+        })
+    }
+
+    fn nonce(count: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[12 - COUNT_LENGTH..].copy_from_slice(&count.to_be_bytes());
+        nonce
+    }
+
+    pub(crate) fn seal(&mut self, payload: &[u8]) -> KVSResult<Vec<u8>> {
+        frame_length(((COUNT_LENGTH + payload.len() + TAG_LENGTH) as u64).to_le_bytes())?;
+        let count = self.sent;
+        self.sent = count
+            .checked_add(1)
+            .ok_or_else(|| KVSError::LogicError("The session is exhausted.".to_string()))?;
+        let data = self
+            .sending
+            .encrypt(Nonce::from_slice(&SessionCipher::nonce(count)), payload)?;
+        Ok([&count.to_be_bytes()[..], &data].concat())
+    }
+
+    /// Decrypt the next message, refusing replayed, dropped or reordered ones.
+    pub(crate) fn open(&mut self, data: &[u8]) -> KVSResult<Vec<u8>> {
+        if data.len() < COUNT_LENGTH {
+            return Err(KVSError::LogicError("Illegal message".to_string()));
+        }
+        let (count, data) = data.split_at(COUNT_LENGTH);
+        let count = u64::from_be_bytes(count.try_into().unwrap());
+        if count != self.received {
+            return Err(KVSError::LogicError(format!(
+                "The message {} is out of order, expected {}.",
+                count, self.received
+            )));
+        }
+        let payload = self
+            .receiving
+            .decrypt(Nonce::from_slice(&SessionCipher::nonce(count)), data)?;
+        self.received += 1;
+        Ok(payload)
+    }
+}
+
 impl KVSSession {
     pub fn to<'a, T: serde::de::Deserialize<'a>>(bytes: &'a [u8]) -> KVSResult<T> {
         let data: T = bincode::deserialize(bytes)?;
@@ -22,51 +161,214 @@ impl KVSSession {
 }
 
 impl KVSSession {
+    pub fn connect(repository: &str) -> KVSResult<Self> {
+        let stream = TcpStream::connect(repository)?;
+        stream.set_read_timeout(Some(Duration::from_millis(1000)))?;
+        KVSSession::new(stream)
+    }
+
     pub fn new(stream: TcpStream) -> KVSResult<Self> {
         let (sk, pk) = key_pair();
         // 通道建立
-        bincode::serialize_into(&stream, &pk.as_bytes())?;
-        let pk_bytes: [u8; 32] = bincode::deserialize_from(&stream)?;
+        (&stream).write_all(&handshake_hello(&pk))?;
+        let mut head = [0u8; HANDSHAKE_HEAD_LENGTH];
+        (&stream).read_exact(&mut head)?;
+        check_handshake_head(&head)?;
+        let mut pk_bytes = [0u8; 32];
+        (&stream).read_exact(&mut pk_bytes)?;
 
-        let shared_secret = sk.diffie_hellman(&to_pub_key(pk_bytes));
-        let key = Key::from_slice(shared_secret.as_bytes());
-        let cipher = Aes256Gcm::new(key);
-        Ok(KVSSession { stream, cipher })
+        let cipher = SessionCipher::new(sk, pk, pk_bytes)?;
+        Ok(KVSSession {
+            stream,
+            cipher,
+            closed: false,
+        })
+    }
+}
+
+impl KVSSession {
+    /// Wait for the next action of the client, `false` once it closed the
+    /// session or stayed idle for the read timeout.
+    pub fn has_next(&self) -> bool {
+        matches!(self.stream.peek(&mut [0u8; 1]), Ok(length) if length > 0)
+    }
+
+    /// The count of the messages sent and received on the session.
+    pub(crate) fn counts(&self) -> (u64, u64) {
+        (self.cipher.sent, self.cipher.received)
+    }
+
+    /// Whether the remote closed the session before it could serve the
+    /// request started at `counts`: writing its first message failed, or the
+    /// session ended before any reply once that one message was sent.
+    pub(crate) fn closed_before(&self, (sent, received): (u64, u64)) -> bool {
+        self.closed && self.cipher.sent == sent + 1 && self.cipher.received == received
+    }
+
+    #[cfg(test)]
+    pub(crate) fn shutdown(&self) {
+        self.stream.shutdown(std::net::Shutdown::Write).unwrap();
     }
 }
 
 impl Session for KVSSession {
     fn read_vec(&mut self) -> KVSResult<Vec<u8>> {
-        let payload: Vec<u8> = bincode::deserialize_from(&self.stream)?;
-        match self.cipher.decrypt(Nonce::from_slice(NONCE), &*payload) {
-            Ok(data) => Ok(data),
-            Err(err) => Err(KVSError::AESGcmError(err)),
+        let mut length = [0u8; 8];
+        if let Err(error) = (&self.stream).read_exact(&mut length) {
+            self.closed |= error.kind() == std::io::ErrorKind::UnexpectedEof;
+            return Err(error.into());
         }
+        let mut payload = vec![0u8; frame_length(length)?];
+        (&self.stream).read_exact(&mut payload)?;
+        self.cipher.open(&payload)
     }
 
     fn write_vec(&mut self, payload: &[u8]) -> KVSResult<()> {
-        let data = self.cipher.encrypt(Nonce::from_slice(NONCE), &*payload)?;
-        bincode::serialize_into(&self.stream, &data)?;
+        let data = self.cipher.seal(payload)?;
+        // one write for the length and the data, small writes stall on Nagle
+        if let Err(error) = (&self.stream).write_all(&bincode::serialize(&data)?) {
+            self.closed = true;
+            return Err(error.into());
+        }
         Ok(())
     }
 
-    fn write<T: ?Sized>(&mut self, payload: &T) -> KVSResult<()>
-    where
-        T: serde::Serialize,
-    {
-        let payload = bincode::serialize(payload)?;
-        let data = self.cipher.encrypt(Nonce::from_slice(NONCE), &*payload)?;
-        bincode::serialize_into(&self.stream, &data)?;
-        Ok(())
+    fn write<T: ?Sized + serde::Serialize>(&mut self, payload: &T) -> KVSResult<()> {
+        self.write_vec(&bincode::serialize(payload)?)
+    }
+}
+
+/// Signs the first message of the session, the action, with the identity
+/// key of the client.
+pub struct SignedSession<S: Session> {
+    session: S,
+    priv_key_bits: Vec<u8>,
+    signed: bool,
+}
+
+impl<S: Session> SignedSession<S> {
+    pub fn new(session: S, priv_key_bits: Vec<u8>) -> Self {
+        SignedSession {
+            session,
+            priv_key_bits,
+            signed: false,
+        }
+    }
+}
+
+impl<S: Session> Session for &mut S {
+    fn read_vec(&mut self) -> KVSResult<Vec<u8>> {
+        (**self).read_vec()
+    }
+
+    fn write_vec(&mut self, payload: &[u8]) -> KVSResult<()> {
+        (**self).write_vec(payload)
+    }
+
+    fn write<T: ?Sized + serde::Serialize>(&mut self, payload: &T) -> KVSResult<()> {
+        (**self).write(payload)
+    }
+}
+
+impl<S: Session> Session for SignedSession<S> {
+    fn read_vec(&mut self) -> KVSResult<Vec<u8>> {
+        self.session.read_vec()
+    }
+
+    fn write_vec(&mut self, payload: &[u8]) -> KVSResult<()> {
+        if self.signed {
+            return self.session.write_vec(payload);
+        }
+        self.signed = true;
+        let time_stamp = chrono::Local::now().timestamp_millis();
+        let nonce = (0..16).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+        let signature = Secret::sign_with_priv_key_bits(
+            &self.priv_key_bits,
+            &SignedRequest::payload(payload, time_stamp, &nonce),
+        )?;
+        self.session.write(&Actions::Signed(SignedRequest {
+            action: payload.to_vec(),
+            time_stamp,
+            nonce,
+            signature,
+        }))
+    }
+
+    fn write<T: ?Sized + serde::Serialize>(&mut self, payload: &T) -> KVSResult<()> {
+        self.write_vec(&bincode::serialize(payload)?)
+    }
+}
+
+/// Send `action` on a new session, waiting and retrying while the remote
+/// answers that we are rate limited. The action is signed when the
+/// `sign_requests` config is `true`.
+pub fn request<R, A>(repository: &str, action: &A) -> KVSResult<R>
+where
+    R: serde::Serialize,
+    A: KVSAction<R> + Clone,
+{
+    // unlock the identity before connecting, the remote does not wait for prompts
+    let secret = get_or_create_secret()?;
+    let priv_key_bits = match get_sign_requests_config()? {
+        true => Some(secret.priv_key_bits),
+        false => None,
+    };
+    request_with(repository, priv_key_bits.as_deref(), action)
+}
+
+/// `request`, signing the action with `priv_key_bits` when given.
+pub fn request_with<R, A>(
+    repository: &str,
+    priv_key_bits: Option<&[u8]>,
+    action: &A,
+) -> KVSResult<R>
+where
+    R: serde::Serialize,
+    A: KVSAction<R> + Clone,
+{
+    let mut retries = 0;
+    loop {
+        let mut session = KVSSession::connect(repository)?;
+        match request_on(&mut session, priv_key_bits, action) {
+            Err(KVSError::RateLimited(retry_after)) if retries < MAX_RATE_LIMITED_RETRIES => {
+                tracing::debug!("rate limited, retry after {} ms", retry_after);
+                std::thread::sleep(Duration::from_millis(retry_after));
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub(crate) const MAX_RATE_LIMITED_RETRIES: usize = 8;
+
+/// Run `action` on an open session, signed with `priv_key_bits` when given.
+pub fn request_on<R, A>(
+    session: &mut KVSSession,
+    priv_key_bits: Option<&[u8]>,
+    action: &A,
+) -> KVSResult<R>
+where
+    R: serde::Serialize,
+    A: KVSAction<R> + Clone,
+{
+    let mut action = action.clone();
+    match priv_key_bits {
+        Some(priv_key_bits) => {
+            action.request(&mut SignedSession::new(session, priv_key_bits.to_vec()))
+        }
+        None => action.request(session),
     }
 }
 
 #[cfg(test)]
+#[allow(dead_code)]
 pub struct MockSession {
     stream: std::fs::File,
     cipher: Aes256Gcm,
 }
 #[cfg(test)]
+#[allow(dead_code)]
 impl MockSession {
     pub fn new() -> KVSResult<Self> {
         if !std::path::Path::new("mock_stream").exists() {
@@ -91,18 +393,85 @@ impl Session for MockSession {
     }
 
     fn write_vec(&mut self, payload: &[u8]) -> KVSResult<()> {
-        let data = self.cipher.encrypt(Nonce::from_slice(NONCE), &*payload)?;
+        let data = self.cipher.encrypt(Nonce::from_slice(NONCE), payload)?;
         bincode::serialize_into(&self.stream, &data)?;
         Ok(())
     }
 
-    fn write<T: ?Sized>(&mut self, payload: &T) -> KVSResult<()>
-    where
-        T: serde::Serialize,
-    {
+    fn write<T: ?Sized + serde::Serialize>(&mut self, payload: &T) -> KVSResult<()> {
         let payload = bincode::serialize(payload)?;
         let data = self.cipher.encrypt(Nonce::from_slice(NONCE), &*payload)?;
         bincode::serialize_into(&self.stream, &data)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_handshake_head, frame_length, handshake_hello, SessionCipher, HANDSHAKE_HEAD_LENGTH,
+        MAX_FRAME_LENGTH,
+    };
+    use crate::secret::key_pair;
+
+    fn cipher_pair() -> (SessionCipher, SessionCipher) {
+        let (client_sk, client_pk) = key_pair();
+        let (server_sk, server_pk) = key_pair();
+        let (client_pk_bytes, server_pk_bytes) = (*client_pk.as_bytes(), *server_pk.as_bytes());
+        (
+            SessionCipher::new(client_sk, client_pk, server_pk_bytes).unwrap(),
+            SessionCipher::new(server_sk, server_pk, client_pk_bytes).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_session_cipher() {
+        let (mut client, mut server) = cipher_pair();
+        let first = client.seal(b"action").unwrap();
+        let second = client.seal(b"action").unwrap();
+        assert_ne!(first[8..], second[8..]);
+        assert_eq!(server.open(&first).unwrap(), b"action");
+        // replayed
+        assert!(server.open(&first).is_err());
+        assert_eq!(server.open(&second).unwrap(), b"action");
+        let reply = server.seal(b"reply").unwrap();
+        // reflected back to its sender
+        assert!(server.open(&reply).is_err());
+        assert_eq!(client.open(&reply).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_frame_length() {
+        assert_eq!(frame_length(1024u64.to_le_bytes()).unwrap(), 1024);
+        assert!(frame_length(u64::MAX.to_le_bytes()).is_err());
+        let (mut client, _) = cipher_pair();
+        assert!(client.seal(&vec![0; MAX_FRAME_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn test_handshake_head() {
+        let (_, pk) = key_pair();
+        let hello = handshake_hello(&pk);
+        let head = |hello: &[u8]| <[u8; HANDSHAKE_HEAD_LENGTH]>::try_from(hello).unwrap();
+        assert!(check_handshake_head(&head(&hello[..HANDSHAKE_HEAD_LENGTH])).is_ok());
+        let mut newer = head(&hello[..HANDSHAKE_HEAD_LENGTH]);
+        newer[HANDSHAKE_HEAD_LENGTH - 1] += 1;
+        assert!(check_handshake_head(&newer).is_err());
+        // older ends start with their public key
+        assert!(check_handshake_head(&[0x5a; HANDSHAKE_HEAD_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn test_session_cipher_out_of_order() {
+        let (mut client, mut server) = cipher_pair();
+        let first = client.seal(b"first").unwrap();
+        let second = client.seal(b"second").unwrap();
+        assert!(server.open(&second).is_err());
+        assert_eq!(server.open(&first).unwrap(), b"first");
+        // a message relabelled with the expected count does not decrypt
+        let mut third = client.seal(b"third").unwrap();
+        third[..8].copy_from_slice(&1u64.to_be_bytes());
+        assert!(server.open(&third).is_err());
+        assert_eq!(server.open(&second).unwrap(), b"second");
+    }
+}
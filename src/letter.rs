@@ -25,8 +25,8 @@ pub static LETTER: &str = "小学时 我曾想
 爱你 我的宝";
 
 pub fn print_letter() {
-  LETTER.split("\n").into_iter().for_each(|line| {
-    println!("{}",line);
-    std::thread::sleep(std::time::Duration::from_secs(2));
-  });
-}
\ No newline at end of file
+    LETTER.split("\n").for_each(|line| {
+        println!("{}", line);
+        std::thread::sleep(std::time::Duration::from_secs(2));
+    });
+}
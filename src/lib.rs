@@ -2,14 +2,31 @@
 extern crate version;
 
 mod actions;
+#[cfg(feature = "async")]
+mod async_client;
+mod at_rest;
+mod audit;
+mod client;
 mod config;
 mod errors;
 mod kv_commands;
 mod kv_server;
 mod kv_session;
+mod letter;
+mod rate_limit;
 mod secret;
+mod shamir;
+mod signature;
 mod spec;
+mod sync;
 mod utils;
-mod letter;
 
+pub use crate::actions::{Envelope, KVSToken, KeyMeta, KeyShare};
+#[cfg(feature = "async")]
+pub use crate::async_client::AsyncKvsClient;
+pub use crate::client::KvsClient;
+pub use crate::config::set_profile;
+pub use crate::errors::{KVSError, KVSResult};
 pub use crate::kv_commands::Commands;
+pub use crate::secret::{KeyType, Secret};
+pub use crate::utils::detect_mime;
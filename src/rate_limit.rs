@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::errors::{KVSError, KVSResult};
+
+/// One request every ~17 minutes, slower limits are refused.
+const MIN_PER_SECOND: f64 = 0.001;
+/// The longest wait a limited client is told about.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// A token bucket holding at most `burst` requests, refilled with
+/// `per_second` requests every second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+impl FromStr for RateLimit {
+    type Err = KVSError;
+
+    /// `<burst>,<per_second>`, e.g. `10,1`
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let illegal = || KVSError::LogicError(format!("Illegal rate limit: {}", content));
+        let (burst, per_second) = content.trim().split_once(',').ok_or_else(illegal)?;
+        let burst = burst.trim().parse::<f64>().map_err(|_| illegal())?;
+        let per_second = per_second.trim().parse::<f64>().map_err(|_| illegal())?;
+        if !burst.is_finite()
+            || !per_second.is_finite()
+            || burst < 1.0
+            || per_second < MIN_PER_SECOND
+        {
+            return Err(illegal());
+        }
+        Ok(RateLimit { burst, per_second })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+pub struct RateLimiter {
+    limit: Option<RateLimit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take one request from the bucket of `key`.
+    pub fn check(&self, key: &str) -> KVSResult<()> {
+        self.check_at(key, Instant::now())
+    }
+
+    /// Take `cost` requests from the bucket of `key` even when it is empty,
+    /// the bucket then refills for longer before the next request.
+    pub fn charge(&self, key: &str, cost: f64) {
+        self.charge_at(key, cost, Instant::now())
+    }
+
+    fn charge_at(&self, key: &str, cost: f64, now: Instant) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = RateLimiter::refill(&mut buckets, key, limit, now);
+        bucket.tokens = (bucket.tokens - cost).max(-limit.burst);
+    }
+
+    fn refill<'a>(
+        buckets: &'a mut HashMap<String, Bucket>,
+        key: &str,
+        limit: RateLimit,
+        now: Instant,
+    ) -> &'a mut Bucket {
+        if buckets.len() > 10_000 {
+            buckets.retain(|_, bucket| {
+                let idle = now.duration_since(bucket.updated_at).as_secs_f64();
+                bucket.tokens + idle * limit.per_second < limit.burst
+            });
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.burst,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst);
+        bucket.updated_at = now;
+        bucket
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> KVSResult<()> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = RateLimiter::refill(&mut buckets, key, limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let retry_after = Duration::try_from_secs_f64((1.0 - bucket.tokens) / limit.per_second)
+                .unwrap_or(MAX_RETRY_AFTER)
+                .min(MAX_RETRY_AFTER);
+            Err(KVSError::RateLimited(retry_after.as_millis() as u64 + 1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimit, RateLimiter};
+    use crate::errors::KVSError;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!(
+            "10,0.5".parse::<RateLimit>().unwrap(),
+            RateLimit {
+                burst: 10.0,
+                per_second: 0.5
+            }
+        );
+        assert!("10".parse::<RateLimit>().is_err());
+        assert!("0,1".parse::<RateLimit>().is_err());
+        for content in ["NaN,1", "1,NaN", "inf,1", "1,inf", "1,1e-300", "1,-1"] {
+            assert!(content.parse::<RateLimit>().is_err(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(Some("2,1".parse().unwrap()));
+        let now = Instant::now();
+        assert!(limiter.check_at("a", now).is_ok());
+        assert!(limiter.check_at("a", now).is_ok());
+        match limiter.check_at("a", now) {
+            Err(KVSError::RateLimited(retry_after)) => assert!(retry_after > 0),
+            _ => panic!("should be rate limited"),
+        }
+        assert!(limiter.check_at("b", now).is_ok());
+        assert!(limiter
+            .check_at("a", now + Duration::from_millis(1001))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_charge() {
+        let limiter = RateLimiter::new(Some("4,1".parse().unwrap()));
+        let now = Instant::now();
+        limiter.charge_at("a", 100.0, now);
+        assert!(limiter.check_at("a", now).is_err());
+        // the debt is at most one burst
+        assert!(limiter
+            .check_at("a", now + Duration::from_millis(4500))
+            .is_err());
+        assert!(limiter
+            .check_at("a", now + Duration::from_millis(5001))
+            .is_ok());
+
+        // the slowest limit still replies a bounded wait
+        let limiter = RateLimiter::new(Some("1,0.001".parse().unwrap()));
+        limiter.charge_at("a", 1e300, now);
+        match limiter.check_at("a", now) {
+            Err(KVSError::RateLimited(retry_after)) => assert!(retry_after <= 3_600_001),
+            _ => panic!("should be rate limited"),
+        }
+    }
+}
@@ -2,44 +2,79 @@ use std::fmt::Display;
 
 use rand::rngs::OsRng;
 use rsa::pkcs1::{FromRsaPrivateKey, ToRsaPrivateKey};
-use rsa::pkcs8::FromPublicKey;
+use rsa::pkcs8::{FromPrivateKey, FromPublicKey, ToPrivateKey};
 use rsa::{pkcs8::ToPublicKey, RsaPrivateKey, RsaPublicKey};
-use rsa::{PaddingScheme, PublicKey};
+use rsa::{Hash, PaddingScheme, PublicKey};
 
-use crate::errors::KVSResult;
+use aes_gcm::{aead::Aead, Aes256Gcm, Key, NewAead, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
 
+use sha2::Digest;
+use x25519_dalek::{EphemeralSecret, PublicKey as DHPublicKey, StaticSecret};
+
+use crate::{
+    errors::{KVSError, KVSResult},
+    utils::sha256,
+};
+
+/// Marks the private key line of a secret file encrypted with a passphrase,
+/// followed by `<salt>:<nonce>:<ciphertext>` in base64.
+const ENCRYPTED_PREFIX: &str = "argon2:";
 
 // pub const PUB_KEY_LENGTH: usize = 162;
 
+/// The first byte of Ed25519 identity keys, RSA keys are DER and start with 0x30.
+const ED25519_TAG: u8 = 0x01;
+
+/// The first bytes of `Secret::hybrid_encrypt_with_pub_key_bits` output.
+const HYBRID_PREFIX: &[u8] = b"kvs hybrid 1\0";
+
+/// The PKCS#8 (RFC 8410) DER of an Ed25519 private key before its seed.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    /// RSA-1024 encrypting with PKCS#1 v1.5.
+    Rsa,
+    /// Ed25519 signing, X25519 wrapping.
+    Ed25519,
+}
+
+impl std::str::FromStr for KeyType {
+    type Err = KVSError;
+
+    fn from_str(key_type: &str) -> Result<Self, Self::Err> {
+        match key_type {
+            "rsa" => Ok(KeyType::Rsa),
+            "ed25519" => Ok(KeyType::Ed25519),
+            _ => Err(KVSError::LogicError(format!(
+                "Illegal key type: {}",
+                key_type
+            ))),
+        }
+    }
+}
+
+impl Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KeyType::Rsa => "rsa",
+            KeyType::Ed25519 => "ed25519",
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct Secret {
-    pub pub_key_bits: Vec<u8>, // 162
+    pub pub_key_bits: Vec<u8>, // 162 for RSA, 65 for Ed25519
     pub priv_key_bits: Vec<u8>,
 }
 
 impl Default for Secret {
     fn default() -> Self {
-        let mut rng = OsRng;
-        let bits = 1024;
-        let priv_key = RsaPrivateKey::new(&mut rng, bits).expect("failed to generate a priv key");
-        let pub_key = RsaPublicKey::from(&priv_key);
-        let pub_key_content = pub_key
-            .to_public_key_pem()
-            .expect("failed to get public key content");
-        let pem = pem::parse(pub_key_content).expect("failed to parse pub key content");
-        let pub_key_bits = pem.contents;
-        // let pub_key = base64::encode(pub_key_bits.clone());
-        // let addr = sha256::digest_bytes(&pub_key_bits);
-
-        let priv_key_content = priv_key
-            .to_pkcs1_der()
-            .expect("failed to get priv key content");
-        let priv_key_bits = priv_key_content.as_der().to_vec();
-        // let pwd = base64::encode(priv_key_bits);
-
-        Self {
-            pub_key_bits,
-            priv_key_bits,
-        }
+        Secret::new(KeyType::Ed25519)
     }
 }
 
@@ -60,6 +95,15 @@ impl Display for Secret {
     }
 }
 
+/// Only the scope, the private key is never printed.
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secret")
+            .field("scope", &crate::utils::to_addr(&self.pub_key_bits))
+            .finish_non_exhaustive()
+    }
+}
+
 impl From<String> for Secret {
     fn from(content: String) -> Self {
         let mut it = content.split("\n");
@@ -73,29 +117,360 @@ impl From<String> for Secret {
 }
 
 impl Secret {
-    pub fn encrypt_with_pub_key_bits(pub_key_bits: &[u8], message: &[u8]) -> Vec<u8> {
+    /// Whether the content of a secret file is encrypted with a passphrase.
+    pub fn is_encrypted(content: &str) -> bool {
+        content
+            .lines()
+            .nth(1)
+            .is_some_and(|line| line.starts_with(ENCRYPTED_PREFIX))
+    }
+
+    fn passphrase_key(passphrase: &str, salt: &[u8]) -> KVSResult<Vec<u8>> {
+        let mut key = vec![0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|error| KVSError::LogicError(format!("Argon2 Error: {}", error)))?;
+        Ok(key)
+    }
+
+    /// The content of a secret file with the private key encrypted by a key
+    /// derived from `passphrase`.
+    pub fn to_encrypted_string(&self, passphrase: &str) -> KVSResult<String> {
+        let salt = (0..16).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+        let nonce = (0..12).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+        let key = Secret::passphrase_key(passphrase, &salt)?;
+        let cipher = Aes256Gcm::new(Key::from_slice(&key));
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), &*self.priv_key_bits)?;
+        Ok(format!(
+            "{}\n{}{}:{}:{}",
+            base64::encode(&self.pub_key_bits),
+            ENCRYPTED_PREFIX,
+            base64::encode(salt),
+            base64::encode(nonce),
+            base64::encode(ciphertext)
+        ))
+    }
+
+    /// Read a secret file encrypted by `to_encrypted_string`.
+    pub fn from_encrypted_string(content: &str, passphrase: &str) -> KVSResult<Secret> {
+        let illegal = || KVSError::LogicError("Illegal secret file".to_string());
+        let mut lines = content.lines();
+        let pub_key_bits =
+            base64::decode(lines.next().ok_or_else(illegal)?).map_err(|_| illegal())?;
+        let fields = lines
+            .next()
+            .and_then(|line| line.strip_prefix(ENCRYPTED_PREFIX))
+            .ok_or_else(illegal)?
+            .split(':')
+            .map(base64::decode)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| illegal())?;
+        if fields.len() != 3 || fields[1].len() != 12 {
+            return Err(illegal());
+        }
+        let key = Secret::passphrase_key(passphrase, &fields[0])?;
+        let cipher = Aes256Gcm::new(Key::from_slice(&key));
+        let priv_key_bits = cipher
+            .decrypt(Nonce::from_slice(&fields[1]), &*fields[2])
+            .map_err(|_| KVSError::LogicError("Wrong passphrase".to_string()))?;
+        Ok(Secret {
+            pub_key_bits,
+            priv_key_bits,
+        })
+    }
+
+    /// A new identity of `key_type`.
+    pub fn new(key_type: KeyType) -> Secret {
+        match key_type {
+            KeyType::Rsa => Secret::new_rsa(),
+            KeyType::Ed25519 => Secret::from_ed25519_seed(&rand::random::<[u8; 32]>()),
+        }
+    }
+
+    fn new_rsa() -> Secret {
         let mut rng = OsRng;
-        let pub_key =
-            RsaPublicKey::from_public_key_der(pub_key_bits).expect("failed to parse pub key");
-        let enc_data = pub_key
-            .encrypt(&mut rng, PaddingScheme::new_pkcs1v15_encrypt(), message)
-            .expect("failed to encrypt");
-        enc_data
+        let bits = 1024;
+        let priv_key = RsaPrivateKey::new(&mut rng, bits).expect("failed to generate a priv key");
+        let pub_key = RsaPublicKey::from(&priv_key);
+        let pub_key_content = pub_key
+            .to_public_key_pem()
+            .expect("failed to get public key content");
+        let pem = pem::parse(pub_key_content).expect("failed to parse pub key content");
+        let pub_key_bits = pem.contents;
+
+        let priv_key_content = priv_key
+            .to_pkcs1_der()
+            .expect("failed to get priv key content");
+        let priv_key_bits = priv_key_content.as_der().to_vec();
+
+        Secret {
+            pub_key_bits,
+            priv_key_bits,
+        }
+    }
+
+    /// `[ED25519_TAG] ++ seed` as private key, `[ED25519_TAG] ++ ed25519 ++
+    /// x25519` as public key. The x25519 key is derived from the seed.
+    fn from_ed25519_seed(seed: &[u8; 32]) -> Secret {
+        let secret_key = ed25519_dalek::SecretKey::from_bytes(seed).expect("failed to parse seed");
+        let pub_key = ed25519_dalek::PublicKey::from(&secret_key);
+        let dh_pub_key = DHPublicKey::from(&x25519_secret(seed));
+        Secret {
+            pub_key_bits: [
+                &[ED25519_TAG][..],
+                pub_key.as_bytes(),
+                dh_pub_key.as_bytes(),
+            ]
+            .concat(),
+            priv_key_bits: [&[ED25519_TAG], &seed[..]].concat(),
+        }
+    }
+
+    /// The public key of the Ed25519 identity with the raw 32 byte Ed25519
+    /// key `ed25519`, its X25519 key is the Montgomery form of that point.
+    pub fn from_ed25519_pub_key(ed25519: &[u8]) -> KVSResult<Vec<u8>> {
+        let illegal = || KVSError::LogicError("Illegal public key".to_string());
+        let ed25519 = <[u8; 32]>::try_from(ed25519).map_err(|_| illegal())?;
+        let point = CompressedEdwardsY(ed25519)
+            .decompress()
+            .ok_or_else(illegal)?;
+        Ok([
+            &[ED25519_TAG][..],
+            &ed25519,
+            point.to_montgomery().as_bytes(),
+        ]
+        .concat())
+    }
+
+    /// The type of a public or private key.
+    pub fn key_type(key_bits: &[u8]) -> KeyType {
+        match key_bits.first() {
+            Some(&ED25519_TAG) => KeyType::Ed25519,
+            _ => KeyType::Rsa,
+        }
+    }
+
+    /// Whether `pub_key_bits` is a public key of a supported type.
+    pub fn is_pub_key(pub_key_bits: &[u8]) -> bool {
+        match Secret::key_type(pub_key_bits) {
+            KeyType::Rsa => pub_key_bits.len() == 162,
+            KeyType::Ed25519 => pub_key_bits.len() == 65,
+        }
+    }
+
+    /// The key that wrapped the `rand` of values saved as `Envelope::Legacy`.
+    pub fn legacy_wrap_key(&self) -> Vec<u8> {
+        match Secret::key_type(&self.priv_key_bits) {
+            KeyType::Rsa => self.priv_key_bits[..32].to_vec(),
+            KeyType::Ed25519 => sha256(&[b"kvs wrap key", &self.priv_key_bits[..]].concat()),
+        }
+    }
+
+    /// The private key as a PKCS#8 PEM document.
+    pub fn to_pkcs8_pem(&self) -> KVSResult<String> {
+        if let KeyType::Ed25519 = Secret::key_type(&self.priv_key_bits) {
+            return Ok(pem::encode(&pem::Pem {
+                tag: "PRIVATE KEY".to_string(),
+                contents: [&ED25519_PKCS8_PREFIX[..], &self.priv_key_bits[1..]].concat(),
+            }));
+        }
+        let priv_key = rsa_priv_key(&self.priv_key_bits)?;
+        let pem = priv_key
+            .to_pkcs8_pem()
+            .map_err(|error| KVSError::LogicError(format!("PKCS#8 Error: {}", error)))?;
+        Ok(pem.to_string())
+    }
+
+    /// Read a PKCS#8 (`PRIVATE KEY`, RSA or Ed25519) or PKCS#1 (`RSA PRIVATE
+    /// KEY`) PEM document.
+    pub fn from_pem(pem: &str) -> KVSResult<Secret> {
+        if let Ok(document) = pem::parse(pem) {
+            if let Some(seed) = document.contents.strip_prefix(&ED25519_PKCS8_PREFIX[..]) {
+                let seed = <[u8; 32]>::try_from(seed)?;
+                return Ok(Secret::from_ed25519_seed(&seed));
+            }
+        }
+        let priv_key = match RsaPrivateKey::from_pkcs8_pem(pem) {
+            Ok(priv_key) => priv_key,
+            Err(_) => RsaPrivateKey::from_pkcs1_pem(pem)
+                .map_err(|_| KVSError::LogicError("Illegal private key PEM".to_string()))?,
+        };
+        let pub_key_bits = RsaPublicKey::from(&priv_key)
+            .to_public_key_der()
+            .map_err(|error| KVSError::LogicError(format!("PKCS#8 Error: {}", error)))?
+            .as_ref()
+            .to_vec();
+        if pub_key_bits.len() != 162 {
+            return Err(KVSError::LogicError(
+                "Only 1024 bit RSA keys are supported".to_string(),
+            ));
+        }
+        let priv_key_bits = priv_key
+            .to_pkcs1_der()
+            .map_err(|error| KVSError::LogicError(format!("PKCS#1 Error: {}", error)))?
+            .as_der()
+            .to_vec();
+        Ok(Secret {
+            pub_key_bits,
+            priv_key_bits,
+        })
+    }
+
+    /// RSA PKCS#1 v1.5, or for Ed25519 identities an ephemeral X25519
+    /// exchange with the recipient followed by AES-GCM:
+    /// `ephemeral public key ++ nonce ++ ciphertext`.
+    pub fn encrypt_with_pub_key_bits(pub_key_bits: &[u8], message: &[u8]) -> KVSResult<Vec<u8>> {
+        if !Secret::is_pub_key(pub_key_bits) {
+            return Err(KVSError::LogicError("Illegal public key".to_string()));
+        }
+        if let KeyType::Ed25519 = Secret::key_type(pub_key_bits) {
+            let dh_pub_key = DHPublicKey::from(<[u8; 32]>::try_from(&pub_key_bits[33..65])?);
+            let (ephemeral_secret, ephemeral_pub_key) = key_pair();
+            let shared_secret = ephemeral_secret.diffie_hellman(&dh_pub_key);
+            let key = sha256(
+                &[
+                    &shared_secret.as_bytes()[..],
+                    ephemeral_pub_key.as_bytes(),
+                    dh_pub_key.as_bytes(),
+                ]
+                .concat(),
+            );
+            let nonce = rand::random::<[u8; 12]>();
+            let cipher = Aes256Gcm::new(Key::from_slice(&key));
+            let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), message)?;
+            return Ok([ephemeral_pub_key.as_bytes(), &nonce[..], &ciphertext].concat());
+        }
+        let mut rng = OsRng;
+        let pub_key = RsaPublicKey::from_public_key_der(pub_key_bits)
+            .map_err(|_| KVSError::LogicError("Illegal public key".to_string()))?;
+        Ok(pub_key.encrypt(&mut rng, PaddingScheme::new_pkcs1v15_encrypt(), message)?)
+    }
+
+    /// Encrypt a message of any size: a random AES-GCM key wrapped with
+    /// `encrypt_with_pub_key_bits`, written as `HYBRID_PREFIX ++ wrapped key
+    /// length (u16 BE) ++ wrapped key ++ nonce ++ ciphertext`.
+    pub fn hybrid_encrypt_with_pub_key_bits(
+        pub_key_bits: &[u8],
+        message: &[u8],
+    ) -> KVSResult<Vec<u8>> {
+        let key = rand::random::<[u8; 32]>();
+        let nonce = rand::random::<[u8; 12]>();
+        let wrapped_key = Secret::encrypt_with_pub_key_bits(pub_key_bits, &key)?;
+        let cipher = Aes256Gcm::new(Key::from_slice(&key));
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), message)?;
+        Ok([
+            HYBRID_PREFIX,
+            &(wrapped_key.len() as u16).to_be_bytes(),
+            &wrapped_key,
+            &nonce,
+            &ciphertext,
+        ]
+        .concat())
+    }
+
+    /// Decrypt `hybrid_encrypt_with_pub_key_bits` output, or a message
+    /// encrypted by `encrypt_with_pub_key_bits` alone.
+    pub fn hybrid_decrypt_with_priv_key_bits(
+        priv_key_bits: &[u8],
+        enc_data: &[u8],
+    ) -> KVSResult<Vec<u8>> {
+        let data = match enc_data.strip_prefix(HYBRID_PREFIX) {
+            Some(data) if data.len() >= 2 => data,
+            _ => return Secret::decrypt_width_priv_key_bits(priv_key_bits, enc_data),
+        };
+        let wrapped_key_length = u16::from_be_bytes([data[0], data[1]]) as usize;
+        if data.len() < 2 + wrapped_key_length + 12 {
+            return Err(KVSError::LogicError("Illegal ciphertext".to_string()));
+        }
+        let (wrapped_key, data) = data[2..].split_at(wrapped_key_length);
+        let (nonce, ciphertext) = data.split_at(12);
+        let key = Secret::decrypt_width_priv_key_bits(priv_key_bits, wrapped_key)?;
+        if key.len() != 32 {
+            return Err(KVSError::LogicError("Illegal ciphertext".to_string()));
+        }
+        let cipher = Aes256Gcm::new(Key::from_slice(&key));
+        Ok(cipher.decrypt(Nonce::from_slice(nonce), ciphertext)?)
     }
 
     pub fn decrypt_width_priv_key_bits(
         priv_key_bits: &[u8],
         enc_data: &[u8],
     ) -> KVSResult<Vec<u8>> {
-        let priv_key =
-            RsaPrivateKey::from_pkcs1_der(priv_key_bits).expect("failed to parse priv key");
+        if let KeyType::Ed25519 = Secret::key_type(priv_key_bits) {
+            if enc_data.len() < 44 {
+                return Err(KVSError::LogicError("Illegal ciphertext".to_string()));
+            }
+            let seed = <[u8; 32]>::try_from(&priv_key_bits[1..])?;
+            let dh_secret = x25519_secret(&seed);
+            let ephemeral_pub_key = DHPublicKey::from(<[u8; 32]>::try_from(&enc_data[..32])?);
+            let shared_secret = dh_secret.diffie_hellman(&ephemeral_pub_key);
+            let key = sha256(
+                &[
+                    &shared_secret.as_bytes()[..],
+                    ephemeral_pub_key.as_bytes(),
+                    DHPublicKey::from(&dh_secret).as_bytes(),
+                ]
+                .concat(),
+            );
+            let cipher = Aes256Gcm::new(Key::from_slice(&key));
+            return Ok(cipher.decrypt(Nonce::from_slice(&enc_data[32..44]), &enc_data[44..])?);
+        }
+        let priv_key = rsa_priv_key(priv_key_bits)?;
         // Decrypt
-        let dec_data = priv_key.decrypt(PaddingScheme::new_pkcs1v15_encrypt(), &enc_data)?;
+        let dec_data = priv_key.decrypt(PaddingScheme::new_pkcs1v15_encrypt(), enc_data)?;
         Ok(dec_data)
     }
+
+    pub fn sign_with_priv_key_bits(priv_key_bits: &[u8], message: &[u8]) -> KVSResult<Vec<u8>> {
+        if let KeyType::Ed25519 = Secret::key_type(priv_key_bits) {
+            let secret_key = ed25519_dalek::SecretKey::from_bytes(&priv_key_bits[1..])
+                .map_err(|_| KVSError::LogicError("Illegal private key".to_string()))?;
+            let pub_key = ed25519_dalek::PublicKey::from(&secret_key);
+            let signature =
+                ed25519_dalek::ExpandedSecretKey::from(&secret_key).sign(message, &pub_key);
+            return Ok(signature.to_bytes().to_vec());
+        }
+        let priv_key = rsa_priv_key(priv_key_bits)?;
+        let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+        Ok(priv_key.sign(padding, &sha256(message))?)
+    }
+
+    pub fn verify_with_pub_key_bits(pub_key_bits: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        if let KeyType::Ed25519 = Secret::key_type(pub_key_bits) {
+            use ed25519_dalek::Verifier;
+            let pub_key = match pub_key_bits
+                .get(1..33)
+                .map(ed25519_dalek::PublicKey::from_bytes)
+            {
+                Some(Ok(pub_key)) => pub_key,
+                _ => return false,
+            };
+            return match ed25519_dalek::Signature::try_from(signature) {
+                Ok(signature) => pub_key.verify(message, &signature).is_ok(),
+                Err(_) => false,
+            };
+        }
+        let pub_key = match RsaPublicKey::from_public_key_der(pub_key_bits) {
+            Ok(pub_key) => pub_key,
+            Err(_) => return false,
+        };
+        let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+        pub_key.verify(padding, &sha256(message), signature).is_ok()
+    }
+}
+
+fn rsa_priv_key(priv_key_bits: &[u8]) -> KVSResult<RsaPrivateKey> {
+    RsaPrivateKey::from_pkcs1_der(priv_key_bits)
+        .map_err(|_| KVSError::LogicError("Illegal private key".to_string()))
 }
 
-use x25519_dalek::{EphemeralSecret, PublicKey as DHPublicKey};
+/// The X25519 key of an Ed25519 identity, as libsodium derives it.
+fn x25519_secret(seed: &[u8; 32]) -> StaticSecret {
+    let hash = sha2::Sha512::digest(seed);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hash[..32]);
+    StaticSecret::from(key)
+}
 
 pub fn key_pair() -> (EphemeralSecret, DHPublicKey) {
     let sk = EphemeralSecret::new(rand_core::OsRng);
@@ -113,7 +488,7 @@ pub fn to_pub_key(bytes: [u8; 32]) -> DHPublicKey {
 
 #[cfg(test)]
 mod test {
-    use super::key_pair;
+    use super::{key_pair, KeyType, Secret, ED25519_TAG};
 
     #[test]
     fn test_dh() {
@@ -124,5 +499,107 @@ mod test {
             b_sk.diffie_hellman(&a_pk).as_bytes()
         );
     }
-}
 
+    #[test]
+    fn test_hybrid_encryption() {
+        let message = vec![7u8; 4096];
+        for key_type in [KeyType::Rsa, KeyType::Ed25519] {
+            let secret = Secret::new(key_type);
+            let encrypted =
+                Secret::hybrid_encrypt_with_pub_key_bits(&secret.pub_key_bits, &message).unwrap();
+            let decrypted =
+                Secret::hybrid_decrypt_with_priv_key_bits(&secret.priv_key_bits, &encrypted);
+            assert_eq!(decrypted.unwrap(), message);
+            // the format of `kvs en` before hybrid encryption
+            let encrypted =
+                Secret::encrypt_with_pub_key_bits(&secret.pub_key_bits, b"kvs").unwrap();
+            let decrypted =
+                Secret::hybrid_decrypt_with_priv_key_bits(&secret.priv_key_bits, &encrypted);
+            assert_eq!(decrypted.unwrap(), b"kvs");
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        let secret = Secret::default();
+        let signature = Secret::sign_with_priv_key_bits(&secret.priv_key_bits, b"kvs").unwrap();
+        assert!(Secret::verify_with_pub_key_bits(
+            &secret.pub_key_bits,
+            b"kvs",
+            &signature
+        ));
+        assert!(!Secret::verify_with_pub_key_bits(
+            &secret.pub_key_bits,
+            b"kvs!",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_secret() {
+        let secret = Secret::default();
+        let content = secret.to_encrypted_string("correct horse").unwrap();
+        assert!(Secret::is_encrypted(&content));
+        assert!(!Secret::is_encrypted(&secret.to_string()));
+        let decrypted = Secret::from_encrypted_string(&content, "correct horse").unwrap();
+        assert_eq!(decrypted.priv_key_bits, secret.priv_key_bits);
+        assert!(Secret::from_encrypted_string(&content, "battery staple").is_err());
+    }
+
+    #[test]
+    fn test_pem() {
+        for key_type in [KeyType::Rsa, KeyType::Ed25519] {
+            let secret = Secret::new(key_type);
+            let imported = Secret::from_pem(&secret.to_pkcs8_pem().unwrap()).unwrap();
+            assert_eq!(imported.pub_key_bits, secret.pub_key_bits);
+            assert_eq!(imported.priv_key_bits, secret.priv_key_bits);
+        }
+        assert!(Secret::from_pem("not a pem").is_err());
+        // a damaged RSA key on disk is an error, not a panic
+        let broken = [0x30, 0x00];
+        assert!(Secret::decrypt_width_priv_key_bits(&broken, b"kvs").is_err());
+        assert!(Secret::sign_with_priv_key_bits(&broken, b"kvs").is_err());
+    }
+
+    #[test]
+    fn test_ed25519_identity() {
+        let secret = Secret::new(KeyType::Ed25519);
+        assert!(Secret::is_pub_key(&secret.pub_key_bits));
+        assert_eq!(Secret::key_type(&secret.pub_key_bits), KeyType::Ed25519);
+        assert_eq!(
+            Secret::from_ed25519_pub_key(&secret.pub_key_bits[1..33]).unwrap(),
+            secret.pub_key_bits
+        );
+        assert!(Secret::from_ed25519_pub_key(&secret.pub_key_bits).is_err());
+
+        let encrypted = Secret::encrypt_with_pub_key_bits(&secret.pub_key_bits, b"kvs").unwrap();
+        let decrypted = Secret::decrypt_width_priv_key_bits(&secret.priv_key_bits, &encrypted);
+        assert_eq!(decrypted.unwrap(), b"kvs");
+        let other = Secret::new(KeyType::Ed25519);
+        assert!(Secret::decrypt_width_priv_key_bits(&other.priv_key_bits, &encrypted).is_err());
+
+        let signature = Secret::sign_with_priv_key_bits(&secret.priv_key_bits, b"kvs").unwrap();
+        assert!(Secret::verify_with_pub_key_bits(
+            &secret.pub_key_bits,
+            b"kvs",
+            &signature
+        ));
+        assert!(!Secret::verify_with_pub_key_bits(
+            &other.pub_key_bits,
+            b"kvs",
+            &signature
+        ));
+
+        let imported = Secret::from_pem(&secret.to_pkcs8_pem().unwrap()).unwrap();
+        assert_eq!(imported.pub_key_bits, secret.pub_key_bits);
+        assert_eq!(secret.legacy_wrap_key().len(), 32);
+    }
+
+    #[test]
+    fn test_encrypt_with_illegal_pub_key() {
+        for pub_key_bits in [&[][..], &[ED25519_TAG][..], b"AQ", &[0u8; 162][..]] {
+            assert!(Secret::encrypt_with_pub_key_bits(pub_key_bits, b"kvs").is_err());
+            assert!(Secret::hybrid_encrypt_with_pub_key_bits(pub_key_bits, b"kvs").is_err());
+        }
+    }
+}
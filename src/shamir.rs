@@ -0,0 +1,260 @@
+use std::{fmt::Display, str::FromStr};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    errors::{KVSError, KVSResult},
+    utils::{is_scope_addr, to_u8str},
+};
+
+/// Multiply in GF(2^8) with the AES polynomial.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80;
+        a <<= 1;
+        if carry != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 is the inverse of a in GF(2^8)
+    let mut result = 1;
+    for _ in 0..254 {
+        result = gf_mul(result, a);
+    }
+    result
+}
+
+/// Split `secret` into `count` shares, any `threshold` of them rebuild it.
+/// A share is its x coordinate followed by one y byte per secret byte.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> KVSResult<Vec<Vec<u8>>> {
+    if threshold == 0 || threshold > count {
+        return Err(KVSError::LogicError(format!(
+            "The threshold must be between 1 and {}.",
+            count
+        )));
+    }
+    let mut shares = (1..=count).map(|x| vec![x]).collect::<Vec<_>>();
+    for byte in secret {
+        let mut coefficients = vec![*byte];
+        coefficients.extend((1..threshold).map(|_| rand::random::<u8>()));
+        for share in shares.iter_mut() {
+            let x = share[0];
+            // Horner's rule
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |y, coefficient| gf_mul(y, x) ^ coefficient);
+            share.push(y);
+        }
+    }
+    Ok(shares)
+}
+
+/// Rebuild the secret from at least `threshold` shares of `split`.
+pub fn combine(shares: &[Vec<u8>]) -> KVSResult<Vec<u8>> {
+    let illegal = || KVSError::LogicError("Illegal shares".to_string());
+    let length = shares.first().ok_or_else(illegal)?.len();
+    if length == 0
+        || shares
+            .iter()
+            .any(|share| share.len() != length || share[0] == 0)
+    {
+        return Err(illegal());
+    }
+    let xs = shares.iter().map(|share| share[0]).collect::<Vec<_>>();
+    if (1..xs.len()).any(|i| xs[..i].contains(&xs[i])) {
+        return Err(illegal());
+    }
+    // Lagrange basis polynomials at x = 0
+    let basis = xs
+        .iter()
+        .map(|xi| {
+            xs.iter()
+                .filter(|xj| *xj != xi)
+                .fold(1, |basis, xj| gf_mul(basis, gf_mul(*xj, gf_inv(xj ^ xi))))
+        })
+        .collect::<Vec<_>>();
+    Ok((1..length)
+        .map(|i| {
+            shares
+                .iter()
+                .zip(basis.iter())
+                .fold(0, |secret, (share, basis)| {
+                    secret ^ gf_mul(share[i], *basis)
+                })
+        })
+        .collect())
+}
+
+/// Stored as the private key `<key>.shamir` shared with the trustees next
+/// to the shares, share `i` (from 1) is the private key `<key>.shamir.<i>`
+/// readable by `trustees[i - 1]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShamirManifest {
+    pub threshold: u8,
+    pub commitment: Commitment,
+    pub trustees: Vec<String>,
+}
+
+/// Checks the rebuilt value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Commitment {
+    /// The hex HMAC-SHA256 of the value under a random key, the shares
+    /// rebuild the key followed by the value.
+    Hmac(String),
+}
+
+const COMMITMENT_KEY_LENGTH: usize = 32;
+
+fn hmac_sha256(key: &[u8], value: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(value);
+    to_u8str(&mac.finalize().into_bytes())
+}
+
+impl Commitment {
+    /// The commitment to `value` and the secret to split for it.
+    pub fn new(value: &[u8]) -> (Commitment, Vec<u8>) {
+        let key = rand::random::<[u8; COMMITMENT_KEY_LENGTH]>();
+        let commitment = Commitment::Hmac(hmac_sha256(&key, value));
+        (commitment, [&key[..], value].concat())
+    }
+
+    /// The value in the secret rebuilt from the shares, when it is the one
+    /// committed to.
+    pub fn open(&self, secret: &[u8]) -> Option<Vec<u8>> {
+        let value = match self {
+            Commitment::Hmac(mac) if secret.len() >= COMMITMENT_KEY_LENGTH => {
+                let (key, value) = secret.split_at(COMMITMENT_KEY_LENGTH);
+                match hmac_sha256(key, value) == *mac {
+                    true => value,
+                    false => return None,
+                }
+            }
+            _ => return None,
+        };
+        Some(value.to_vec())
+    }
+}
+
+impl ShamirManifest {
+    pub fn manifest_key(key: &str) -> String {
+        format!("{}.shamir", key)
+    }
+
+    pub fn share_key(key: &str, index: usize) -> String {
+        format!("{}.shamir.{}", key, index)
+    }
+
+    /// Where a trustee releases its share to the combiner, named after the
+    /// commitment rather than the owner's scope, which a rotation changes.
+    pub fn released_share_key(&self, key: &str, index: usize) -> String {
+        let Commitment::Hmac(mac) = &self.commitment;
+        format!("{}/{}", mac, ShamirManifest::share_key(key, index))
+    }
+}
+
+impl Display for ShamirManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "threshold: {}", self.threshold)?;
+        match &self.commitment {
+            Commitment::Hmac(mac) => writeln!(f, "hmac: {}", mac)?,
+        }
+        for (i, trustee) in self.trustees.iter().enumerate() {
+            writeln!(f, "share {}: {}", i + 1, trustee)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ShamirManifest {
+    type Err = KVSError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let illegal = || KVSError::LogicError("Illegal shamir manifest".to_string());
+        let mut lines = text.lines();
+        let threshold = lines
+            .next()
+            .and_then(|line| line.strip_prefix("threshold: "))
+            .and_then(|threshold| threshold.parse::<u8>().ok())
+            .ok_or_else(illegal)?;
+        let commitment = match lines.next().and_then(|line| line.split_once(": ")) {
+            Some(("hmac", mac)) => Commitment::Hmac(mac.to_string()),
+            _ => return Err(illegal()),
+        };
+        let mut trustees = vec![];
+        for (i, line) in lines.enumerate() {
+            match line.split_once(": ") {
+                Some((share, trustee))
+                    if share == format!("share {}", i + 1) && is_scope_addr(trustee) =>
+                {
+                    trustees.push(trustee.to_string())
+                }
+                _ => return Err(illegal()),
+            }
+        }
+        if threshold == 0 || threshold as usize > trustees.len() {
+            return Err(illegal());
+        }
+        Ok(ShamirManifest {
+            threshold,
+            commitment,
+            trustees,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{combine, split, Commitment, ShamirManifest};
+
+    #[test]
+    fn test_split_combine() {
+        let secret = b"root:hunter2".to_vec();
+        let shares = split(&secret, 3, 5).unwrap();
+        assert_eq!(combine(&shares[..3]).unwrap(), secret);
+        assert_eq!(combine(&shares[2..]).unwrap(), secret);
+        assert_eq!(
+            combine(&[shares[4].clone(), shares[0].clone(), shares[2].clone()]).unwrap(),
+            secret
+        );
+        assert_ne!(combine(&shares[..2]).unwrap(), secret);
+        assert!(split(&secret, 6, 5).is_err());
+
+        let manifest = ShamirManifest {
+            threshold: 2,
+            commitment: Commitment::Hmac("00".repeat(32)),
+            trustees: vec![
+                format!("0x{}", "a".repeat(40)),
+                format!("0x{}", "b".repeat(40)),
+            ],
+        };
+        assert_eq!(
+            manifest.to_string().parse::<ShamirManifest>().unwrap(),
+            manifest
+        );
+    }
+
+    #[test]
+    fn test_commitment() {
+        let (commitment, secret) = Commitment::new(b"root:hunter2");
+        assert_eq!(commitment.open(&secret).unwrap(), b"root:hunter2");
+        let mut forged = secret.clone();
+        forged[40] ^= 1;
+        assert_eq!(commitment.open(&forged), None);
+        assert_eq!(commitment.open(&secret[..16]), None);
+        // the value alone does not give away the commitment
+        let (other, _) = Commitment::new(b"root:hunter2");
+        assert_ne!(commitment, other);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::{
+    errors::{KVSError, KVSResult},
+    secret::{KeyType, Secret},
+    utils::to_addr,
+};
+
+const VERSION: &str = "1";
+
+/// A detached signature made by `kvs sign`, written as `name: value` lines:
+///
+/// ```text
+/// kvs-signature: 1
+/// scope: 0x<ripemd160(sha256(pub key)) in hex>
+/// algorithm: rsa-pkcs1v15-sha256 | ed25519
+/// pub-key: <base64 DER SubjectPublicKeyInfo | base64 raw 32 byte Ed25519 key>
+/// signature: <base64 signature of the content>
+/// ```
+///
+/// `pub_key` holds the kvs public key, the scope is the hash of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetachedSignature {
+    pub scope: String,
+    pub algorithm: String,
+    pub pub_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+fn algorithm(pub_key_bits: &[u8]) -> &'static str {
+    match Secret::key_type(pub_key_bits) {
+        KeyType::Rsa => "rsa-pkcs1v15-sha256",
+        KeyType::Ed25519 => "ed25519",
+    }
+}
+
+impl DetachedSignature {
+    pub fn sign(secret: &Secret, content: &[u8]) -> KVSResult<Self> {
+        Ok(DetachedSignature {
+            scope: to_addr(&secret.pub_key_bits),
+            algorithm: algorithm(&secret.pub_key_bits).to_string(),
+            pub_key: secret.pub_key_bits.clone(),
+            signature: Secret::sign_with_priv_key_bits(&secret.priv_key_bits, content)?,
+        })
+    }
+
+    /// Check the signature of `content` and that the public key belongs to
+    /// the scope.
+    pub fn verify(&self, content: &[u8]) -> KVSResult<()> {
+        if !Secret::is_pub_key(&self.pub_key) || self.algorithm != algorithm(&self.pub_key) {
+            return Err(KVSError::LogicError(format!(
+                "Illegal signature algorithm: {}",
+                self.algorithm
+            )));
+        }
+        if to_addr(&self.pub_key) != self.scope {
+            return Err(KVSError::LogicError(format!(
+                "The public key of {} does not match its scope",
+                self.scope
+            )));
+        }
+        if !Secret::verify_with_pub_key_bits(&self.pub_key, content, &self.signature) {
+            return Err(KVSError::LogicError(format!(
+                "Bad signature from {}",
+                self.scope
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Display for DetachedSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "kvs-signature: {}", VERSION)?;
+        writeln!(f, "scope: {}", self.scope)?;
+        writeln!(f, "algorithm: {}", self.algorithm)?;
+        let pub_key = match Secret::key_type(&self.pub_key) {
+            KeyType::Ed25519 => self.pub_key.get(1..33).unwrap_or_default(),
+            KeyType::Rsa => &self.pub_key,
+        };
+        writeln!(f, "pub-key: {}", base64::encode(pub_key))?;
+        writeln!(f, "signature: {}", base64::encode(&self.signature))
+    }
+}
+
+impl FromStr for DetachedSignature {
+    type Err = KVSError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let illegal = |reason: &str| KVSError::LogicError(format!("Illegal signature: {}", reason));
+        let field = |name: &str| {
+            text.lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(field, _)| field.trim() == name)
+                .map(|(_, value)| value.trim().to_string())
+                .ok_or_else(|| illegal(&format!("missing {}", name)))
+        };
+        if field("kvs-signature")? != VERSION {
+            return Err(illegal("unsupported version"));
+        }
+        let algorithm = field("algorithm")?;
+        let mut pub_key = base64::decode(field("pub-key")?).map_err(|_| illegal("pub-key"))?;
+        // the raw Ed25519 key, older versions wrote the kvs public key
+        if algorithm == "ed25519" && pub_key.len() == 32 {
+            pub_key = Secret::from_ed25519_pub_key(&pub_key)?;
+        }
+        Ok(DetachedSignature {
+            scope: field("scope")?,
+            algorithm,
+            pub_key,
+            signature: base64::decode(field("signature")?).map_err(|_| illegal("signature"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DetachedSignature;
+    use crate::secret::{KeyType, Secret};
+
+    #[test]
+    fn test_detached_signature() {
+        for key_type in [KeyType::Rsa, KeyType::Ed25519] {
+            let secret = Secret::new(key_type);
+            let signature = DetachedSignature::sign(&secret, b"kvs").unwrap();
+            let text = signature.to_string();
+            let parsed = text.parse::<DetachedSignature>().unwrap();
+            assert_eq!(parsed, signature);
+            if key_type == KeyType::Ed25519 {
+                let pub_key = base64::encode(&secret.pub_key_bits[1..33]);
+                assert!(text.contains(&format!("pub-key: {}\n", pub_key)));
+            }
+            assert!(parsed.verify(b"kvs").is_ok());
+            assert!(parsed.verify(b"kvs!").is_err());
+
+            let other = Secret::new(key_type);
+            let forged = DetachedSignature {
+                pub_key: other.pub_key_bits.clone(),
+                ..parsed
+            };
+            assert!(forged.verify(b"kvs").is_err());
+        }
+    }
+}
@@ -1,15 +1,13 @@
 use serde::{Deserialize, Serialize};
 
-use crate::errors::KVSResult;
+use crate::errors::{KVSError, KVSResult};
 
 pub trait Session {
     fn read_vec(&mut self) -> KVSResult<Vec<u8>>;
 
     fn write_vec(&mut self, payload: &[u8]) -> KVSResult<()>;
 
-    fn write<T: ?Sized>(&mut self, payload: &T) -> KVSResult<()>
-    where
-        T: serde::Serialize;
+    fn write<T: ?Sized + serde::Serialize>(&mut self, payload: &T) -> KVSResult<()>;
 }
 pub trait KVSAction<R: serde::Serialize> {
     fn serve(&mut self, session: &mut impl Session) -> KVSResult<R>;
@@ -20,6 +18,7 @@ pub trait KVSAction<R: serde::Serialize> {
         Ok(data)
     }
 
+    #[allow(dead_code)]
     fn request_serialize(&mut self, session: &mut impl Session) -> KVSResult<Vec<u8>> {
         let data = bincode::serialize(&KVPayloadResult::Ok(self.request(session)?))?;
         Ok(data)
@@ -32,7 +31,22 @@ pub trait KVSAction<R: serde::Serialize> {
 //     Ok(T),
 // }
 
-pub type KVPayloadResult<T> = Result<T, String>;
+pub type KVPayloadResult<T> = Result<T, RemoteError>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RemoteError {
+    Logic(String),
+    RateLimited { retry_after: u64 },
+}
+
+impl From<RemoteError> for KVSError {
+    fn from(error: RemoteError) -> Self {
+        match error {
+            RemoteError::Logic(error) => KVSError::LogicError(error),
+            RemoteError::RateLimited { retry_after } => KVSError::RateLimited(retry_after),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[repr(u8)]
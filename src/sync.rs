@@ -0,0 +1,523 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actions::{FileFilter, KeyMeta, LocalFileMeta},
+    client::{KeyAddress, KvsClient},
+    config::get_or_create_sync_state_dir,
+    errors::{KVSError, KVSResult},
+    utils::{detect_mime, sha256, to_addr, to_u8str},
+};
+
+#[derive(Debug, Default)]
+pub struct SyncOptions {
+    /// Prepended to the relative path of every file to make its key.
+    pub prefix: String,
+    pub public: bool,
+    pub jobs: usize,
+    /// Delete the remote keys under the prefix missing locally.
+    pub delete: bool,
+    /// Only work out what would change.
+    pub dry_run: bool,
+    pub filter: FileFilter,
+}
+
+enum Task {
+    Upload(String, String),
+    Delete(String),
+}
+
+enum Synced {
+    Created,
+    Updated,
+    Unchanged,
+    Deleted,
+}
+
+/// The keys changed by a sync or a pull, or that would be with `dry_run`.
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub deleted: Vec<String>,
+    pub unchanged: usize,
+    pub failed: Vec<(String, KVSError)>,
+}
+
+/// What the last sync of a directory saw, kept in the profile.
+#[derive(Serialize, Deserialize, Default)]
+struct SyncState {
+    /// The `ListStampAction` reply `remote` was listed after.
+    stamp: Vec<u8>,
+    remote: HashMap<String, RemoteFile>,
+    files: HashMap<String, LocalFile>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RemoteFile {
+    original_hash: Vec<u8>,
+    public: bool,
+}
+
+/// A file is taken as unchanged while its size and mtime are.
+#[derive(Serialize, Deserialize, Clone)]
+struct LocalFile {
+    size: u64,
+    modified: u128,
+    original_hash: Vec<u8>,
+}
+
+impl SyncState {
+    /// One state per repository, scope, directory and prefix.
+    fn path(client: &KvsClient, path: &str, prefix: &str) -> KVSResult<PathBuf> {
+        let target_path =
+            relative_path::RelativePath::new(path).to_logical_path(std::env::current_dir()?);
+        let id = format!(
+            "{}\n{}\n{}\n{}",
+            client.repository(),
+            to_addr(&client.identity().pub_key_bits),
+            target_path.display(),
+            prefix
+        );
+        Ok(get_or_create_sync_state_dir()?.join(to_u8str(&sha256(id.as_bytes()))))
+    }
+
+    /// An empty state when there is none or it can not be read.
+    fn load(path: &Path) -> SyncState {
+        std::fs::read(path)
+            .ok()
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Upload the files under `path` that are missing or changed on the remote,
+/// `jobs` at a time. Files unchanged since the last sync are not read, and
+/// the remote list is only fetched when it changed since then.
+pub fn sync_dir(client: &KvsClient, path: &str, options: &SyncOptions) -> KVSResult<SyncSummary> {
+    let started = modified_nanos(SystemTime::now());
+    let prefix = options.prefix.trim_end_matches('/');
+    tracing::info!("analysis local files");
+    let files = LocalFileMeta::get_all_files(path, &options.filter)?
+        .into_iter()
+        .map(|(name, path)| (format!("{}{}", prefix, name), path))
+        .collect::<Vec<_>>();
+    let state_path = SyncState::path(client, path, prefix)?;
+    let state = SyncState::load(&state_path);
+    // older servers and tokens limited to some keys can not stamp the list
+    let stamp = client.list_stamp().unwrap_or_else(|error| {
+        tracing::debug!("list stamp: {}", error);
+        vec![]
+    });
+    let mut remote = match !stamp.is_empty() && stamp == state.stamp {
+        true => {
+            tracing::info!("remote files unchanged since the last sync");
+            state.remote
+        }
+        false => {
+            tracing::info!("analysis remote files");
+            client
+                .list(None)?
+                .into_iter()
+                .map(|meta| {
+                    let remote_file = RemoteFile {
+                        original_hash: meta.original_hash,
+                        public: meta.rand.is_none(),
+                    };
+                    (meta.name, remote_file)
+                })
+                .collect::<HashMap<_, _>>()
+        }
+    };
+
+    let mut tasks = vec![];
+    if options.delete {
+        let local = files.iter().map(|(name, _)| name).collect::<HashSet<_>>();
+        let synced_prefix = format!("{}/", prefix);
+        tasks.extend(
+            remote
+                .keys()
+                .filter(|name| name.starts_with(&synced_prefix) && !local.contains(name))
+                // keep the keys of the ignored files
+                .filter(|name| !Path::new(path).join(&name[synced_prefix.len()..]).exists())
+                .map(|name| Task::Delete(name.clone())),
+        );
+    }
+    tasks.extend(
+        files
+            .into_iter()
+            .map(|(name, path)| Task::Upload(name, path)),
+    );
+
+    let seen = Mutex::new(HashMap::new());
+    let summary = run_tasks(tasks, options.jobs, |task| match task {
+        Task::Upload(name, path) => {
+            let cached = state.files.get(&name);
+            let synced =
+                sync_file(client, &remote, cached, &name, &path, options).map(|(synced, file)| {
+                    if let Some(file) = file {
+                        seen.lock().unwrap().insert(name.clone(), file);
+                    }
+                    synced
+                });
+            (name, synced)
+        }
+        Task::Delete(name) => {
+            let synced = match options.dry_run {
+                true => Ok(Synced::Deleted),
+                false => client.delete(&name).map(|_| Synced::Deleted),
+            };
+            (name, synced)
+        }
+    });
+
+    if !options.dry_run {
+        let mut files = seen.into_inner().unwrap();
+        for name in summary.created.iter().chain(summary.updated.iter()) {
+            let remote_file = RemoteFile {
+                original_hash: files[name].original_hash.clone(),
+                public: options.public,
+            };
+            remote.insert(name.clone(), remote_file);
+        }
+        for name in summary.deleted.iter() {
+            remote.remove(name);
+        }
+        // a file changed again within its mtime tick would look unchanged
+        files.retain(|_, file| file.modified + 1_000_000_000 < started);
+        let state = SyncState {
+            stamp,
+            remote,
+            files,
+        };
+        std::fs::write(&state_path, bincode::serialize(&state)?)?;
+    }
+    Ok(summary)
+}
+
+/// Write the keys under `source`, `prefix`, `scope:prefix` or
+/// `team:<name>:prefix`, into `dir` with the rest of their names as paths.
+/// Files already holding the value are not downloaded again, `no_verify`
+/// skips the check of the publisher signature of public values.
+pub fn pull_dir(
+    client: &KvsClient,
+    source: &str,
+    dir: &str,
+    jobs: usize,
+    no_verify: bool,
+) -> KVSResult<SyncSummary> {
+    let (namespace, address, prefix) = match KeyAddress::parse(source) {
+        KeyAddress::Own(prefix) => (None, String::new(), prefix),
+        KeyAddress::Scope(scope, prefix) => (Some(scope.clone()), format!("{}:", scope), prefix),
+        KeyAddress::Team(team, prefix) => (
+            Some(format!("team:{}", team)),
+            format!("team:{}:", team),
+            prefix,
+        ),
+    };
+    let prefix = prefix.trim_end_matches('/');
+    tracing::info!("analysis remote files");
+    let metas = client
+        .list(namespace.as_deref())?
+        .into_iter()
+        .filter(|meta| match prefix.is_empty() {
+            true => true,
+            false => matches!(meta.name.strip_prefix(prefix), Some(rest) if rest.starts_with('/')),
+        })
+        .collect::<Vec<_>>();
+    Ok(run_tasks(metas, jobs, |meta| {
+        let relative = meta.name[prefix.len()..].trim_start_matches('/');
+        let synced = pull_file(
+            client,
+            &format!("{}{}", address, meta.name),
+            relative,
+            dir,
+            &meta,
+            no_verify,
+        );
+        (meta.name, synced)
+    }))
+}
+
+/// Run `task` on every item, `jobs` at a time.
+fn run_tasks<T: Send>(
+    tasks: Vec<T>,
+    jobs: usize,
+    task: impl Fn(T) -> (String, KVSResult<Synced>) + Sync,
+) -> SyncSummary {
+    let progress = ProgressBar::new(tasks.len() as u64);
+    let queue = Mutex::new(tasks.into_iter());
+    let summary = Mutex::new(SyncSummary::default());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let (name, synced) = match next {
+                    Some(next) => task(next),
+                    None => break,
+                };
+                let mut summary = summary.lock().unwrap();
+                match synced {
+                    Ok(Synced::Created) => summary.created.push(name),
+                    Ok(Synced::Updated) => summary.updated.push(name),
+                    Ok(Synced::Deleted) => summary.deleted.push(name),
+                    Ok(Synced::Unchanged) => summary.unchanged += 1,
+                    Err(error) => summary.failed.push((name, error)),
+                }
+                progress.inc(1);
+            });
+        }
+    });
+    progress.finish_and_clear();
+    let mut summary = summary.into_inner().unwrap();
+    summary.created.sort();
+    summary.updated.sort();
+    summary.deleted.sort();
+    summary
+}
+
+fn modified_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Sync one file, with what is now known of it unless it was not read.
+fn sync_file(
+    client: &KvsClient,
+    remote: &HashMap<String, RemoteFile>,
+    cached: Option<&LocalFile>,
+    name: &str,
+    path: &str,
+    options: &SyncOptions,
+) -> KVSResult<(Synced, Option<LocalFile>)> {
+    let public = options.public;
+    let metadata = std::fs::metadata(path)?;
+    let (size, modified) = (metadata.len(), modified_nanos(metadata.modified()?));
+    let remote_file = remote.get(name);
+    if let (Some(cached), Some(remote_file)) = (cached, remote_file) {
+        if cached.size == size
+            && cached.modified == modified
+            && cached.original_hash == remote_file.original_hash
+            && remote_file.public == public
+        {
+            return Ok((Synced::Unchanged, Some(cached.clone())));
+        }
+    }
+    if remote_file.is_none() && options.dry_run {
+        return Ok((Synced::Created, None));
+    }
+    let (local, value) = LocalFileMeta::read(name, path)?;
+    let synced = match remote_file {
+        None => {
+            client.create(name, &value, public, &detect_mime(name, &value))?;
+            Synced::Created
+        }
+        Some(remote_file)
+            if remote_file.original_hash == local.original_hash && remote_file.public == public =>
+        {
+            Synced::Unchanged
+        }
+        Some(_) => {
+            if !options.dry_run {
+                client.update(name, &value, public, &detect_mime(name, &value))?;
+            }
+            Synced::Updated
+        }
+    };
+    let file = LocalFile {
+        size,
+        modified,
+        original_hash: local.original_hash,
+    };
+    Ok((synced, Some(file)))
+}
+
+fn pull_file(
+    client: &KvsClient,
+    key: &str,
+    relative: &str,
+    dir: &str,
+    meta: &KeyMeta,
+    no_verify: bool,
+) -> KVSResult<Synced> {
+    // a key name must not lead out of `dir`
+    let is_relative = Path::new(relative)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if relative.is_empty() || !is_relative {
+        return Err(KVSError::LogicError(format!(
+            "The key: `{}` is not a relative path.",
+            meta.name
+        )));
+    }
+    let path = Path::new(dir).join(relative);
+    let exists = path.exists();
+    if exists && sha256(&std::fs::read(&path)?) == meta.original_hash {
+        return Ok(Synced::Unchanged);
+    }
+    let (value, _) = match no_verify {
+        true => client.read_unverified(key)?,
+        false => client.read(key)?,
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, value)?;
+    match exists {
+        true => Ok(Synced::Updated),
+        false => Ok(Synced::Created),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::Path,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::{pull_dir, pull_file, sync_dir, SyncOptions, SyncState};
+    use crate::{
+        actions::FileFilter,
+        client::KvsClient,
+        kv_server::test::{test_dir, test_repository},
+        secret::{KeyType, Secret},
+    };
+
+    fn remote_names(client: &KvsClient) -> Vec<String> {
+        let mut names = client
+            .list(None)
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_sync_delete() {
+        let client = KvsClient::connect(&test_repository(), Secret::new(KeyType::Ed25519)).unwrap();
+        let dir = test_dir("sync_delete");
+        std::fs::write(Path::new(&dir).join("kept.txt"), "kept").unwrap();
+        std::fs::write(Path::new(&dir).join("ignored.tmp"), "ignored").unwrap();
+        for name in [
+            "docs/kept.txt",
+            "docs/gone.txt",
+            "docs/ignored.tmp",
+            "docs-old/gone.txt",
+        ] {
+            client.create(name, b"remote", false, "text/plain").unwrap();
+        }
+        let mut options = SyncOptions {
+            prefix: "docs/".to_string(),
+            jobs: 2,
+            delete: true,
+            dry_run: true,
+            filter: FileFilter {
+                exclude: vec!["*.tmp".to_string()],
+                ..FileFilter::default()
+            },
+            ..SyncOptions::default()
+        };
+
+        let summary = sync_dir(&client, &dir, &options).unwrap();
+        assert_eq!(summary.deleted, vec!["docs/gone.txt"]);
+        assert_eq!(summary.updated, vec!["docs/kept.txt"]);
+        assert!(summary.failed.is_empty());
+        // a dry run changes neither the remote nor the sync state
+        let state_path = SyncState::path(&client, &dir, "docs").unwrap();
+        assert!(!state_path.exists());
+        assert_eq!(client.read("docs/kept.txt").unwrap().0, b"remote");
+        assert_eq!(remote_names(&client).len(), 4);
+
+        options.dry_run = false;
+        let summary = sync_dir(&client, &dir, &options).unwrap();
+        assert_eq!(summary.deleted, vec!["docs/gone.txt"]);
+        assert!(state_path.exists());
+        assert_eq!(client.read("docs/kept.txt").unwrap().0, b"kept");
+        assert_eq!(
+            remote_names(&client),
+            vec!["docs-old/gone.txt", "docs/ignored.tmp", "docs/kept.txt"]
+        );
+    }
+
+    #[test]
+    fn test_pull_relative_paths() {
+        let client = KvsClient::connect(&test_repository(), Secret::new(KeyType::Ed25519)).unwrap();
+        let dir = test_dir("pull");
+        let out = format!("{}/out", dir);
+        for name in [
+            "pulled/a/ok.txt",
+            "pulled/../escape.txt",
+            "pulled/a/../../escape.txt",
+        ] {
+            client.create(name, b"pulled", false, "text/plain").unwrap();
+        }
+        let summary = pull_dir(&client, "pulled", &out, 2, false).unwrap();
+        assert_eq!(summary.created, vec!["pulled/a/ok.txt"]);
+        assert_eq!(summary.failed.len(), 2);
+        assert_eq!(
+            std::fs::read(Path::new(&out).join("a/ok.txt")).unwrap(),
+            b"pulled"
+        );
+        assert!(!Path::new(&dir).join("escape.txt").exists());
+
+        let meta = client.list(None).unwrap().pop().unwrap();
+        for relative in ["", "../escape.txt", "a/../../escape.txt", "/tmp/escape.txt"] {
+            assert!(pull_file(&client, &meta.name, relative, &out, &meta, false).is_err());
+        }
+    }
+
+    /// Write a file as if it was last changed at `modified` seconds.
+    fn write_file(path: &Path, content: &str, modified: u64) {
+        std::fs::write(path, content).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(modified);
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_sync_state() {
+        let client = KvsClient::connect(&test_repository(), Secret::new(KeyType::Ed25519)).unwrap();
+        let dir = test_dir("sync_state");
+        let (a, b) = (Path::new(&dir).join("a.txt"), Path::new(&dir).join("b.txt"));
+        write_file(&a, "a1", 1_600_000_000);
+        write_file(&b, "b1", 1_600_000_000);
+        let options = SyncOptions {
+            jobs: 2,
+            ..SyncOptions::default()
+        };
+        let summary = sync_dir(&client, &dir, &options).unwrap();
+        assert_eq!(summary.created, vec!["/a.txt", "/b.txt"]);
+
+        // the same size and mtime, the file is taken as unchanged unread
+        write_file(&a, "a2", 1_600_000_000);
+        let summary = sync_dir(&client, &dir, &options).unwrap();
+        assert_eq!(summary.unchanged, 2);
+        assert_eq!(client.read("/a.txt").unwrap().0, b"a1");
+
+        // a new mtime or size has the file read again
+        write_file(&a, "a2", 1_600_000_001);
+        write_file(&b, "b22", 1_600_000_000);
+        let summary = sync_dir(&client, &dir, &options).unwrap();
+        assert_eq!(summary.updated, vec!["/a.txt", "/b.txt"]);
+        assert_eq!(client.read("/a.txt").unwrap().0, b"a2");
+
+        // a remote change moves the list stamp, the cached list is not used
+        client
+            .update("/b.txt", b"remote", false, "text/plain")
+            .unwrap();
+        let summary = sync_dir(&client, &dir, &options).unwrap();
+        assert_eq!(summary.updated, vec!["/b.txt"]);
+        assert_eq!(client.read("/b.txt").unwrap().0, b"b22");
+    }
+}
@@ -1,5 +1,7 @@
 use sha2::Digest;
 
+use crate::errors::{KVSError, KVSResult};
+
 pub fn sha256(payload: &[u8]) -> Vec<u8> {
     let mut sha_256_worker = sha2::Sha256::new();
     sha_256_worker.update(payload);
@@ -29,14 +31,119 @@ pub fn to_addr(payload: &[u8]) -> String {
     format!("0x{}", to_u8str(&data))
 }
 
+pub fn is_scope_addr(scope: &str) -> bool {
+    scope.len() == 42
+        && scope.starts_with("0x")
+        && scope[2..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parse `30m`, `12h`, `7d`, `2022-06-01` or a RFC 3339 time to unix
+/// milliseconds.
+pub fn parse_since(since: &str) -> KVSResult<i64> {
+    let illegal = || KVSError::LogicError(format!("Illegal time: {}", since));
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(since) {
+        return Ok(time.timestamp_millis());
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+        let time = date.and_hms_opt(0, 0, 0).ok_or_else(illegal)?;
+        return Ok(chrono::TimeZone::from_local_datetime(&chrono::Local, &time)
+            .single()
+            .ok_or_else(illegal)?
+            .timestamp_millis());
+    }
+    chrono::Local::now()
+        .timestamp_millis()
+        .checked_sub(parse_duration(since)?)
+        .ok_or_else(illegal)
+}
+
+/// Parse `30s`, `30m`, `12h` or `7d` to milliseconds.
+pub fn parse_duration(duration: &str) -> KVSResult<i64> {
+    let illegal = || KVSError::LogicError(format!("Illegal duration: {}", duration));
+    let (index, unit) = duration.char_indices().last().ok_or_else(illegal)?;
+    let count = duration[..index].parse::<i64>().map_err(|_| illegal())?;
+    if count <= 0 {
+        return Err(illegal());
+    }
+    let unit_millis: i64 = match unit {
+        's' => 1000,
+        'm' => 60 * 1000,
+        'h' => 60 * 60 * 1000,
+        'd' => 24 * 60 * 60 * 1000,
+        _ => return Err(illegal()),
+    };
+    count.checked_mul(unit_millis).ok_or_else(illegal)
+}
+
+/// The mime type of a value by its magic bytes, else by the extension of
+/// `name`, else `text/plain` for UTF-8 and `application/octet-stream`.
+pub fn detect_mime(name: &str, value: &[u8]) -> String {
+    if let Some(kind) = infer::get(value) {
+        return kind.mime_type().to_string();
+    }
+    if let Some(mime) = mime_guess::from_path(name).first() {
+        return mime.to_string();
+    }
+    match std::str::from_utf8(value) {
+        Ok(_) => mime::TEXT_PLAIN.to_string(),
+        Err(_) => mime::APPLICATION_OCTET_STREAM.to_string(),
+    }
+}
+
+/// Check a mime type given by the user.
+pub fn parse_mime(mime: &str) -> KVSResult<String> {
+    Ok(mime.parse::<mime::Mime>()?.to_string())
+}
+
 #[cfg(test)]
 mod test {
-    use super::to_u8str;
+    use super::{detect_mime, is_scope_addr, parse_duration, parse_mime, parse_since, to_u8str};
 
     #[test]
     fn test_to_u8str() {
-        assert_eq!(to_u8str(&vec![0]), "00");
-        assert_eq!(to_u8str(&vec![0xff]), "ff");
-        assert_eq!(to_u8str(&vec![1]), "01");
+        assert_eq!(to_u8str(&[0]), "00");
+        assert_eq!(to_u8str(&[0xff]), "ff");
+        assert_eq!(to_u8str(&[1]), "01");
+    }
+
+    #[test]
+    fn test_is_scope_addr() {
+        assert!(is_scope_addr("0x0123456789abcdef0123456789abcdef01234567"));
+        assert!(!is_scope_addr("0123456789abcdef0123456789abcdef01234567"));
+        assert!(!is_scope_addr(
+            "0x../../../../../../../../../../../../../.."
+        ));
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(
+            parse_since("2022-06-01T00:00:00Z").unwrap(),
+            1_654_041_600_000
+        );
+        let now = chrono::Local::now().timestamp_millis();
+        let hour_ago = parse_since("1h").unwrap();
+        assert!((now - 3_600_000 - hour_ago).abs() < 1000);
+        assert!(parse_since("2022-06-01").is_ok());
+        assert!(parse_since("1w").is_err());
+        assert!(parse_since("h").is_err());
+        assert_eq!(parse_duration("1d").unwrap(), 86_400_000);
+        for illegal in ["", "d", "1é", "é", "0s", "-1h", "9223372036854775807d"] {
+            assert!(parse_duration(illegal).is_err(), "{}", illegal);
+        }
+    }
+
+    #[test]
+    fn test_detect_mime() {
+        assert_eq!(detect_mime("a.png", b"\x89PNG\r\n\x1a\n"), "image/png");
+        assert_eq!(detect_mime("a.txt", b"\x89PNG\r\n\x1a\n"), "image/png");
+        assert_eq!(detect_mime("/docs/a.json", b"{}"), "application/json");
+        assert_eq!(detect_mime("greeting", b"hello"), "text/plain");
+        assert_eq!(
+            detect_mime("blob", &[0xff, 0xfe, 0x00]),
+            "application/octet-stream"
+        );
+        assert_eq!(parse_mime("text/plain").unwrap(), "text/plain");
+        assert!(parse_mime("bin").is_err());
     }
 }